@@ -1,25 +1,92 @@
-async function updateVotes() {
+async function loadCandidates() {
   try {
-    const response = await fetch("http://10.100.7.120:8080/api/votes");
+    const response = await fetch("http://10.100.7.120:8080/api/candidates");
     if (!response.ok) {
       throw new Error("Network response was not ok " + response.statusText);
     }
     const data = await response.json();
-    const votes = data.votes;
 
-    const rows = document.querySelectorAll(".chart tbody tr");
-    votes.forEach((vote, index) => {
-      const label = vote[0];
-      const value = vote[1];
-      const percentage = (value / votes.reduce((sum, vote) => sum + vote[1], 0)) * 100 || 0;
+    const tbody = document.getElementById("tally");
+    tbody.replaceChildren();
 
-      rows[index].querySelector("th").textContent = label;
-      rows[index].querySelector("td").style.setProperty("--size", percentage / 100);
-      rows[index].querySelector("td").textContent = `${percentage.toFixed(1)}%`;
+    data.candidates.forEach((candidate) => {
+      const row = document.createElement("tr");
+      const label = document.createElement("th");
+      label.scope = "row";
+      label.textContent = candidate.name;
+      const value = document.createElement("td");
+      value.id = candidate.id;
+      value.style.setProperty("--size", 0);
+      value.textContent = "0.0%";
+      row.append(label, value);
+      tbody.append(row);
     });
+
+    if (data.candidates.length > 0) {
+      const example = document.getElementById("curl-command");
+      example.textContent = example.textContent.replace(/"vote":"[^"]*"/, `"vote":"${data.candidates[0].id}"`);
+    }
+  } catch (error) {
+    console.error("There has been a problem with your fetch operation:", error);
+  }
+}
+
+// First choice totals by candidate id, kept up to date from the live stream.
+const tally = {};
+
+function showVotes() {
+  const total = Object.values(tally).reduce((sum, count) => sum + count, 0);
+
+  Object.entries(tally).forEach(([id, count]) => {
+    const cell = document.getElementById(id);
+    if (!cell) {
+      return;
+    }
+    const percentage = (count / total) * 100 || 0;
+
+    cell.style.setProperty("--size", percentage / 100);
+    cell.textContent = `${percentage.toFixed(1)}%`;
+  });
+}
+
+async function updateVotes() {
+  try {
+    const response = await fetch("http://10.100.7.120:8080/api/votes");
+    if (!response.ok) {
+      throw new Error("Network response was not ok " + response.statusText);
+    }
+    const data = await response.json();
+
+    data.votes.forEach(([id, count]) => (tally[id] = count));
+    showVotes();
   } catch (error) {
     console.error("There has been a problem with your fetch operation:", error);
   }
 }
 
-setInterval(updateVotes, 500);
+// Subscribes to the live tally; every event carries new totals for the candidates whose count changed. Browsers
+// without EventSource fall back to polling.
+function subscribeToVotes() {
+  if (!window.EventSource) {
+    updateVotes();
+    setInterval(updateVotes, 500);
+    return;
+  }
+
+  const events = new EventSource("http://10.100.7.120:8080/api/votes/stream");
+  events.onmessage = (message) => {
+    const event = JSON.parse(message.data);
+
+    event.changed.forEach(([id, count]) => (tally[id] = count));
+    showVotes();
+  };
+  events.onerror = (error) => {
+    // EventSource reconnects by itself, and the first event after reconnecting has every candidate in it.
+    console.error("Lost connection to the live tally; reconnecting:", error);
+  };
+}
+
+document.addEventListener("DOMContentLoaded", async () => {
+  await loadCandidates();
+  subscribeToVotes();
+});
@@ -0,0 +1,167 @@
+//! Instant-runoff tabulation of ranked ballots.
+//!
+//! Each round every ballot counts towards its highest ranked candidate that's still in the running. If a candidate
+//! holds a majority of the ballots that are still live they win; otherwise the candidate(s) with the fewest votes are
+//! eliminated and their ballots transfer to the next preference in the following round.
+//!
+//! Ties for last place are broken by eliminating every tied candidate at once. If that would eliminate everyone left
+//! the count ends in a tie between them.
+
+use democracy_proto::{roster::Roster, IrvResults, IrvRound, Tally};
+
+/// Runs an instant-runoff count. Ballots are lists of roster positions from most to least preferred.
+pub fn tabulate(roster: &Roster, ballots: &[Vec<usize>]) -> IrvResults {
+    let mut running = vec![true; roster.candidates.len()];
+    let mut rounds = vec![];
+
+    loop {
+        let mut counts = vec![0u64; roster.candidates.len()];
+        let mut exhausted = 0;
+
+        for ballot in ballots {
+            match ballot.iter().find(|&&choice| running[choice]) {
+                Some(&choice) => counts[choice] += 1,
+                None => exhausted += 1,
+            }
+        }
+
+        let tallies = (0..counts.len())
+            .filter(|&i| running[i])
+            .map(|i| Tally(roster.candidates[i].id.clone(), counts[i]))
+            .collect();
+
+        let live: u64 = counts.iter().sum();
+        let remaining: Vec<usize> = (0..counts.len()).filter(|&i| running[i]).collect();
+
+        if let Some(&leader) = remaining.iter().find(|&&i| counts[i] * 2 > live) {
+            rounds.push(IrvRound {
+                tallies,
+                exhausted,
+                eliminated: vec![],
+            });
+
+            return IrvResults {
+                rounds,
+                winner: Some(roster.candidates[leader].id.clone()),
+                tied: vec![],
+            };
+        }
+
+        let fewest = remaining.iter().map(|&i| counts[i]).min().unwrap_or(0);
+        let last: Vec<usize> = remaining
+            .iter()
+            .copied()
+            .filter(|&i| counts[i] == fewest)
+            .collect();
+
+        if last.len() == remaining.len() {
+            rounds.push(IrvRound {
+                tallies,
+                exhausted,
+                eliminated: vec![],
+            });
+
+            return IrvResults {
+                rounds,
+                winner: None,
+                tied: remaining
+                    .iter()
+                    .map(|&i| roster.candidates[i].id.clone())
+                    .collect(),
+            };
+        }
+
+        for &i in &last {
+            running[i] = false;
+        }
+
+        rounds.push(IrvRound {
+            tallies,
+            exhausted,
+            eliminated: last
+                .iter()
+                .map(|&i| roster.candidates[i].id.clone())
+                .collect(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roster() -> Roster {
+        Roster::parse(
+            r#"
+            [[candidates]]
+            id = "a"
+            name = "A"
+
+            [[candidates]]
+            id = "b"
+            name = "B"
+
+            [[candidates]]
+            id = "c"
+            name = "C"
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn majority_in_first_round() {
+        let results = tabulate(&roster(), &[vec![0], vec![0, 1], vec![1]]);
+
+        assert_eq!(results.winner.as_deref(), Some("a"));
+        assert_eq!(results.rounds.len(), 1);
+    }
+
+    #[test]
+    fn eliminated_ballots_transfer() {
+        // a leads on first preferences, but c's voters prefer b which puts b over the top.
+        let mut ballots = vec![vec![0]; 4];
+        ballots.extend(vec![vec![1, 0]; 3]);
+        ballots.extend(vec![vec![2, 1]; 2]);
+        let results = tabulate(&roster(), &ballots);
+
+        assert_eq!(results.rounds.len(), 2);
+        assert_eq!(results.rounds[0].eliminated, vec!["c".to_string()]);
+        assert_eq!(
+            results.rounds[1].tallies,
+            vec![Tally("a".into(), 4), Tally("b".into(), 5)]
+        );
+        assert_eq!(results.winner.as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn exhausted_ballots_drop_out() {
+        let ballots = [vec![0], vec![0], vec![1, 0], vec![2]];
+        let results = tabulate(&roster(), &ballots);
+
+        // b and c tie for last and go out together; c's ballot has nowhere to go.
+        assert_eq!(
+            results.rounds[0].eliminated,
+            vec!["b".to_string(), "c".to_string()]
+        );
+        assert_eq!(results.rounds[1].exhausted, 1);
+        assert_eq!(results.rounds[1].tallies, vec![Tally("a".into(), 3)]);
+        assert_eq!(results.winner.as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn tie_between_everyone_left() {
+        let results = tabulate(&roster(), &[vec![0], vec![1]]);
+
+        assert_eq!(results.winner, None);
+        assert_eq!(results.tied, vec!["a".to_string(), "b".to_string()]);
+    }
+
+    #[test]
+    fn no_ballots_is_a_tie() {
+        let results = tabulate(&roster(), &[]);
+
+        assert_eq!(results.winner, None);
+        assert_eq!(results.tied.len(), 3);
+    }
+}
@@ -1,47 +1,239 @@
-use anyhow::{bail, Result};
+mod irv;
+mod metrics;
+mod race;
+mod storage;
+mod stream;
+mod tally;
+
+use anyhow::Result;
 use axum::{
     body::Body,
     extract::{ConnectInfo, Json, Path, State},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::get,
     Router,
 };
+use clap::Parser;
 use dashmap::DashMap;
-use pnet::datalink::{self, NetworkInterface};
+use democracy_proto::{
+    roster::Roster, routes, CandidatesResponse, ErrorResponse, IrvResults, RaceResult,
+    SystemResponse, Tally, TallyEvent, VoteRequest, VoteResponse, VotesResponse, WinnerResponse,
+    API_VERSION, API_VERSION_HEADER,
+};
+use metrics::{Metrics, VoteOutcome};
+use pnet::datalink;
 use rust_embed::RustEmbed;
-use serde::{Deserialize, Serialize};
 use std::{
     net::{IpAddr, Ipv4Addr, SocketAddr, SocketAddrV4},
-    str::FromStr,
-    sync::atomic::AtomicU64,
+    path::PathBuf,
+    sync::{atomic::AtomicU64, RwLock},
+    time::Duration,
 };
+use storage::{FsyncPolicy, VoteLog, VoteRecord};
+use tally::TallyMethod;
+use tokio::sync::{broadcast, Notify};
 use tracing::{error, info, warn};
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 
+// How long after a vote the winner is worked out again; votes coming in meanwhile are counted in the same go.
+const RECOUNT_DELAY: Duration = Duration::from_millis(100);
+
 #[derive(RustEmbed)]
 #[folder = "public"]
 pub struct EmbeddedFrontendFS;
 
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Collects votes for which batch's program gets to run"
+)]
+struct Args {
+    /// Address to listen on.
+    #[arg(default_value_t = SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 8080))]
+    bind_address: SocketAddrV4,
+
+    /// Path to the roster file listing the candidates in this election.
+    #[arg(long, env = "BALLOT_BOX_ROSTER", default_value = "roster.toml")]
+    roster: PathBuf,
+
+    /// Path to the append-only log every accepted vote is written to. It's replayed on startup to restore the tally.
+    #[arg(long, env = "BALLOT_BOX_VOTE_LOG", default_value = "votes.log")]
+    vote_log: PathBuf,
+
+    /// When to flush the vote log to disk.
+    #[arg(long, env = "BALLOT_BOX_FSYNC", value_enum, default_value_t = FsyncPolicy::Always)]
+    fsync: FsyncPolicy,
+
+    /// How often to flush the vote log to disk when using `--fsync interval`.
+    #[arg(long, env = "BALLOT_BOX_FSYNC_INTERVAL_MS", default_value_t = 1000)]
+    fsync_interval_ms: u64,
+
+    /// Minimum number of seconds between votes from the same IP address; 0 turns rate limiting off.
+    #[arg(long, env = "BALLOT_BOX_RATE_LIMIT_SECS", default_value_t = 1)]
+    rate_limit_secs: u64,
+
+    /// Token the scheduler has to present (as `Authorization: Bearer <token>`) to report race results. Race results
+    /// aren't accepted from anyone unless this is given.
+    #[arg(long, env = "BALLOT_BOX_RACE_TOKEN")]
+    race_token: Option<String>,
+}
+
 struct AppContext {
-    summer1_votes: AtomicU64,
-    summer2_votes: AtomicU64,
+    roster: Roster,
+    votes: Vec<AtomicU64>, // First choice tally for each candidate, in roster order.
+    ballots: RwLock<Vec<Vec<usize>>>, // Every ballot cast as roster positions, kept for ranked counting methods.
+    tally_method: Box<dyn TallyMethod>, // How the winner is decided; set by the roster.
+    vote_log: VoteLog,
+    rate_limit_secs: u64,
     rate_limiter: DashMap<IpAddr, u64>,
+    updates: broadcast::Sender<TallyEvent>, // Every counted vote, for /api/votes/stream subscribers.
+    winner: RwLock<WinnerResponse>, // The winner as of the last recount, which is what vote events carry.
+    recount_due: Notify,            // Wakes `recount_winner` once votes have come in.
+    races: RwLock<Vec<RaceResult>>, // The latest rounds of the CPU-time race the scheduler has reported.
+    race_token: Option<String>,     // What the scheduler has to present to report them.
+    metrics: Metrics,               // Served at /metrics.
 }
 
 impl AppContext {
-    fn new() -> Self {
+    fn new(roster: Roster, vote_log: VoteLog, rate_limit_secs: u64) -> Self {
+        let votes = roster
+            .candidates
+            .iter()
+            .map(|_| AtomicU64::new(0))
+            .collect();
+
+        let tally_method = tally::for_method(roster.method);
+        let winner = tally_method.tally(&roster, &[]);
+
         Self {
-            summer1_votes: AtomicU64::new(0),
-            summer2_votes: AtomicU64::new(0),
+            tally_method,
+            winner: RwLock::new(winner),
+            recount_due: Notify::new(),
+            metrics: Metrics::new(&roster),
+            roster,
+            votes,
+            ballots: RwLock::new(vec![]),
+            vote_log,
+            rate_limit_secs,
             rate_limiter: DashMap::new(),
+            updates: broadcast::channel(stream::BACKLOG).0,
+            races: RwLock::new(vec![]),
+            race_token: None,
         }
     }
-}
 
-#[derive(Debug)]
-enum PossibleVote {
-    Summer1,
-    Summer2,
+    /// Accepts race results from whoever presents `token`; without one, they aren't accepted at all.
+    fn with_race_token(mut self, token: Option<String>) -> Self {
+        self.race_token = token;
+        self
+    }
+
+    /// Rebuilds the tally from the records in the vote log.
+    fn replay(&self, records: &[VoteRecord]) {
+        for record in records {
+            let mut ballot = vec![];
+
+            for choice in record.ranking() {
+                match self.roster.position(choice) {
+                    Some(position) => ballot.push(position),
+                    None => {
+                        warn!(vote = %choice, "vote log contains a vote for a candidate not in the roster; ignoring")
+                    }
+                }
+            }
+
+            if !ballot.is_empty() {
+                self.count(ballot);
+            }
+        }
+    }
+
+    /// Turns a voter's ranking into roster positions, making sure every choice is a real candidate listed only once.
+    fn parse_ballot(&self, ranking: &[String]) -> Result<Vec<usize>, String> {
+        if ranking.is_empty() {
+            return Err(format!(
+                "No vote given; Must be one of {}",
+                self.roster.id_list()
+            ));
+        }
+
+        let mut ballot = Vec::with_capacity(ranking.len());
+
+        for choice in ranking {
+            let position = self.roster.position(choice).ok_or_else(|| {
+                format!("Not a valid vote; Must be one of {}", self.roster.id_list())
+            })?;
+
+            if ballot.contains(&position) {
+                return Err(format!("'{}' is ranked more than once", choice));
+            }
+
+            ballot.push(position);
+        }
+
+        Ok(ballot)
+    }
+
+    /// Counts a ballot. Only the first choice total is brought up to date straight away; working out the winner means
+    /// going over every ballot cast, so that's left to [`Self::recount`].
+    fn count(&self, ballot: Vec<usize>) {
+        let mut ballots = self.ballots.write().unwrap();
+
+        let first = ballot[0];
+        let total = self.votes[first].fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+        ballots.push(ballot);
+
+        // Publishing while still holding the lock keeps the events in the same order as the votes they describe.
+        if self.updates.receiver_count() > 0 {
+            let _ = self.updates.send(TallyEvent {
+                changed: vec![Tally(self.roster.candidates[first].id.clone(), total)],
+                winner: self.winner.read().unwrap().clone(),
+            });
+        }
+        drop(ballots);
+
+        self.recount_due.notify_one();
+    }
+
+    /// Works out the winner again from every ballot cast, and lets subscribers know. The ballots are copied out first
+    /// so votes aren't held up while they're counted.
+    fn recount(&self) {
+        let ballots = self.ballots.read().unwrap().clone();
+        let winner = self.tally_method.tally(&self.roster, &ballots);
+
+        *self.winner.write().unwrap() = winner.clone();
+        if self.updates.receiver_count() > 0 {
+            let _ = self.updates.send(TallyEvent {
+                changed: vec![],
+                winner,
+            });
+        }
+    }
+
+    /// The whole tally as a single stream event, for new subscribers.
+    fn snapshot(&self) -> TallyEvent {
+        let ballots = self.ballots.read().unwrap();
+
+        TallyEvent {
+            changed: self.tally(""),
+            winner: self.tally_method.tally(&self.roster, &ballots),
+        }
+    }
+
+    /// Returns the current tally for every candidate in roster order, with each id suffixed by `suffix`.
+    fn tally(&self, suffix: &str) -> Vec<Tally> {
+        self.roster
+            .candidates
+            .iter()
+            .zip(&self.votes)
+            .map(|(candidate, votes)| {
+                Tally(
+                    format!("{}{}", candidate.id, suffix),
+                    votes.load(std::sync::atomic::Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
 }
 
 #[derive(Debug)]
@@ -52,66 +244,49 @@ pub struct AppError {
 
 impl IntoResponse for AppError {
     fn into_response(self) -> axum::response::Response {
-        let body = serde_json::json!({ "error": self.message });
+        let body = ErrorResponse {
+            error: self.message,
+        };
         (self.status, axum::Json(body)).into_response()
     }
 }
 
-fn check_vote(user_vote: &str) -> Result<PossibleVote> {
-    let user_vote = user_vote.to_lowercase();
-
-    match user_vote.as_str() {
-        "summer1" => Ok(PossibleVote::Summer1),
-        "summer2" => Ok(PossibleVote::Summer2),
-        _ => bail!("Not a valid vote"),
-    }
-}
-
-#[derive(Debug, Deserialize, Serialize)]
-struct VoteRequest {
-    vote: String,
-}
+#[tokio::main]
+async fn main() {
+    init_logger().unwrap();
 
-#[derive(Debug, Deserialize, Serialize)]
-struct VoteResponse {
-    current_tally: Vec<(String, u64)>,
-}
+    let args = Args::parse();
 
-#[derive(Debug, Deserialize, Serialize)]
-struct VotesResponse {
-    votes: Vec<(String, u64)>,
-}
+    let roster = Roster::load(&args.roster).unwrap();
+    info!(candidates = %roster.id_list(), method = ?roster.method, "loaded roster");
 
-#[derive(Debug, Deserialize, Serialize)]
-struct SystemResponse {
-    address: String,
-}
+    let (vote_log, records) = VoteLog::open(&args.vote_log, args.fsync).unwrap();
 
-#[tokio::main]
-async fn main() {
-    let app_state = std::sync::Arc::new(AppContext::new());
+    let app_state = std::sync::Arc::new(
+        AppContext::new(roster, vote_log, args.rate_limit_secs).with_race_token(args.race_token),
+    );
+    app_state.replay(&records);
+    info!(path = %args.vote_log.display(), votes = records.len(), fsync = ?args.fsync, "restored votes from log");
 
-    init_logger().unwrap();
+    app_state.recount();
+    tokio::spawn(recount_winner(app_state.clone(), RECOUNT_DELAY));
 
-    let args: Vec<String> = std::env::args().collect();
+    if app_state.vote_log.fsync_policy() == FsyncPolicy::Interval {
+        tokio::spawn(sync_vote_log(
+            app_state.clone(),
+            Duration::from_millis(args.fsync_interval_ms),
+        ));
+    }
 
-    let bind_address = if args.len() > 1 {
-        std::net::SocketAddrV4::from_str(&args[1]).unwrap()
-    } else {
-        SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 8080)
-    };
+    if app_state.race_token.is_none() {
+        info!("no --race-token given; race results won't be accepted");
+    }
 
-    let app = Router::new()
-        .route("/api/system", get(system_handler))
-        .route("/api/votes", get(votes_handler).post(vote_handler))
-        .route(
-            "/",
-            get(|| async { static_handler(Path("".to_string())).await }),
-        )
-        .route("/*path", get(static_handler))
-        .with_state(app_state);
+    let app = app(app_state);
 
-    let listener = tokio::net::TcpListener::bind(bind_address).await.unwrap();
+    let listener = tokio::net::TcpListener::bind(args.bind_address)
+        .await
+        .unwrap();
 
     info!(addr = %listener.local_addr().unwrap(), "started server");
     axum::serve(
@@ -122,9 +297,81 @@ async fn main() {
     .unwrap();
 }
 
+fn app(state: std::sync::Arc<AppContext>) -> Router {
+    Router::new()
+        .route(routes::SYSTEM, get(system_handler))
+        .route(routes::CANDIDATES, get(candidates_handler))
+        .route(routes::VOTES, get(votes_handler).post(vote_handler))
+        .route(routes::VOTES_STREAM, get(stream::stream_handler))
+        .route(routes::RESULTS_IRV, get(irv_results_handler))
+        .route(routes::WINNER, get(winner_handler))
+        .route(
+            routes::RACE,
+            get(race::race_handler).post(race::report_handler),
+        )
+        .route(routes::METRICS, get(metrics::metrics_handler))
+        .route(
+            "/",
+            get(|| async { static_handler(Path("".to_string())).await }),
+        )
+        .route("/*path", get(static_handler))
+        .route_layer(axum::middleware::from_fn_with_state(
+            state.clone(),
+            metrics::time_request,
+        ))
+        .layer(axum::middleware::map_response(advertise_api_version))
+        .with_state(state)
+}
+
+// Lets clients tell which version of the API they're talking to without having to make an extra request.
+async fn advertise_api_version<B>(mut response: Response<B>) -> Response<B> {
+    response.headers_mut().insert(
+        API_VERSION_HEADER,
+        axum::http::HeaderValue::from_static(API_VERSION),
+    );
+    response
+}
+
+// Recounts once votes have come in, waiting `delay` first so a burst of votes is only counted once.
+async fn recount_winner(state: std::sync::Arc<AppContext>, delay: Duration) {
+    loop {
+        state.recount_due.notified().await;
+        tokio::time::sleep(delay).await;
+
+        let state = state.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || state.recount()).await {
+            error!(err = %e, "recount task failed");
+        }
+    }
+}
+
+async fn sync_vote_log(state: std::sync::Arc<AppContext>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        let state = state.clone();
+        match tokio::task::spawn_blocking(move || state.vote_log.sync()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!(err = %e, "could not sync vote log"),
+            Err(e) => error!(err = %e, "vote log sync task failed"),
+        }
+    }
+}
+
 async fn system_handler() -> Result<Json<SystemResponse>, AppError> {
     Ok(Json(SystemResponse {
         address: get_local_ipv4_address().unwrap().to_string(),
+        api_version: API_VERSION.into(),
+    }))
+}
+
+async fn candidates_handler(
+    State(state): State<std::sync::Arc<AppContext>>,
+) -> Result<Json<CandidatesResponse>, AppError> {
+    Ok(Json(CandidatesResponse {
+        candidates: state.roster.info(),
     }))
 }
 
@@ -160,22 +407,36 @@ async fn static_handler(Path(path): Path<String>) -> Result<Response<Body>, AppE
 async fn votes_handler(
     State(state): State<std::sync::Arc<AppContext>>,
 ) -> Result<Json<VotesResponse>, AppError> {
-    let votes = vec![
-        (
-            "summer1".into(),
-            state
-                .summer1_votes
-                .load(std::sync::atomic::Ordering::Relaxed),
-        ),
-        (
-            "summer2".into(),
-            state
-                .summer2_votes
-                .load(std::sync::atomic::Ordering::Relaxed),
-        ),
-    ];
-
-    Ok(Json(VotesResponse { votes }))
+    Ok(Json(VotesResponse {
+        votes: state.tally(""),
+    }))
+}
+
+async fn irv_results_handler(
+    State(state): State<std::sync::Arc<AppContext>>,
+) -> Result<Json<IrvResults>, AppError> {
+    // Counted off the async workers, on a copy of the ballots so votes aren't held up meanwhile.
+    tokio::task::spawn_blocking(move || {
+        let ballots = state.ballots.read().unwrap().clone();
+        irv::tabulate(&state.roster, &ballots)
+    })
+    .await
+    .map(Json)
+    .map_err(|e| {
+        error!(err = %e, "IRV count failed");
+        AppError {
+            status: axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            message: "Could not count the votes".into(),
+        }
+    })
+}
+
+// The winner as of the last recount, which is at most `RECOUNT_DELAY` behind the votes. The scheduler polls this at
+// every decision, so it mustn't cost a count each time.
+async fn winner_handler(
+    State(state): State<std::sync::Arc<AppContext>>,
+) -> Result<Json<WinnerResponse>, AppError> {
+    Ok(Json(state.winner.read().unwrap().clone()))
 }
 
 async fn vote_handler(
@@ -191,7 +452,8 @@ async fn vote_handler(
     if let Some(matched_ip) = matched_ip {
         let last_request_time = *matched_ip.value();
 
-        if epoch_seconds - last_request_time < 1 {
+        if epoch_seconds.saturating_sub(last_request_time) < state.rate_limit_secs {
+            state.metrics.vote_request(VoteOutcome::RateLimited);
             return Err(AppError {
                 status: axum::http::StatusCode::TOO_MANY_REQUESTS,
                 message: "Okay, listen. Democracy has limits. You're doing that too much; try again in a second."
@@ -206,44 +468,46 @@ async fn vote_handler(
         .and_modify(|seconds| *seconds = epoch_seconds)
         .or_insert(epoch_seconds);
 
-    let vote = match check_vote(&input.vote) {
-        Ok(vote) => vote,
-        Err(_) => {
+    let ballot = match state.parse_ballot(&input.ranking()) {
+        Ok(ballot) => ballot,
+        Err(message) => {
+            state.metrics.vote_request(VoteOutcome::Invalid);
             return Err(AppError {
                 status: axum::http::StatusCode::BAD_REQUEST,
-                message: "Not a valid vote; Must be one of 'summer1', 'summer2'".into(),
+                message,
             });
         }
     };
 
-    match vote {
-        PossibleVote::Summer1 => state
-            .summer1_votes
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
-        PossibleVote::Summer2 => state
-            .summer2_votes
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed),
-    };
+    let ranking: Vec<String> = ballot
+        .iter()
+        .map(|&position| state.roster.candidates[position].id.clone())
+        .collect();
+
+    // The vote has to be on disk before it's counted; otherwise we could acknowledge a vote that a crash then loses.
+    let record = VoteRecord::new(epoch_seconds, ranking.clone());
+    let log_state = state.clone();
+    let persisted = tokio::task::spawn_blocking(move || log_state.vote_log.append(&record))
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|result| result);
+
+    if let Err(e) = persisted {
+        error!(err = %e, "could not record vote");
+        state.metrics.vote_request(VoteOutcome::Failed);
+        return Err(AppError {
+            status: axum::http::StatusCode::INTERNAL_SERVER_ERROR,
+            message: "Could not record your vote; please try again.".into(),
+        });
+    }
 
-    let response = vec![
-        (
-            "summer1_votes".into(),
-            state
-                .summer1_votes
-                .load(std::sync::atomic::Ordering::Relaxed),
-        ),
-        (
-            "summer2_votes".into(),
-            state
-                .summer2_votes
-                .load(std::sync::atomic::Ordering::Relaxed),
-        ),
-    ];
-
-    info!(choice = ?vote, "vote cast!");
+    state.count(ballot);
+    state.metrics.vote_request(VoteOutcome::Accepted);
+
+    info!(choice = %ranking[0], ranking = ?ranking, "vote cast!");
 
     Ok(Json(VoteResponse {
-        current_tally: response,
+        current_tally: state.tally("_votes"),
     }))
 }
 
@@ -282,3 +546,59 @@ fn get_local_ipv4_address() -> Option<Ipv4Addr> {
     }
     None
 }
+
+// Fixtures shared by every module's tests.
+#[cfg(test)]
+mod test_fixtures {
+    use super::*;
+    use std::sync::Arc;
+
+    /// The two candidates every test's election is between.
+    pub fn roster() -> Roster {
+        Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+
+            [[candidates]]
+            id = "summer2"
+            name = "Summer 2"
+            "#,
+        )
+        .unwrap()
+    }
+
+    /// A ballot box for [`roster`] with its vote log in a temporary directory, which lasts as long as the returned
+    /// `TempDir` does.
+    pub fn context(rate_limit_secs: u64) -> (tempfile::TempDir, AppContext) {
+        let dir = tempfile::tempdir().unwrap();
+        let (vote_log, _) =
+            VoteLog::open(&dir.path().join("votes.log"), FsyncPolicy::Never).unwrap();
+
+        (dir, AppContext::new(roster(), vote_log, rate_limit_secs))
+    }
+
+    /// [`context`], ready to hand to the handlers.
+    pub fn state(rate_limit_secs: u64) -> (tempfile::TempDir, Arc<AppContext>) {
+        let (dir, context) = context(rate_limit_secs);
+        (dir, Arc::new(context))
+    }
+
+    /// Serves the whole app on a random local port and returns its address.
+    pub async fn serve(state: Arc<AppContext>) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            axum::serve(
+                listener,
+                app(state).into_make_service_with_connect_info::<SocketAddr>(),
+            )
+            .await
+            .unwrap();
+        });
+
+        addr
+    }
+}
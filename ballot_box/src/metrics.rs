@@ -0,0 +1,250 @@
+//! `/metrics`: Prometheus metrics, so floods of votes during a live event can be seen (and alerted on) as they happen.
+//!
+//! Counts of things as they happen are kept as they happen; anything the app already keeps track of (the tally, the
+//! rate limiter, the stream's subscribers) is read off the state when the metrics are scraped.
+
+use crate::AppContext;
+use axum::{
+    extract::{MatchedPath, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use democracy_proto::roster::Roster;
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::sync::{atomic::Ordering, Arc};
+use std::time::Instant;
+
+/// What became of a request to cast a vote.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VoteOutcome {
+    Accepted,
+    RateLimited,
+    Invalid, // not a valid ballot for this roster
+    Failed,  // couldn't be recorded
+}
+
+impl VoteOutcome {
+    fn label(self) -> &'static str {
+        match self {
+            VoteOutcome::Accepted => "accepted",
+            VoteOutcome::RateLimited => "rate_limited",
+            VoteOutcome::Invalid => "invalid",
+            VoteOutcome::Failed => "failed",
+        }
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,             // everything below, for rendering
+    votes: IntGaugeVec,             // first choice votes for each candidate, from the tally
+    vote_requests: IntCounterVec,   // requests to vote, by outcome
+    request_duration: HistogramVec, // how long each route took to answer
+    rate_limiter_size: IntGauge,    // addresses the rate limiter is keeping track of
+    stream_subscribers: IntGauge,   // clients subscribed to the live tally
+}
+
+impl Metrics {
+    pub fn new(roster: &Roster) -> Self {
+        let registry =
+            Registry::new_custom(Some("ballot_box".into()), None).expect("Invalid metrics prefix");
+
+        let metrics = Self {
+            votes: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new("votes", "First choice votes for each candidate"),
+                    &["candidate"],
+                )
+                .expect("Invalid metric"),
+            ),
+            vote_requests: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "vote_requests_total",
+                        "Requests to cast a vote, by whether they were accepted, rate limited, invalid or failed",
+                    ),
+                    &["outcome"],
+                )
+                .expect("Invalid metric"),
+            ),
+            request_duration: register(
+                &registry,
+                HistogramVec::new(
+                    HistogramOpts::new(
+                        "http_request_duration_seconds",
+                        "How long requests took to answer, by route",
+                    ),
+                    &["method", "route", "status"],
+                )
+                .expect("Invalid metric"),
+            ),
+            rate_limiter_size: register(
+                &registry,
+                IntGauge::new(
+                    "rate_limiter_addresses",
+                    "Addresses the rate limiter is keeping track of",
+                )
+                .expect("Invalid metric"),
+            ),
+            stream_subscribers: register(
+                &registry,
+                IntGauge::new(
+                    "stream_subscribers",
+                    "Clients subscribed to the live tally, over Server-Sent Events or a WebSocket",
+                )
+                .expect("Invalid metric"),
+            ),
+            registry,
+        };
+
+        // Every outcome shows up from the start, so alerts on their rates work before the first one happens.
+        for outcome in [
+            VoteOutcome::Accepted,
+            VoteOutcome::RateLimited,
+            VoteOutcome::Invalid,
+            VoteOutcome::Failed,
+        ] {
+            metrics.vote_requests.with_label_values(&[outcome.label()]);
+        }
+        for candidate in &roster.candidates {
+            metrics.votes.with_label_values(&[&candidate.id]);
+        }
+
+        metrics
+    }
+
+    pub fn vote_request(&self, outcome: VoteOutcome) {
+        self.vote_requests
+            .with_label_values(&[outcome.label()])
+            .inc();
+    }
+}
+
+fn register<M: Collector + Clone + 'static>(registry: &Registry, metric: M) -> M {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+    metric
+}
+
+pub async fn metrics_handler(State(state): State<Arc<AppContext>>) -> Response {
+    let metrics = &state.metrics;
+
+    for (candidate, votes) in state.roster.candidates.iter().zip(&state.votes) {
+        metrics
+            .votes
+            .with_label_values(&[&candidate.id])
+            .set(votes.load(Ordering::Relaxed) as i64);
+    }
+    metrics
+        .rate_limiter_size
+        .set(state.rate_limiter.len() as i64);
+    metrics
+        .stream_subscribers
+        .set(state.updates.receiver_count() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+    encoder
+        .encode(&metrics.registry.gather(), &mut buffer)
+        .expect("Could not encode metrics");
+
+    (
+        [(
+            axum::http::header::CONTENT_TYPE,
+            encoder.format_type().to_string(),
+        )],
+        buffer,
+    )
+        .into_response()
+}
+
+/// Middleware timing every request, labelled with the route it matched rather than the path asked for so that
+/// scanners trying random paths can't blow up the number of series.
+pub async fn time_request(
+    State(state): State<Arc<AppContext>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let method = request.method().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or("unmatched".to_string(), |path| path.as_str().to_string());
+
+    let response = next.run(request).await;
+
+    state
+        .metrics
+        .request_duration
+        .with_label_values(&[&method, &route, response.status().as_str()])
+        .observe(started.elapsed().as_secs_f64());
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_fixtures::{serve, state};
+    use democracy_proto::routes;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    // Makes a single request and returns the whole response, headers and all.
+    async fn request(addr: std::net::SocketAddr, method: &str, path: &str, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    method,
+                    path,
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn vote_requests_are_counted_by_outcome() {
+        let (_dir, state) = state(60);
+        let addr = serve(state.clone()).await;
+
+        request(addr, "POST", routes::VOTES, r#"{"vote":"summer2"}"#).await;
+        request(addr, "POST", routes::VOTES, r#"{"vote":"summer1"}"#).await;
+        state.rate_limiter.clear();
+        request(addr, "POST", routes::VOTES, r#"{"vote":"winter"}"#).await;
+        let _subscriber = state.updates.subscribe();
+
+        let response = request(addr, "GET", routes::METRICS, "").await;
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        for line in [
+            r#"ballot_box_votes{candidate="summer1"} 0"#,
+            r#"ballot_box_votes{candidate="summer2"} 1"#,
+            r#"ballot_box_vote_requests_total{outcome="accepted"} 1"#,
+            r#"ballot_box_vote_requests_total{outcome="rate_limited"} 1"#,
+            r#"ballot_box_vote_requests_total{outcome="invalid"} 1"#,
+            r#"ballot_box_vote_requests_total{outcome="failed"} 0"#,
+            r#"ballot_box_http_request_duration_seconds_count{method="POST",route="/api/votes",status="200"} 1"#,
+            r#"ballot_box_http_request_duration_seconds_count{method="POST",route="/api/votes",status="429"} 1"#,
+            "ballot_box_rate_limiter_addresses 1",
+            "ballot_box_stream_subscribers 1",
+        ] {
+            assert!(response.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! `/api/race`: results of the scheduler's CPU-time race.
+//!
+//! The scheduler posts a [`RaceResult`] every time a candidate wins a round, and anyone can fetch the rounds won so
+//! far. Only the scheduler gets to post them: it has to present the `--race-token` the ballot box was started with,
+//! which it sends through `--ballot-header`. They're only kept in memory, and only the latest [`MAX_RESULTS`] of them;
+//! unlike votes there's nothing to restore, since the race starts over whenever the scheduler does.
+//!
+//! A round can only be reported once. Rounds are told apart by the run they came from as well as their number, since a
+//! restarted scheduler counts from round 1 again.
+
+use crate::{AppContext, AppError};
+use axum::extract::{Json, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use democracy_proto::{RaceResponse, RaceResult};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+/// How many rounds are kept; older ones are forgotten.
+pub const MAX_RESULTS: usize = 1000;
+
+pub async fn race_handler(
+    State(state): State<Arc<AppContext>>,
+) -> Result<Json<RaceResponse>, AppError> {
+    Ok(Json(RaceResponse {
+        results: state.races.read().unwrap().clone(),
+    }))
+}
+
+pub async fn report_handler(
+    State(state): State<Arc<AppContext>>,
+    headers: HeaderMap,
+    Json(mut result): Json<RaceResult>,
+) -> Result<Json<RaceResponse>, AppError> {
+    if !authorized(&state, &headers) {
+        warn!(round = result.round, winner = %result.winner, "refused race result without a valid token");
+        return Err(AppError {
+            status: StatusCode::UNAUTHORIZED,
+            message: "Only the scheduler can report race results".into(),
+        });
+    }
+
+    let Some(position) = state.roster.position(&result.winner) else {
+        return Err(AppError {
+            status: StatusCode::BAD_REQUEST,
+            message: format!(
+                "'{}' isn't standing; the winner must be one of {}",
+                result.winner,
+                state.roster.id_list()
+            ),
+        });
+    };
+    result.winner = state.roster.candidates[position].id.clone();
+
+    let mut races = state.races.write().unwrap();
+    if races
+        .iter()
+        .any(|race| race.run == result.run && race.round == result.round)
+    {
+        return Err(AppError {
+            status: StatusCode::CONFLICT,
+            message: format!(
+                "Round {} of run {} has already been reported",
+                result.round, result.run
+            ),
+        });
+    }
+
+    info!(run = result.run, round = result.round, winner = %result.winner, target_ms = result.target_ms, "race won!");
+
+    races.push(result);
+    if races.len() > MAX_RESULTS {
+        let excess = races.len() - MAX_RESULTS;
+        races.drain(..excess);
+    }
+
+    Ok(Json(RaceResponse {
+        results: races.clone(),
+    }))
+}
+
+// Whether the request carries the race token. Nobody is authorized when there isn't one.
+fn authorized(state: &AppContext, headers: &HeaderMap) -> bool {
+    let Some(token) = &state.race_token else {
+        return false;
+    };
+
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .is_some_and(|presented| constant_time_eq(presented.as_bytes(), token.as_bytes()))
+}
+
+// Compares without bailing out at the first difference, so the token can't be guessed a byte at a time from how long
+// the answer takes.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures;
+    use democracy_proto::Tally;
+
+    const TOKEN: &str = "sekrit";
+
+    fn state() -> (tempfile::TempDir, Arc<AppContext>) {
+        state_with_token(Some(TOKEN))
+    }
+
+    fn state_with_token(token: Option<&str>) -> (tempfile::TempDir, Arc<AppContext>) {
+        let (dir, context) = test_fixtures::context(0);
+        (
+            dir,
+            Arc::new(context.with_race_token(token.map(String::from))),
+        )
+    }
+
+    fn result(round: u64, winner: &str) -> RaceResult {
+        RaceResult {
+            run: 1,
+            round,
+            winner: winner.into(),
+            target_ms: 1_000,
+            cpu_ms: vec![Tally("summer1".into(), 1_000), Tally("summer2".into(), 200)],
+        }
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::AUTHORIZATION,
+            format!("Bearer {}", token).parse().unwrap(),
+        );
+        headers
+    }
+
+    async fn report(state: &Arc<AppContext>, result: RaceResult) -> Result<RaceResponse, AppError> {
+        report_handler(State(state.clone()), bearer(TOKEN), Json(result))
+            .await
+            .map(|Json(response)| response)
+    }
+
+    #[tokio::test]
+    async fn rounds_are_kept_in_order() {
+        let (_dir, state) = state();
+
+        let Json(response) = race_handler(State(state.clone())).await.unwrap();
+        assert!(response.results.is_empty());
+
+        report(&state, result(1, "SUMMER1")).await.unwrap();
+        let reported = report(&state, result(2, "summer2")).await.unwrap();
+
+        let Json(response) = race_handler(State(state)).await.unwrap();
+        assert_eq!(reported, response);
+        assert_eq!(
+            response.results,
+            vec![result(1, "summer1"), result(2, "summer2")]
+        );
+    }
+
+    #[tokio::test]
+    async fn winner_must_be_standing() {
+        let (_dir, state) = state();
+
+        let error = report(&state, result(1, "winter")).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::BAD_REQUEST);
+        assert!(state.races.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_scheduler_can_report() {
+        let (_dir, state) = state();
+
+        for headers in [HeaderMap::new(), bearer("guess")] {
+            let error = report_handler(State(state.clone()), headers, Json(result(1, "summer1")))
+                .await
+                .unwrap_err();
+            assert_eq!(error.status, StatusCode::UNAUTHORIZED);
+        }
+
+        // Without a token of its own, the ballot box doesn't take results from anyone.
+        let (_dir, untokened) = state_with_token(None);
+        let error = report(&untokened, result(1, "summer1")).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::UNAUTHORIZED);
+        assert!(untokened.races.read().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rounds_are_only_reported_once_and_the_oldest_are_forgotten() {
+        let (_dir, state) = state();
+
+        report(&state, result(1, "summer1")).await.unwrap();
+        let error = report(&state, result(1, "summer2")).await.unwrap_err();
+        assert_eq!(error.status, StatusCode::CONFLICT);
+
+        for round in 2..=MAX_RESULTS as u64 + 5 {
+            report(&state, result(round, "summer1")).await.unwrap();
+        }
+
+        let races = state.races.read().unwrap();
+        assert_eq!(races.len(), MAX_RESULTS);
+        assert_eq!(races[0].round, 6);
+    }
+
+    #[tokio::test]
+    async fn a_restarted_scheduler_counts_rounds_from_1_again() {
+        let (_dir, state) = state();
+
+        report(&state, result(1, "summer1")).await.unwrap();
+        let restarted = RaceResult {
+            run: 2,
+            ..result(1, "summer2")
+        };
+        let response = report(&state, restarted.clone()).await.unwrap();
+        assert_eq!(response.results, vec![result(1, "summer1"), restarted]);
+    }
+}
@@ -0,0 +1,221 @@
+//! Durable storage for votes.
+//!
+//! Every accepted vote is appended to a log file as a single line of JSON before it is counted or acknowledged.
+//! On startup the log is replayed to rebuild the tallies, so restarting (or crashing) the ballot box doesn't wipe the
+//! election.
+//!
+//! Records are written straight to the file (no userspace buffering), so once a vote has been acknowledged it will
+//! survive the process being killed. Surviving the machine losing power is what the [`FsyncPolicy`] controls.
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tracing::warn;
+
+/// When to flush the vote log to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FsyncPolicy {
+    /// Fsync after every vote before it is acknowledged. Slowest, but no acknowledged vote can be lost.
+    Always,
+
+    /// Fsync periodically in the background. A power loss can drop the votes cast since the last sync.
+    Interval,
+
+    /// Leave flushing entirely up to the operating system.
+    Never,
+}
+
+/// A single line in the vote log.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VoteRecord {
+    /// Epoch seconds of when the vote was accepted.
+    pub ts: u64,
+
+    /// The candidate id that was voted for; the first choice on a ranked ballot.
+    pub vote: String,
+
+    /// The full ranking for ballots with more than one choice. Single choice ballots leave this out so that the log
+    /// stays readable by (and compatible with) versions that only understood plain votes.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranking: Vec<String>,
+}
+
+impl VoteRecord {
+    /// Builds a record for a ballot; `ranking` must contain at least one choice.
+    pub fn new(ts: u64, mut ranking: Vec<String>) -> Self {
+        let vote = ranking[0].clone();
+        if ranking.len() == 1 {
+            ranking.clear();
+        }
+
+        Self { ts, vote, ranking }
+    }
+
+    /// The voter's choices from most to least preferred.
+    pub fn ranking(&self) -> Vec<&str> {
+        if self.ranking.is_empty() {
+            vec![self.vote.as_str()]
+        } else {
+            self.ranking.iter().map(String::as_str).collect()
+        }
+    }
+}
+
+pub struct VoteLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    fsync: FsyncPolicy,
+}
+
+impl VoteLog {
+    /// Opens (creating if needed) the vote log at `path` and returns it along with every record already in it.
+    ///
+    /// If the last record was only partially written (the process died mid-write) it is discarded and the file is
+    /// truncated back to the last complete record so new votes don't get glued onto the torn line.
+    pub fn open(path: &Path, fsync: FsyncPolicy) -> Result<(Self, Vec<VoteRecord>)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .with_context(|| format!("Could not open vote log '{}'", path.display()))?;
+
+        let mut records = vec![];
+        let mut valid_len: u64 = 0;
+        let mut reader = BufReader::new(&mut file);
+        let mut line = String::new();
+
+        loop {
+            line.clear();
+            let read = reader
+                .read_line(&mut line)
+                .with_context(|| format!("Could not read vote log '{}'", path.display()))?;
+
+            if read == 0 {
+                break;
+            }
+
+            if !line.ends_with('\n') {
+                warn!(path = %path.display(), "discarding partially written record at end of vote log");
+                break;
+            }
+
+            match serde_json::from_str::<VoteRecord>(line.trim_end()) {
+                Ok(record) => records.push(record),
+                Err(e) => {
+                    warn!(path = %path.display(), offset = valid_len, err = %e, "skipping unreadable vote log record")
+                }
+            }
+
+            valid_len += read as u64;
+        }
+
+        drop(reader);
+
+        if file.metadata()?.len() != valid_len {
+            file.set_len(valid_len)?;
+            file.seek(SeekFrom::End(0))?;
+        }
+
+        Ok((
+            Self {
+                path: path.to_path_buf(),
+                file: Mutex::new(file),
+                fsync,
+            },
+            records,
+        ))
+    }
+
+    /// Durably records a vote. Only once this returns successfully should the vote be counted and acknowledged.
+    pub fn append(&self, record: &VoteRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let mut file = self.file.lock().unwrap();
+
+        // A single write_all to a file opened in append mode keeps each record contiguous.
+        file.write_all(&line)
+            .with_context(|| format!("Could not write to vote log '{}'", self.path.display()))?;
+
+        if self.fsync == FsyncPolicy::Always {
+            file.sync_data()
+                .with_context(|| format!("Could not sync vote log '{}'", self.path.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Flushes everything written so far to disk. Used by [`FsyncPolicy::Interval`].
+    pub fn sync(&self) -> Result<()> {
+        self.file
+            .lock()
+            .unwrap()
+            .sync_data()
+            .with_context(|| format!("Could not sync vote log '{}'", self.path.display()))
+    }
+
+    pub fn fsync_policy(&self) -> FsyncPolicy {
+        self.fsync
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(vote: &str) -> VoteRecord {
+        VoteRecord::new(1, vec![vote.into()])
+    }
+
+    #[test]
+    fn replay_appended_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("votes.log");
+
+        let (log, records) = VoteLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert!(records.is_empty());
+        log.append(&record("summer1")).unwrap();
+        log.append(&record("summer2")).unwrap();
+        drop(log);
+
+        let (_, records) = VoteLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(records, vec![record("summer1"), record("summer2")]);
+    }
+
+    #[test]
+    fn ranked_records() {
+        let single = VoteRecord::new(1, vec!["summer1".into()]);
+        let ranked = VoteRecord::new(1, vec!["summer2".into(), "summer1".into()]);
+
+        assert_eq!(
+            serde_json::to_string(&single).unwrap(),
+            r#"{"ts":1,"vote":"summer1"}"#
+        );
+        assert_eq!(single.ranking(), vec!["summer1"]);
+        assert_eq!(ranked.vote, "summer2");
+        assert_eq!(ranked.ranking(), vec!["summer2", "summer1"]);
+    }
+
+    #[test]
+    fn discard_torn_record() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("votes.log");
+        std::fs::write(&path, "{\"ts\":1,\"vote\":\"summer1\"}\n{\"ts\":1,\"vo").unwrap();
+
+        let (log, records) = VoteLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(records, vec![record("summer1")]);
+
+        // New votes shouldn't end up glued onto the torn record.
+        log.append(&record("summer2")).unwrap();
+        drop(log);
+
+        let (_, records) = VoteLog::open(&path, FsyncPolicy::Never).unwrap();
+        assert_eq!(records, vec![record("summer1"), record("summer2")]);
+    }
+}
@@ -0,0 +1,262 @@
+//! `/api/votes/stream`: pushes the tally to clients as votes come in so they don't have to keep polling.
+//!
+//! Plain requests get Server-Sent Events, which is what the frontend's `EventSource` speaks; requests asking to upgrade
+//! get a WebSocket carrying the same JSON. Either way every message is a [`TallyEvent`].
+
+use crate::AppContext;
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use democracy_proto::TallyEvent;
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::debug;
+
+/// How many events a subscriber can fall behind by before it's skipped ahead to a fresh snapshot instead.
+pub const BACKLOG: usize = 64;
+
+/// A single client's feed of tally events.
+pub struct Subscription {
+    state: Arc<AppContext>,
+    receiver: broadcast::Receiver<TallyEvent>,
+    snapshot_due: bool, // whether the next event should list every candidate
+}
+
+impl Subscription {
+    pub fn new(state: Arc<AppContext>) -> Self {
+        // Subscribe before taking the snapshot so nothing counted in between is missed. At worst a vote shows up in
+        // both, which is harmless since events carry totals.
+        let receiver = state.updates.subscribe();
+
+        Self {
+            state,
+            receiver,
+            snapshot_due: true,
+        }
+    }
+
+    /// Waits for the next event. Returns `None` once the server is shutting down.
+    pub async fn next(&mut self) -> Option<TallyEvent> {
+        loop {
+            if std::mem::take(&mut self.snapshot_due) {
+                return Some(self.state.snapshot());
+            }
+
+            match self.receiver.recv().await {
+                Ok(event) => return Some(event),
+                Err(RecvError::Lagged(missed)) => {
+                    debug!(
+                        missed,
+                        "stream subscriber fell behind; sending a fresh snapshot"
+                    );
+                    self.snapshot_due = true;
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+pub async fn stream_handler(
+    State(state): State<Arc<AppContext>>,
+    upgrade: Option<WebSocketUpgrade>,
+) -> Response {
+    let subscription = Subscription::new(state);
+
+    match upgrade {
+        Some(upgrade) => upgrade.on_upgrade(|socket| send_over_websocket(socket, subscription)),
+        None => {
+            let events = futures_util::stream::unfold(subscription, |mut subscription| async {
+                let event = subscription.next().await?;
+                Some((Event::default().json_data(event), subscription))
+            });
+
+            Sse::new(events)
+                .keep_alive(KeepAlive::default())
+                .into_response()
+        }
+    }
+}
+
+async fn send_over_websocket(mut socket: WebSocket, mut subscription: Subscription) {
+    loop {
+        tokio::select! {
+            event = subscription.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+
+                let message = Message::Text(serde_json::to_string(&event).unwrap());
+                if socket.send(message).await.is_err() {
+                    break;
+                }
+            }
+
+            // Clients aren't expected to say anything, but we need to notice when they hang up.
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_fixtures::{serve, state};
+    use democracy_proto::{routes, Tally};
+    use futures_util::StreamExt;
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+    use tokio::net::TcpStream;
+
+    async fn next_sse_event(lines: &mut Lines<BufReader<TcpStream>>) -> TallyEvent {
+        loop {
+            let line = lines.next_line().await.unwrap().unwrap();
+            if let Some(data) = line.strip_prefix("data:") {
+                return serde_json::from_str(data.trim()).unwrap();
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_everything_then_changes() {
+        let (_dir, state) = state(0);
+        state.count(vec![0]);
+
+        let mut subscription = Subscription::new(state.clone());
+        let first = subscription.next().await.unwrap();
+        assert_eq!(
+            first.changed,
+            vec![Tally("summer1".into(), 1), Tally("summer2".into(), 0)]
+        );
+        assert_eq!(first.winner.winner.as_deref(), Some("summer1"));
+
+        state.count(vec![1, 0]);
+        state.count(vec![1]);
+        state.recount();
+
+        let second = subscription.next().await.unwrap();
+        assert_eq!(second.changed, vec![Tally("summer2".into(), 1)]);
+
+        let third = subscription.next().await.unwrap();
+        assert_eq!(third.changed, vec![Tally("summer2".into(), 2)]);
+
+        // The winner catches up once the votes have been recounted.
+        let recounted = subscription.next().await.unwrap();
+        assert!(recounted.changed.is_empty());
+        assert_eq!(recounted.winner.winner.as_deref(), Some("summer2"));
+    }
+
+    #[tokio::test]
+    async fn lagging_subscribers_catch_up_with_a_snapshot() {
+        let (_dir, state) = state(0);
+
+        let mut subscription = Subscription::new(state.clone());
+        subscription.next().await.unwrap();
+
+        for _ in 0..BACKLOG + 10 {
+            state.count(vec![0]);
+        }
+
+        let event = subscription.next().await.unwrap();
+        assert_eq!(
+            event.changed,
+            vec![
+                Tally("summer1".into(), BACKLOG as u64 + 10),
+                Tally("summer2".into(), 0)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn server_sent_events() {
+        let (_dir, state) = state(0);
+        let addr = serve(state.clone()).await;
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nAccept: text/event-stream\r\n\r\n",
+                    routes::VOTES_STREAM
+                )
+                .as_bytes(),
+            )
+            .await
+            .unwrap();
+
+        let mut lines = BufReader::new(stream).lines();
+
+        assert_eq!(next_sse_event(&mut lines).await.winner.ballots, 0);
+
+        state.count(vec![1]);
+        state.recount();
+
+        let event = next_sse_event(&mut lines).await;
+        assert_eq!(event.changed, vec![Tally("summer2".into(), 1)]);
+        assert_eq!(next_sse_event(&mut lines).await.winner.ballots, 1);
+    }
+
+    #[tokio::test]
+    async fn websocket() {
+        let (_dir, state) = state(0);
+        let addr = serve(state.clone()).await;
+
+        let (mut socket, _) =
+            tokio_tungstenite::connect_async(format!("ws://{}{}", addr, routes::VOTES_STREAM))
+                .await
+                .unwrap();
+
+        let message = socket.next().await.unwrap().unwrap();
+        let event: TallyEvent = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(event.changed.len(), 2);
+
+        state.count(vec![0]);
+        state.recount();
+
+        let message = socket.next().await.unwrap().unwrap();
+        let event: TallyEvent = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(event.changed, vec![Tally("summer1".into(), 1)]);
+
+        let message = socket.next().await.unwrap().unwrap();
+        let event: TallyEvent = serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(event.winner.winner.as_deref(), Some("summer1"));
+    }
+
+    #[tokio::test]
+    async fn bursts_of_votes_are_recounted_together() {
+        let (_dir, state) = state(0);
+        tokio::spawn(crate::recount_winner(
+            state.clone(),
+            std::time::Duration::from_millis(50),
+        ));
+
+        let mut subscription = Subscription::new(state.clone());
+        subscription.next().await.unwrap();
+
+        for _ in 0..3 {
+            state.count(vec![1]);
+        }
+        for total in 1..=3 {
+            let event = subscription.next().await.unwrap();
+            assert_eq!(event.changed, vec![Tally("summer2".into(), total)]);
+            assert_eq!(event.winner.ballots, 0);
+        }
+
+        let recounted = subscription.next().await.unwrap();
+        assert!(recounted.changed.is_empty());
+        assert_eq!(recounted.winner.ballots, 3);
+        assert_eq!(
+            state.winner.read().unwrap().winner.as_deref(),
+            Some("summer2")
+        );
+    }
+}
@@ -0,0 +1,438 @@
+//! Counting methods used to decide the winner of an election.
+//!
+//! Every method works off the same ranked ballots (lists of roster positions from most to least preferred); a plain
+//! single choice vote is just a ranking with one entry. Which method an election uses is set in the roster file.
+
+use crate::irv;
+use democracy_proto::{roster::Roster, CountingMethod, Tally, WinnerResponse};
+
+/// A way of turning ballots into a winner.
+pub trait TallyMethod: Send + Sync {
+    fn method(&self) -> CountingMethod;
+
+    /// Counts the ballots. Ballots are lists of roster positions from most to least preferred.
+    fn tally(&self, roster: &Roster, ballots: &[Vec<usize>]) -> WinnerResponse;
+}
+
+/// Returns the implementation for the given counting method.
+pub fn for_method(method: CountingMethod) -> Box<dyn TallyMethod> {
+    match method {
+        CountingMethod::Plurality => Box::new(Plurality),
+        CountingMethod::Approval => Box::new(Approval),
+        CountingMethod::Borda => Box::new(Borda),
+        CountingMethod::Schulze => Box::new(Schulze),
+        CountingMethod::Irv => Box::new(InstantRunoff),
+    }
+}
+
+/// Whoever is ranked first on the most ballots wins.
+pub struct Plurality;
+
+impl TallyMethod for Plurality {
+    fn method(&self) -> CountingMethod {
+        CountingMethod::Plurality
+    }
+
+    fn tally(&self, roster: &Roster, ballots: &[Vec<usize>]) -> WinnerResponse {
+        let mut scores = vec![0; roster.candidates.len()];
+        for ballot in ballots {
+            scores[ballot[0]] += 1;
+        }
+
+        highest_score(self.method(), roster, ballots, scores, "first choice votes")
+    }
+}
+
+/// Every candidate listed on a ballot gets a point, so voters can back as many candidates as they like.
+pub struct Approval;
+
+impl TallyMethod for Approval {
+    fn method(&self) -> CountingMethod {
+        CountingMethod::Approval
+    }
+
+    fn tally(&self, roster: &Roster, ballots: &[Vec<usize>]) -> WinnerResponse {
+        let mut scores = vec![0; roster.candidates.len()];
+        for &choice in ballots.iter().flatten() {
+            scores[choice] += 1;
+        }
+
+        highest_score(self.method(), roster, ballots, scores, "approvals")
+    }
+}
+
+/// With n candidates a first choice is worth n-1 points, a second choice n-2 and so on. Unranked candidates get
+/// nothing.
+pub struct Borda;
+
+impl TallyMethod for Borda {
+    fn method(&self) -> CountingMethod {
+        CountingMethod::Borda
+    }
+
+    fn tally(&self, roster: &Roster, ballots: &[Vec<usize>]) -> WinnerResponse {
+        let n = roster.candidates.len() as u64;
+        let mut scores = vec![0; roster.candidates.len()];
+        for ballot in ballots {
+            for (rank, &choice) in ballot.iter().enumerate() {
+                scores[choice] += n - 1 - rank as u64;
+            }
+        }
+
+        highest_score(self.method(), roster, ballots, scores, "Borda points")
+    }
+}
+
+/// The candidate that beats every other candidate head to head (the Condorcet winner). When preferences are cyclic
+/// and there isn't one, the Schulze method compares the strongest chains of head to head wins instead.
+///
+/// A candidate's score is the number of opponents they beat under the Schulze ordering.
+pub struct Schulze;
+
+impl TallyMethod for Schulze {
+    fn method(&self) -> CountingMethod {
+        CountingMethod::Schulze
+    }
+
+    fn tally(&self, roster: &Roster, ballots: &[Vec<usize>]) -> WinnerResponse {
+        let n = roster.candidates.len();
+
+        // preferred[i][j] is the number of ballots ranking i above j. Unranked candidates count as ranked below
+        // everyone that was ranked.
+        let mut preferred = vec![vec![0u64; n]; n];
+        for ballot in ballots {
+            for (rank, &i) in ballot.iter().enumerate() {
+                for (j, count) in preferred[i].iter_mut().enumerate() {
+                    if i != j && !ballot[..rank].contains(&j) {
+                        *count += 1;
+                    }
+                }
+            }
+        }
+
+        // strength[i][j] is the strength of the strongest path of head to head wins from i to j.
+        let mut strength = vec![vec![0u64; n]; n];
+        for i in 0..n {
+            for j in 0..n {
+                if i != j && preferred[i][j] > preferred[j][i] {
+                    strength[i][j] = preferred[i][j];
+                }
+            }
+        }
+
+        for k in 0..n {
+            for i in (0..n).filter(|&i| i != k) {
+                for j in (0..n).filter(|&j| j != k && j != i) {
+                    strength[i][j] = strength[i][j].max(strength[i][k].min(strength[k][j]));
+                }
+            }
+        }
+
+        let beats = |i: usize, j: usize| strength[i][j] > strength[j][i];
+        let scores: Vec<u64> = (0..n)
+            .map(|i| (0..n).filter(|&j| beats(i, j)).count() as u64)
+            .collect();
+        let leaders: Vec<usize> = (0..n)
+            .filter(|&i| (0..n).all(|j| i == j || !beats(j, i)))
+            .collect();
+
+        let condorcet = leaders.len() == 1
+            && (0..n)
+                .all(|j| j == leaders[0] || preferred[leaders[0]][j] > preferred[j][leaders[0]]);
+
+        let explanation = if ballots.is_empty() {
+            no_votes_explanation()
+        } else if leaders.len() == 1 && condorcet {
+            format!(
+                "{} beats every other candidate head to head",
+                roster.candidates[leaders[0]].id
+            )
+        } else if leaders.len() == 1 {
+            format!(
+                "There is no Condorcet winner; {} has the strongest chain of head to head wins",
+                roster.candidates[leaders[0]].id
+            )
+        } else {
+            format!(
+                "{} can't be separated by their head to head results",
+                id_list(roster, &leaders)
+            )
+        };
+
+        outcome(self.method(), roster, ballots, scores, leaders, explanation)
+    }
+}
+
+/// Instant-runoff; see [`irv::tabulate`]. A candidate's score is their vote count in the final round, zero if they
+/// were eliminated before it.
+pub struct InstantRunoff;
+
+impl TallyMethod for InstantRunoff {
+    fn method(&self) -> CountingMethod {
+        CountingMethod::Irv
+    }
+
+    fn tally(&self, roster: &Roster, ballots: &[Vec<usize>]) -> WinnerResponse {
+        let results = irv::tabulate(roster, ballots);
+        let last_round = results.rounds.last();
+
+        let scores: Vec<u64> = roster
+            .candidates
+            .iter()
+            .map(|candidate| {
+                last_round
+                    .and_then(|round| round.tallies.iter().find(|tally| tally.0 == candidate.id))
+                    .map(|tally| tally.1)
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        let explanation = if ballots.is_empty() {
+            no_votes_explanation()
+        } else {
+            match &results.winner {
+                Some(winner) => format!(
+                    "{} won a majority after {} round(s) of instant-runoff",
+                    winner,
+                    results.rounds.len()
+                ),
+                None => format!(
+                    "{} are tied after {} round(s) of instant-runoff",
+                    results.tied.join(" and "),
+                    results.rounds.len()
+                ),
+            }
+        };
+
+        WinnerResponse {
+            method: self.method(),
+            winner: results.winner,
+            tied: results.tied,
+            scores: tallies(roster, &scores),
+            ballots: ballots.len() as u64,
+            explanation,
+        }
+    }
+}
+
+/// Picks whoever has the highest score, for methods where that's all there is to it.
+fn highest_score(
+    method: CountingMethod,
+    roster: &Roster,
+    ballots: &[Vec<usize>],
+    scores: Vec<u64>,
+    unit: &str,
+) -> WinnerResponse {
+    let top = scores.iter().copied().max().unwrap_or(0);
+    let leaders: Vec<usize> = (0..scores.len()).filter(|&i| scores[i] == top).collect();
+
+    let explanation = if ballots.is_empty() {
+        no_votes_explanation()
+    } else if leaders.len() == 1 {
+        format!(
+            "{} has the most {} ({} from {} ballot(s))",
+            roster.candidates[leaders[0]].id,
+            unit,
+            top,
+            ballots.len()
+        )
+    } else {
+        format!(
+            "{} are tied with {} {} each",
+            id_list(roster, &leaders),
+            top,
+            unit
+        )
+    };
+
+    outcome(method, roster, ballots, scores, leaders, explanation)
+}
+
+/// Builds the response from the candidates sharing first place; more than one of them is a tie.
+fn outcome(
+    method: CountingMethod,
+    roster: &Roster,
+    ballots: &[Vec<usize>],
+    scores: Vec<u64>,
+    leaders: Vec<usize>,
+    explanation: String,
+) -> WinnerResponse {
+    let ids = |positions: &[usize]| {
+        positions
+            .iter()
+            .map(|&i| roster.candidates[i].id.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // Without any votes nobody is ahead of anyone else, whatever the method.
+    let (winner, tied) = if ballots.is_empty() {
+        (None, ids(&(0..roster.candidates.len()).collect::<Vec<_>>()))
+    } else if leaders.len() == 1 {
+        (Some(roster.candidates[leaders[0]].id.clone()), vec![])
+    } else {
+        (None, ids(&leaders))
+    };
+
+    WinnerResponse {
+        method,
+        winner,
+        tied,
+        scores: tallies(roster, &scores),
+        ballots: ballots.len() as u64,
+        explanation,
+    }
+}
+
+fn tallies(roster: &Roster, scores: &[u64]) -> Vec<Tally> {
+    roster
+        .candidates
+        .iter()
+        .zip(scores)
+        .map(|(candidate, &score)| Tally(candidate.id.clone(), score))
+        .collect()
+}
+
+fn id_list(roster: &Roster, positions: &[usize]) -> String {
+    positions
+        .iter()
+        .map(|&i| roster.candidates[i].id.as_str())
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+fn no_votes_explanation() -> String {
+    "No votes have been cast yet".into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roster() -> Roster {
+        Roster::parse(
+            r#"
+            [[candidates]]
+            id = "a"
+            name = "A"
+
+            [[candidates]]
+            id = "b"
+            name = "B"
+
+            [[candidates]]
+            id = "c"
+            name = "C"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn ballots(spec: &[(usize, &[usize])]) -> Vec<Vec<usize>> {
+        spec.iter()
+            .flat_map(|&(count, ballot)| std::iter::repeat_n(ballot.to_vec(), count))
+            .collect()
+    }
+
+    #[test]
+    fn plurality_counts_first_choices() {
+        let results = Plurality.tally(&roster(), &ballots(&[(2, &[0, 1]), (1, &[1, 0])]));
+
+        assert_eq!(results.winner.as_deref(), Some("a"));
+        assert_eq!(
+            results.scores,
+            vec![
+                Tally("a".into(), 2),
+                Tally("b".into(), 1),
+                Tally("c".into(), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn plurality_reports_ties() {
+        let results = Plurality.tally(&roster(), &ballots(&[(1, &[0]), (1, &[2])]));
+
+        assert_eq!(results.winner, None);
+        assert_eq!(results.tied, vec!["a".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn approval_counts_every_listed_candidate() {
+        let results = Approval.tally(&roster(), &ballots(&[(2, &[0]), (2, &[1, 2]), (1, &[2])]));
+
+        assert_eq!(results.winner.as_deref(), Some("c"));
+        assert_eq!(results.scores[2], Tally("c".into(), 3));
+    }
+
+    #[test]
+    fn borda_rewards_broad_support() {
+        // a has the most first choices, but b is everyone's first or second choice.
+        let results = Borda.tally(
+            &roster(),
+            &ballots(&[(3, &[0, 1, 2]), (2, &[1, 2, 0]), (2, &[2, 1, 0])]),
+        );
+
+        assert_eq!(results.winner.as_deref(), Some("b"));
+        assert_eq!(results.scores[1], Tally("b".into(), 9));
+    }
+
+    #[test]
+    fn schulze_finds_condorcet_winner() {
+        let results = Schulze.tally(
+            &roster(),
+            &ballots(&[(3, &[0, 1, 2]), (2, &[1, 2, 0]), (2, &[2, 1, 0])]),
+        );
+
+        assert_eq!(results.winner.as_deref(), Some("b"));
+        assert!(results.explanation.contains("head to head"));
+    }
+
+    #[test]
+    fn schulze_resolves_cycles() {
+        // a beats b 5:2, b beats c 5:2 and c beats a 4:3. c > a is the weakest link in the cycle, so a wins.
+        let results = Schulze.tally(
+            &roster(),
+            &ballots(&[(3, &[0, 1, 2]), (2, &[1, 2, 0]), (2, &[2, 0, 1])]),
+        );
+
+        assert_eq!(results.winner.as_deref(), Some("a"));
+        assert!(results.explanation.contains("no Condorcet winner"));
+        assert_eq!(
+            results.scores,
+            vec![
+                Tally("a".into(), 2),
+                Tally("b".into(), 1),
+                Tally("c".into(), 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn irv_uses_runoff_winner() {
+        let results = InstantRunoff.tally(
+            &roster(),
+            &ballots(&[(4, &[0]), (3, &[1, 0]), (2, &[2, 1])]),
+        );
+
+        assert_eq!(results.winner.as_deref(), Some("b"));
+        assert_eq!(results.scores[2], Tally("c".into(), 0));
+    }
+
+    #[test]
+    fn no_votes_ties_everyone() {
+        for method in [
+            CountingMethod::Plurality,
+            CountingMethod::Approval,
+            CountingMethod::Borda,
+            CountingMethod::Schulze,
+            CountingMethod::Irv,
+        ] {
+            let results = for_method(method).tally(&roster(), &[]);
+
+            assert_eq!(results.method, method);
+            assert_eq!(results.winner, None);
+            assert_eq!(results.ballots, 0);
+            assert_eq!(results.tied.len(), 3);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+//! Kills the ballot box while it's in the middle of accepting votes and makes sure every vote it acknowledged is
+//! still there when it comes back up.
+
+use democracy_proto::VotesResponse;
+use std::{
+    io::{Read, Write},
+    net::{TcpListener, TcpStream},
+    path::Path,
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const CANDIDATES: [&str; 2] = ["summer1", "summer2"];
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn start_server(dir: &Path, port: u16) -> Child {
+    let child = Command::new(env!("CARGO_BIN_EXE_ballot_box"))
+        .arg(format!("127.0.0.1:{port}"))
+        .arg("--roster")
+        .arg(dir.join("roster.toml"))
+        .arg("--vote-log")
+        .arg(dir.join("votes.log"))
+        .args(["--fsync", "always", "--rate-limit-secs", "0"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("could not start ballot_box");
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        assert!(
+            Instant::now() < deadline,
+            "ballot_box never started listening"
+        );
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    child
+}
+
+/// Makes a bare-bones HTTP/1.1 request and returns the status code and body.
+fn request(port: u16, method: &str, path: &str, body: &str) -> std::io::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port))?;
+    write!(
+        stream,
+        "{method} {path} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+
+    let status = response
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or(0);
+    let body = response
+        .split_once("\r\n\r\n")
+        .map(|(_, body)| body.to_string())
+        .unwrap_or_default();
+
+    Ok((status, body))
+}
+
+#[test]
+fn acknowledged_votes_survive_a_crash() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join("roster.toml"),
+        r#"
+        [[candidates]]
+        id = "summer1"
+        name = "Summer 1"
+
+        [[candidates]]
+        id = "summer2"
+        name = "Summer 2"
+        "#,
+    )
+    .unwrap();
+
+    let port = free_port();
+    let mut server = start_server(dir.path(), port);
+
+    let acked: Arc<[AtomicU64; 2]> = Arc::new([AtomicU64::new(0), AtomicU64::new(0)]);
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let voters: Vec<_> = (0..4)
+        .map(|voter| {
+            let acked = acked.clone();
+            let stop = stop.clone();
+
+            std::thread::spawn(move || {
+                let mut i = voter;
+                while !stop.load(Ordering::Relaxed) {
+                    let candidate = i % CANDIDATES.len();
+                    i += 1;
+
+                    let body = format!(r#"{{"vote":"{}"}}"#, CANDIDATES[candidate]);
+                    match request(port, "POST", "/api/votes", &body) {
+                        // Only a complete response counts as an acknowledgement.
+                        Ok((200, body)) if body.contains("current_tally") => {
+                            acked[candidate].fetch_add(1, Ordering::SeqCst);
+                        }
+                        Ok(_) => {}
+                        Err(_) => std::thread::sleep(Duration::from_millis(5)),
+                    }
+                }
+            })
+        })
+        .collect();
+
+    // Let a decent number of votes through and then pull the plug while the voters are still going.
+    let deadline = Instant::now() + Duration::from_secs(30);
+    while acked.iter().map(|n| n.load(Ordering::SeqCst)).sum::<u64>() < 200 {
+        assert!(Instant::now() < deadline, "votes were not being accepted");
+        std::thread::sleep(Duration::from_millis(10));
+    }
+
+    server.kill().unwrap(); // SIGKILL; no chance to clean up.
+    server.wait().unwrap();
+
+    stop.store(true, Ordering::Relaxed);
+    for voter in voters {
+        voter.join().unwrap();
+    }
+
+    let mut server = start_server(dir.path(), port);
+    let (status, body) = request(port, "GET", "/api/votes", "").unwrap();
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    assert_eq!(status, 200);
+    let recovered: VotesResponse = serde_json::from_str(&body).unwrap();
+
+    for (i, candidate) in CANDIDATES.iter().enumerate() {
+        let tally = recovered
+            .votes
+            .iter()
+            .find(|tally| tally.0 == *candidate)
+            .unwrap();
+        let acked = acked[i].load(Ordering::SeqCst);
+
+        // A vote can be on disk without its acknowledgement making it back before the crash, so the recovered tally
+        // may be higher, but it can never be lower.
+        assert!(
+            tally.1 >= acked,
+            "{candidate}: {acked} votes were acknowledged but only {} were recovered",
+            tally.1
+        );
+    }
+}
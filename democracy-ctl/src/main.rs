@@ -0,0 +1,273 @@
+//! `democracy-ctl`: asks a running democracy-scheduler how it's getting on, and changes its settings, over its control
+//! socket. See [`democracy_proto::control`] for the protocol.
+
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand};
+use democracy_proto::control::{self, PolicyChange, ProgramState, Request, Response};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// How long to wait for the scheduler to answer. It answers between scheduling decisions, so this needs to be well over
+// its schedule interval.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "Queries and configures a running democracy-scheduler"
+)]
+struct Args {
+    /// The scheduler's control socket.
+    #[arg(long, env = "DEMOCRACY_CONTROL_SOCKET", default_value = control::DEFAULT_SOCKET)]
+    socket: PathBuf,
+
+    /// Print the scheduler's answer as JSON, exactly as it was sent.
+    #[arg(long)]
+    json: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// How the scheduler is getting on overall.
+    Stats,
+
+    /// The settings deciding how votes become CPU time.
+    Policy,
+
+    /// How each candidate is getting on.
+    Candidates,
+
+    /// The BPF side's counters.
+    Bpf,
+
+    /// Change some of the scheduler's settings while it runs. Anything not given stays as it is.
+    Set(SetArgs),
+}
+
+#[derive(Debug, clap::Args)]
+struct SetArgs {
+    /// How CPU time is handed out: `winner-takes-all`, `proportional`, `round-robin` or `race`.
+    #[arg(long)]
+    policy: Option<String>,
+
+    /// Who gets to run when candidates are tied for first place, e.g. `round-robin`.
+    #[arg(long)]
+    tie_policy: Option<String>,
+
+    /// How often the scheduler makes a decision, in milliseconds.
+    #[arg(long)]
+    schedule_interval_ms: Option<u64>,
+
+    /// Slice the winner is dispatched with under winner-takes-all, and every candidate that runs under round-robin and
+    /// race, in microseconds.
+    #[arg(long)]
+    winner_slice_us: Option<u64>,
+
+    /// Slice tasks that aren't candidates are dispatched with, in microseconds.
+    #[arg(long)]
+    fallback_slice_us: Option<u64>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let request = match args.command {
+        Command::Stats => Request::Stats,
+        Command::Policy => Request::Policy,
+        Command::Candidates => Request::Candidates,
+        Command::Bpf => Request::Bpf,
+        Command::Set(set) => Request::Set(PolicyChange {
+            policy: set.policy,
+            tie_policy: set.tie_policy,
+            schedule_interval_ms: set.schedule_interval_ms,
+            winner_slice_us: set.winner_slice_us,
+            fallback_slice_us: set.fallback_slice_us,
+        }),
+    };
+
+    let response = ask(&args.socket, &request)?;
+    if let Response::Error { error } = &response {
+        bail!("The scheduler couldn't do that: {}", error);
+    }
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&response)?);
+    } else {
+        print!("{}", describe(&response));
+    }
+
+    Ok(())
+}
+
+// Sends a single request and waits for the answer.
+fn ask(socket: &Path, request: &Request) -> Result<Response> {
+    let stream = UnixStream::connect(socket).with_context(|| {
+        format!(
+            "Could not connect to the scheduler at {}; is it running?",
+            socket.display()
+        )
+    })?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+
+    let mut json = serde_json::to_string(request)?;
+    json.push('\n');
+    (&stream)
+        .write_all(json.as_bytes())
+        .context("Could not send request")?;
+
+    let mut answer = String::new();
+    BufReader::new(&stream)
+        .read_line(&mut answer)
+        .context("The scheduler didn't answer")?;
+    if answer.is_empty() {
+        bail!("The scheduler hung up without answering");
+    }
+
+    serde_json::from_str(&answer).context("Could not make sense of the scheduler's answer")
+}
+
+// The answer, laid out for people rather than programs.
+fn describe(response: &Response) -> String {
+    let mut out = String::new();
+    let mut line = |label: &str, value: String| out.push_str(&format!("{:<22}{}\n", label, value));
+
+    match response {
+        Response::Stats(stats) => {
+            line(
+                "uptime",
+                format!("{:?}", Duration::from_millis(stats.uptime_ms)),
+            );
+            line(
+                "winner",
+                match (&stats.winner, stats.tied.as_slice()) {
+                    (Some(winner), _) => winner.clone(),
+                    (None, []) => "nobody".into(),
+                    (None, tied) => format!("tied between {}", tied.join(", ")),
+                },
+            );
+            line("ballots", stats.ballots.to_string());
+            line(
+                "votes",
+                if stats.votes_stale {
+                    "out of date"
+                } else {
+                    "up to date"
+                }
+                .into(),
+            );
+            line("queued tasks", stats.queued_tasks.to_string());
+            line(
+                "candidate dispatches",
+                stats.candidate_dispatches.to_string(),
+            );
+            line("fallback dispatches", stats.fallback_dispatches.to_string());
+        }
+        Response::Policy(policy) => {
+            line("policy", policy.policy.clone());
+            line("tie policy", policy.tie_policy.clone());
+            line(
+                "schedule interval",
+                format!("{}ms", policy.schedule_interval_ms),
+            );
+            line("winner slice", format!("{}us", policy.winner_slice_us));
+            line("fallback slice", format!("{}us", policy.fallback_slice_us));
+        }
+        Response::Candidates { candidates } => {
+            out.push_str(&format!(
+                "{:<16}{:<20}{:>8}{:>12}{:>12}{:>8}  {}\n",
+                "ID", "NAME", "SCORE", "CPU", "DISPATCHES", "QUEUED", "PROGRAM"
+            ));
+            for candidate in candidates {
+                out.push_str(&format!(
+                    "{:<16}{:<20}{:>8}{:>12}{:>12}{:>8}  {}\n",
+                    candidate.id,
+                    candidate.name,
+                    candidate.score,
+                    format!("{:.1}s", candidate.cpu_ms as f64 / 1000.0),
+                    candidate.dispatches,
+                    candidate.queued_tasks,
+                    program(candidate.program)
+                ));
+            }
+        }
+        Response::Bpf { counters } => {
+            for counter in counters {
+                out.push_str(&format!("{:<24}{}\n", counter.0, counter.1));
+            }
+        }
+        Response::Error { error } => out.push_str(&format!("error: {}\n", error)),
+    }
+
+    out
+}
+
+fn program(state: ProgramState) -> &'static str {
+    match state {
+        ProgramState::Attached => "attached",
+        ProgramState::Pending => "pending",
+        ProgramState::Running => "running",
+        ProgramState::Restarting => "restarting",
+        ProgramState::Failed => "failed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use democracy_proto::control::CandidateStats;
+    use std::os::unix::net::UnixListener;
+
+    #[test]
+    fn asks_over_the_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+
+        // Stands in for the scheduler, answering a single request.
+        let scheduler = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut request = String::new();
+            BufReader::new(&stream).read_line(&mut request).unwrap();
+
+            let response = Response::Candidates {
+                candidates: vec![CandidateStats {
+                    id: "summer1".into(),
+                    name: "Summer 1".into(),
+                    score: 3,
+                    cpu_ms: 1_500,
+                    dispatches: 12,
+                    queued_tasks: 1,
+                    program: ProgramState::Running,
+                }],
+            };
+            writeln!(&stream, "{}", serde_json::to_string(&response).unwrap()).unwrap();
+
+            serde_json::from_str::<Request>(&request).unwrap()
+        });
+
+        let response = ask(&path, &Request::Candidates).unwrap();
+        assert_eq!(scheduler.join().unwrap(), Request::Candidates);
+
+        let described = describe(&response);
+        assert!(described.starts_with("ID"));
+        assert!(described
+            .lines()
+            .nth(1)
+            .is_some_and(|line| line.starts_with("summer1")
+                && line.contains("1.5s")
+                && line.ends_with("running")));
+    }
+
+    #[test]
+    fn says_when_the_scheduler_isnt_running() {
+        let dir = tempfile::tempdir().unwrap();
+        let error = ask(&dir.path().join("control.sock"), &Request::Stats).unwrap_err();
+
+        assert!(error.to_string().contains("is it running?"));
+    }
+}
@@ -0,0 +1,208 @@
+//! The scheduler's control socket: a Unix domain socket that `democracy-ctl` (or `socat`, or a script) uses to ask a
+//! running scheduler how it's getting on and to change its settings without restarting it.
+//!
+//! The protocol is newline-delimited JSON: the client writes a [`Request`] on a line of its own and the scheduler
+//! answers with a [`Response`] on a line of its own. A connection can be used for as many requests as the client
+//! likes.
+
+use crate::{CandidateId, Tally};
+use serde::{Deserialize, Serialize};
+
+/// Where the scheduler listens unless told otherwise.
+pub const DEFAULT_SOCKET: &str = "/run/democracy-scheduler.sock";
+
+/// Something to ask the scheduler, e.g. `{"request":"stats"}` or
+/// `{"request":"set","winner_slice_us":50000}`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "request", rename_all = "snake_case")]
+pub enum Request {
+    /// How the scheduler is getting on overall; answered with [`Response::Stats`].
+    Stats,
+
+    /// The settings deciding how votes become CPU time; answered with [`Response::Policy`].
+    Policy,
+
+    /// How each candidate is getting on; answered with [`Response::Candidates`].
+    Candidates,
+
+    /// The BPF side's counters; answered with [`Response::Bpf`].
+    Bpf,
+
+    /// Changes some of the settings, leaving the rest alone; answered with [`Response::Policy`] showing the new
+    /// settings. Nothing is changed if any of them aren't valid.
+    Set(PolicyChange),
+}
+
+/// The scheduler's answer to a [`Request`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "response", rename_all = "snake_case")]
+pub enum Response {
+    Stats(Stats),
+    Policy(Policy),
+    Candidates {
+        candidates: Vec<CandidateStats>,
+    },
+
+    /// Each of the BPF side's counters by name, e.g. `["nr_failed_dispatches", 0]`.
+    Bpf {
+        counters: Vec<Tally>,
+    },
+
+    /// The request couldn't be carried out.
+    Error {
+        error: String,
+    },
+}
+
+/// Answer to [`Request::Stats`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Stats {
+    /// How long the scheduler has been running, in milliseconds.
+    pub uptime_ms: u64,
+
+    /// Who's winning the vote, as the ballot box last reported it.
+    pub winner: Option<CandidateId>,
+
+    /// The candidates sharing first place when there's no outright winner.
+    pub tied: Vec<CandidateId>,
+
+    /// The number of ballots counted.
+    pub ballots: u64,
+
+    /// Whether the election results are out of date because the vote source has stopped answering.
+    pub votes_stale: bool,
+
+    /// Candidates' tasks waiting to be dispatched.
+    pub queued_tasks: u64,
+
+    /// Tasks dispatched for the candidates so far.
+    pub candidate_dispatches: u64,
+
+    /// Tasks dispatched so far that don't belong to any candidate.
+    pub fallback_dispatches: u64,
+}
+
+/// How a single candidate is getting on, in answer to [`Request::Candidates`].
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CandidateStats {
+    pub id: CandidateId,
+    pub name: String,
+
+    /// The candidate's score in the election.
+    pub score: u64,
+
+    /// CPU time the candidate has had, in milliseconds.
+    pub cpu_ms: u64,
+
+    /// Tasks dispatched for the candidate so far.
+    pub dispatches: u64,
+
+    /// The candidate's tasks waiting to be dispatched.
+    pub queued_tasks: u64,
+
+    /// Whether the candidate's program is up.
+    pub program: ProgramState,
+}
+
+/// Where a candidate's program is in its life, as far as the scheduler knows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgramState {
+    /// Already running when the scheduler started; the scheduler attached to it and doesn't restart it.
+    Attached,
+
+    /// Not launched yet.
+    Pending,
+
+    Running,
+
+    /// Exited, and waiting to be restarted.
+    Restarting,
+
+    /// Exited too many times in a row to be restarted again, or stopped along with the scheduler.
+    Failed,
+}
+
+/// The settings deciding how votes become CPU time, in answer to [`Request::Policy`] and [`Request::Set`]. Policies are
+/// named as they are on the scheduler's command line, e.g. `winner-takes-all`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Policy {
+    pub policy: String,
+    pub tie_policy: String,
+    pub schedule_interval_ms: u64,
+    pub winner_slice_us: u64,
+    pub fallback_slice_us: u64,
+}
+
+/// Body of [`Request::Set`]: the settings to change. Anything left out stays as it is.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyChange {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub policy: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tie_policy: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub schedule_interval_ms: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub winner_slice_us: Option<u64>,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fallback_slice_us: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_match_wire_format() {
+        assert_eq!(
+            serde_json::to_string(&Request::Stats).unwrap(),
+            r#"{"request":"stats"}"#
+        );
+
+        let request: Request =
+            serde_json::from_str(r#"{"request":"set","winner_slice_us":50000}"#).unwrap();
+        assert_eq!(
+            request,
+            Request::Set(PolicyChange {
+                winner_slice_us: Some(50_000),
+                ..Default::default()
+            })
+        );
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"request":"set","winner_slice_us":50000}"#
+        );
+    }
+
+    #[test]
+    fn responses_round_trip() {
+        for response in [
+            Response::Candidates {
+                candidates: vec![CandidateStats {
+                    id: "summer1".into(),
+                    name: "Summer 1".into(),
+                    score: 3,
+                    cpu_ms: 1_500,
+                    dispatches: 12,
+                    queued_tasks: 1,
+                    program: ProgramState::Restarting,
+                }],
+            },
+            Response::Bpf {
+                counters: vec![Tally("nr_failed_dispatches".into(), 0)],
+            },
+            Response::Error {
+                error: "Unknown tie policy".into(),
+            },
+        ] {
+            let json = serde_json::to_string(&response).unwrap();
+            assert_eq!(serde_json::from_str::<Response>(&json).unwrap(), response);
+        }
+    }
+}
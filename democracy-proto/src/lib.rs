@@ -0,0 +1,392 @@
+//! Wire types shared between ballot_box and anything that talks to it (democracy-scheduler, scripts, etc).
+//!
+//! Both sides of the API used to keep their own copies of these structs in sync by hand, which is how the scheduler
+//! ended up decoding tallies as `u32` while the ballot box was sending `u64`. Anything that crosses the wire belongs
+//! here.
+
+pub mod control;
+pub mod roster;
+
+use serde::{Deserialize, Serialize};
+
+/// Version of the ballot box HTTP API described by this crate. Bump this whenever a breaking change is made to any
+/// of the types below.
+pub const API_VERSION: &str = "v1";
+
+/// Header the ballot box uses to advertise which [`API_VERSION`] it speaks.
+pub const API_VERSION_HEADER: &str = "x-democracy-api-version";
+
+/// Routes served by the ballot box.
+pub mod routes {
+    pub const SYSTEM: &str = "/api/system";
+    pub const CANDIDATES: &str = "/api/candidates";
+    pub const VOTES: &str = "/api/votes";
+    pub const VOTES_STREAM: &str = "/api/votes/stream";
+    pub const RESULTS_IRV: &str = "/api/results/irv";
+    pub const WINNER: &str = "/api/winner";
+    pub const RACE: &str = "/api/race";
+    pub const METRICS: &str = "/metrics";
+}
+
+/// The id of a candidate as listed in the roster, e.g. "summer1". Ids are matched case-insensitively.
+pub type CandidateId = String;
+
+/// The number of votes a single candidate has received.
+///
+/// Serialized as a two element array (`["summer1", 10]`) to stay compatible with existing clients.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Tally(pub CandidateId, pub u64);
+
+/// How the ballot box counts ballots to decide the winner of an election. Set per election in the roster file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CountingMethod {
+    /// Whoever is ranked first on the most ballots wins.
+    #[default]
+    Plurality,
+
+    /// Every candidate listed on a ballot gets a point, regardless of where they're ranked.
+    Approval,
+
+    /// Candidates get more points the higher they're ranked on each ballot.
+    Borda,
+
+    /// The Condorcet winner, with cycles resolved using the Schulze method.
+    Schulze,
+
+    /// Instant-runoff; see `GET /api/results/irv` for the round by round breakdown.
+    Irv,
+}
+
+/// A candidate as presented to voters; this intentionally leaves out roster details like the command the scheduler
+/// launches.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CandidateInfo {
+    pub id: CandidateId,
+    pub name: String,
+}
+
+/// Body of `POST /api/votes`.
+///
+/// A voter either picks a single candidate (`{"vote": "summer1"}`) or ranks as many candidates as they like in order
+/// of preference (`{"ranking": ["summer2", "summer1"]}`). A single vote is just a ranking with one entry.
+#[derive(Debug, Clone, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub struct VoteRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vote: Option<CandidateId>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ranking: Vec<CandidateId>,
+}
+
+impl VoteRequest {
+    /// The voter's choices from most to least preferred.
+    pub fn ranking(&self) -> Vec<CandidateId> {
+        match &self.vote {
+            Some(vote) if self.ranking.is_empty() => vec![vote.clone()],
+            _ => self.ranking.clone(),
+        }
+    }
+}
+
+/// Response to `POST /api/votes`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VoteResponse {
+    pub current_tally: Vec<Tally>,
+}
+
+/// Response to `GET /api/votes`; tallies are in roster order.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct VotesResponse {
+    pub votes: Vec<Tally>,
+}
+
+/// Response to `GET /api/candidates`; candidates are in roster order.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CandidatesResponse {
+    pub candidates: Vec<CandidateInfo>,
+}
+
+/// A single round of an instant-runoff count.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IrvRound {
+    /// First preference counts for every candidate still in the running, in roster order.
+    pub tallies: Vec<Tally>,
+
+    /// Ballots that no longer rank any candidate still in the running.
+    pub exhausted: u64,
+
+    /// Candidates knocked out at the end of this round.
+    pub eliminated: Vec<CandidateId>,
+}
+
+/// Response to `GET /api/results/irv`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct IrvResults {
+    pub rounds: Vec<IrvRound>,
+
+    /// The candidate that reached a majority, if any.
+    pub winner: Option<CandidateId>,
+
+    /// The candidates left standing when the count ended in a tie; empty when there is a winner.
+    pub tied: Vec<CandidateId>,
+}
+
+/// Response to `GET /api/winner`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct WinnerResponse {
+    /// The counting method used to pick the winner.
+    pub method: CountingMethod,
+
+    /// The winning candidate; `None` when the count ended in a tie or no votes have been cast.
+    pub winner: Option<CandidateId>,
+
+    /// The candidates sharing first place when there is no outright winner; empty when there is a winner.
+    pub tied: Vec<CandidateId>,
+
+    /// Each candidate's score under `method`, in roster order. What a point means depends on the method.
+    pub scores: Vec<Tally>,
+
+    /// The number of ballots counted.
+    pub ballots: u64,
+
+    /// Human readable account of how the winner was decided.
+    pub explanation: String,
+}
+
+/// Pushed to subscribers of `/api/votes/stream` (as a Server-Sent Event, or a text message over a WebSocket) every
+/// time a vote is counted.
+///
+/// The first event after subscribing lists every candidate in `changed`, as does the next one after a subscriber falls
+/// too far behind; after that it only lists the candidates whose first choice count moved. Counts are totals rather
+/// than increments, so applying an event twice is harmless.
+///
+/// Working out the winner means going over every ballot, so the ballot box does it shortly after votes come in rather
+/// than for each one. Events for votes carry the winner as of the last recount, and each recount is pushed as an event
+/// of its own with nothing in `changed`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TallyEvent {
+    /// New first choice totals for the candidates that changed, in roster order.
+    pub changed: Vec<Tally>,
+
+    /// The winner as of the last recount, as `GET /api/winner` would have reported it then.
+    pub winner: WinnerResponse,
+}
+
+/// The outcome of one round of the scheduler's CPU-time race: the first candidate to have run for `target_ms` wins.
+/// The scheduler reports it with `POST /api/race`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RaceResult {
+    /// Which of the scheduler's races this was. The scheduler picks a new one every time it starts, since its rounds
+    /// count from 1 again; results from schedulers that don't send one are all run 0.
+    #[serde(default)]
+    pub run: u64,
+
+    /// Which round this was, counting from 1. The race only goes past round 1 when it's reset after each win.
+    pub round: u64,
+
+    /// The candidate that got there first.
+    pub winner: CandidateId,
+
+    /// How much CPU time it took to win, in milliseconds.
+    pub target_ms: u64,
+
+    /// CPU time each candidate had in this round, in milliseconds and roster order.
+    pub cpu_ms: Vec<Tally>,
+}
+
+/// Response to `GET /api/race` and `POST /api/race`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RaceResponse {
+    /// Every round won since the ballot box started, oldest first.
+    pub results: Vec<RaceResult>,
+}
+
+/// Response to `GET /api/system`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct SystemResponse {
+    pub address: String,
+    pub api_version: String,
+}
+
+/// Body returned alongside any non-2xx status code.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::de::DeserializeOwned;
+
+    fn round_trip<T>(value: &T) -> T
+    where
+        T: Serialize + DeserializeOwned,
+    {
+        let json = serde_json::to_string(value).unwrap();
+        serde_json::from_str(&json).unwrap()
+    }
+
+    #[test]
+    fn tally_is_a_two_element_array() {
+        let tally = Tally("summer1".into(), 42);
+
+        assert_eq!(serde_json::to_string(&tally).unwrap(), r#"["summer1",42]"#);
+        assert_eq!(round_trip(&tally), tally);
+    }
+
+    #[test]
+    fn tally_holds_counts_past_u32() {
+        let tally = Tally("summer1".into(), u64::from(u32::MAX) + 1);
+
+        assert_eq!(round_trip(&tally), tally);
+    }
+
+    #[test]
+    fn votes_response_round_trip() {
+        let response = VotesResponse {
+            votes: vec![Tally("summer1".into(), 3), Tally("summer2".into(), 7)],
+        };
+
+        assert_eq!(round_trip(&response), response);
+    }
+
+    #[test]
+    fn votes_response_matches_wire_format() {
+        let response: VotesResponse =
+            serde_json::from_str(r#"{"votes":[["summer1",3],["summer2",7]]}"#).unwrap();
+
+        assert_eq!(
+            response.votes,
+            vec![Tally("summer1".into(), 3), Tally("summer2".into(), 7)]
+        );
+    }
+
+    #[test]
+    fn vote_request_round_trip() {
+        let request = VoteRequest {
+            vote: Some("summer2".into()),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            serde_json::to_string(&request).unwrap(),
+            r#"{"vote":"summer2"}"#
+        );
+        assert_eq!(round_trip(&request), request);
+        assert_eq!(request.ranking(), vec!["summer2".to_string()]);
+    }
+
+    #[test]
+    fn ranked_vote_request_round_trip() {
+        let request: VoteRequest =
+            serde_json::from_str(r#"{"ranking":["summer2","summer1"]}"#).unwrap();
+
+        assert_eq!(request.vote, None);
+        assert_eq!(round_trip(&request), request);
+        assert_eq!(
+            request.ranking(),
+            vec!["summer2".to_string(), "summer1".to_string()]
+        );
+    }
+
+    #[test]
+    fn irv_results_round_trip() {
+        let results = IrvResults {
+            rounds: vec![IrvRound {
+                tallies: vec![Tally("summer1".into(), 2), Tally("summer2".into(), 1)],
+                exhausted: 0,
+                eliminated: vec![],
+            }],
+            winner: Some("summer1".into()),
+            tied: vec![],
+        };
+
+        assert_eq!(round_trip(&results), results);
+    }
+
+    #[test]
+    fn winner_response_round_trip() {
+        let response = WinnerResponse {
+            method: CountingMethod::Borda,
+            winner: None,
+            tied: vec!["summer1".into(), "summer2".into()],
+            scores: vec![Tally("summer1".into(), 4), Tally("summer2".into(), 4)],
+            ballots: 4,
+            explanation: "summer1 and summer2 are tied".into(),
+        };
+
+        assert!(serde_json::to_string(&response)
+            .unwrap()
+            .contains(r#""method":"borda""#));
+        assert_eq!(round_trip(&response), response);
+    }
+
+    #[test]
+    fn tally_event_round_trip() {
+        let event = TallyEvent {
+            changed: vec![Tally("summer2".into(), 3)],
+            winner: WinnerResponse {
+                method: CountingMethod::Plurality,
+                winner: Some("summer2".into()),
+                tied: vec![],
+                scores: vec![Tally("summer1".into(), 1), Tally("summer2".into(), 3)],
+                ballots: 4,
+                explanation: "summer2 has the most first choice votes".into(),
+            },
+        };
+
+        assert_eq!(round_trip(&event), event);
+    }
+
+    #[test]
+    fn vote_response_round_trip() {
+        let response = VoteResponse {
+            current_tally: vec![Tally("summer1_votes".into(), 1)],
+        };
+
+        assert_eq!(round_trip(&response), response);
+    }
+
+    #[test]
+    fn candidates_response_round_trip() {
+        let response = CandidatesResponse {
+            candidates: vec![CandidateInfo {
+                id: "summer1".into(),
+                name: "Summer 1".into(),
+            }],
+        };
+
+        assert_eq!(round_trip(&response), response);
+    }
+
+    #[test]
+    fn race_response_round_trip() {
+        let response = RaceResponse {
+            results: vec![RaceResult {
+                run: 1_722_000_000_000_000_000,
+                round: 1,
+                winner: "summer2".into(),
+                target_ms: 10_000,
+                cpu_ms: vec![Tally("summer1".into(), 4_210), Tally("summer2".into(), 10_003)],
+            }],
+        };
+
+        assert_eq!(round_trip(&response), response);
+    }
+
+    #[test]
+    fn system_and_error_responses_round_trip() {
+        let system = SystemResponse {
+            address: "10.0.0.1".into(),
+            api_version: API_VERSION.into(),
+        };
+        let error = ErrorResponse {
+            error: "Not a valid vote".into(),
+        };
+
+        assert_eq!(round_trip(&system), system);
+        assert_eq!(round_trip(&error), error);
+    }
+}
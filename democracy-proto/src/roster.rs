@@ -0,0 +1,263 @@
+//! The roster is the list of candidates standing in the current election. Both ballot_box and democracy-scheduler
+//! load the same file (see roster.toml in the root of the repo), so adding a batch for a new election cycle doesn't
+//! require touching either binary.
+
+use crate::{CandidateId, CandidateInfo, CountingMethod};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Candidate {
+    /// What voters submit to vote for this candidate.
+    pub id: CandidateId,
+
+    /// Display name for the frontend.
+    pub name: String,
+
+    /// The program (and its arguments) the scheduler launches to compete on behalf of this candidate. Leave it out to
+    /// have the scheduler attach to processes that are already running instead, picked by `pid`, `comm` or `cgroup`.
+    #[serde(default)]
+    pub command: Vec<String>,
+
+    /// A cgroup v2 path, relative to /sys/fs/cgroup, to bind the candidate to. Everything in it competes on the
+    /// candidate's behalf and its program, if it has one, is launched into it. Unset, the candidate is its program's
+    /// process tree.
+    #[serde(default)]
+    pub cgroup: Option<String>,
+
+    /// Attach to the running process with this pid, along with its threads and descendants.
+    #[serde(default)]
+    pub pid: Option<u32>,
+
+    /// Attach to every running process whose name (as in /proc/<pid>/comm) matches this regex, along with their
+    /// threads and descendants.
+    #[serde(default)]
+    pub comm: Option<String>,
+}
+
+impl Candidate {
+    /// Whether the scheduler attaches to processes that are already running for this candidate, rather than
+    /// launching its command.
+    pub fn is_attached(&self) -> bool {
+        self.command.is_empty()
+            && (self.pid.is_some() || self.comm.is_some() || self.cgroup.is_some())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Roster {
+    /// How ballots are counted for this election; plurality unless set.
+    #[serde(default)]
+    pub method: CountingMethod,
+
+    pub candidates: Vec<Candidate>,
+}
+
+impl Roster {
+    pub fn load(path: &std::path::Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Could not read roster file '{}'", path.display()))?;
+
+        Self::parse(&contents)
+            .with_context(|| format!("Could not load roster file '{}'", path.display()))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self> {
+        let roster: Roster = toml::from_str(contents)?;
+        roster.validate()?;
+
+        Ok(roster)
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.candidates.is_empty() {
+            bail!("Roster must contain at least one candidate");
+        }
+
+        for (i, candidate) in self.candidates.iter().enumerate() {
+            if candidate.id.is_empty() {
+                bail!("Roster candidate #{} has an empty id", i + 1);
+            }
+
+            if self.candidates[..i]
+                .iter()
+                .any(|other| other.id.eq_ignore_ascii_case(&candidate.id))
+            {
+                bail!("Roster contains duplicate candidate id '{}'", candidate.id);
+            }
+
+            let targets = [
+                candidate.pid.is_some(),
+                candidate.comm.is_some(),
+                candidate.cgroup.is_some(),
+            ];
+            if targets.iter().filter(|&&target| target).count() > 1 {
+                bail!(
+                    "Roster candidate '{}' can only be attached by one of pid, comm or cgroup",
+                    candidate.id
+                );
+            }
+            if !candidate.command.is_empty()
+                && (candidate.pid.is_some() || candidate.comm.is_some())
+            {
+                bail!(
+                    "Roster candidate '{}' has both a command to launch and a process to attach to",
+                    candidate.id
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns the position of the candidate with the given id; ids are matched case-insensitively.
+    pub fn position(&self, id: &str) -> Option<usize> {
+        self.candidates
+            .iter()
+            .position(|candidate| candidate.id.eq_ignore_ascii_case(id))
+    }
+
+    /// Returns the candidate with the given id; ids are matched case-insensitively.
+    pub fn get(&self, id: &str) -> Option<&Candidate> {
+        self.position(id).map(|position| &self.candidates[position])
+    }
+
+    /// Returns the voter facing details for every candidate, in roster order.
+    pub fn info(&self) -> Vec<CandidateInfo> {
+        self.candidates
+            .iter()
+            .map(|candidate| CandidateInfo {
+                id: candidate.id.clone(),
+                name: candidate.name.clone(),
+            })
+            .collect()
+    }
+
+    /// Returns a human readable list of the valid ids, useful for error messages.
+    pub fn id_list(&self) -> String {
+        self.candidates
+            .iter()
+            .map(|candidate| format!("'{}'", candidate.id))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_roster() {
+        let roster = Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+            command = ["thingdoer", "summer1"]
+            cgroup = "democracy/summer1"
+
+            [[candidates]]
+            id = "fall1"
+            name = "Fall 1"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(roster.candidates.len(), 2);
+        assert_eq!(roster.method, CountingMethod::Plurality);
+        assert_eq!(roster.position("SUMMER1"), Some(0));
+        assert_eq!(roster.get("fall1").unwrap().command, Vec::<String>::new());
+        assert_eq!(
+            roster.get("summer1").unwrap().cgroup.as_deref(),
+            Some("democracy/summer1")
+        );
+        assert_eq!(roster.get("fall1").unwrap().cgroup, None);
+        assert_eq!(roster.get("winter1"), None);
+    }
+
+    #[test]
+    fn parse_counting_method() {
+        let roster = Roster::parse(
+            r#"
+            method = "schulze"
+
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(roster.method, CountingMethod::Schulze);
+    }
+
+    #[test]
+    fn reject_duplicate_ids() {
+        let result = Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+
+            [[candidates]]
+            id = "Summer1"
+            name = "Summer 1 again"
+            "#,
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn attached_candidates() {
+        let roster = Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+            pid = 1234
+
+            [[candidates]]
+            id = "summer2"
+            name = "Summer 2"
+            comm = "^postgres"
+
+            [[candidates]]
+            id = "fall1"
+            name = "Fall 1"
+            command = ["thingdoer", "fall1"]
+            cgroup = "democracy/fall1"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(roster.candidates[0].pid, Some(1234));
+        assert_eq!(roster.candidates[1].comm.as_deref(), Some("^postgres"));
+        assert!(roster.candidates[0].is_attached() && roster.candidates[1].is_attached());
+        assert!(!roster.candidates[2].is_attached());
+
+        let attached_twice = r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+            pid = 1234
+            comm = "thingdoer"
+            "#;
+        assert!(Roster::parse(attached_twice).is_err());
+
+        let launched_and_attached = r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+            command = ["thingdoer", "summer1"]
+            pid = 1234
+            "#;
+        assert!(Roster::parse(launched_and_attached).is_err());
+    }
+
+    #[test]
+    fn reject_empty_roster() {
+        assert!(Roster::parse("candidates = []").is_err());
+    }
+}
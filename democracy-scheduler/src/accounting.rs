@@ -0,0 +1,144 @@
+//! Keeps track of how much CPU time every candidate has had.
+//!
+//! The kernel tells us each task's total CPU time (`sum_exec_runtime`) every time it's queued. The difference since
+//! the last time is what it ran for in between, and that's charged to the task's candidate. The candidate's total
+//! carries on across threads coming and going and programs being restarted, which the tasks' own counters don't.
+//!
+//! Every task also gets a vruntime: its CPU time scaled by its weight, as in CFS, so that a task with a higher priority
+//! ages more slowly.
+
+use crate::bpf::QueuedTask;
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// The weight of a task at nice 0.
+pub const DEFAULT_WEIGHT: u64 = 100;
+
+/// CPU time scaled by weight: how long a task at nice 0 would have had to run to be charged the same.
+pub fn weighted(ran_ns: u64, weight: u64) -> u64 {
+    (ran_ns as u128 * DEFAULT_WEIGHT as u128 / weight.max(1) as u128) as u64
+}
+
+/// What a candidate has used.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// CPU time, in nanoseconds.
+    pub cpu_ns: u64,
+
+    /// CPU time scaled by the weight of the tasks that used it.
+    pub vruntime: u64,
+
+    /// Number of times the candidate's tasks gave up the CPU before their slice ran out.
+    pub nvcsw: u64,
+}
+
+impl Usage {
+    pub fn cpu_time(&self) -> Duration {
+        Duration::from_nanos(self.cpu_ns)
+    }
+}
+
+// What we knew about a task the last time it was queued.
+struct Seen {
+    owner: String,
+    sum_exec_runtime: u64,
+    nvcsw: u64,
+    vruntime: u64,
+}
+
+#[derive(Default)]
+pub struct Accounting {
+    tasks: HashMap<u32, Seen>, // every candidate task seen that hasn't exited
+    candidates: HashMap<String, Usage>, // candidate id to what it's used
+}
+
+impl Accounting {
+    /// Charges the task's candidate for whatever it's run since it was last queued, and returns the task's vruntime.
+    /// Anything a task ran before we first saw it (before it was attached to, say) isn't charged.
+    pub fn charge(&mut self, owner: &str, task: &QueuedTask) -> u64 {
+        let fresh = || Seen {
+            owner: owner.to_string(),
+            sum_exec_runtime: task.sum_exec_runtime,
+            nvcsw: task.nvcsw,
+            vruntime: 0,
+        };
+
+        let seen = self.tasks.entry(task.pid as u32).or_insert_with(fresh);
+        if seen.owner != owner {
+            // The pid has been reused, or the task has changed hands; either way its history isn't the new owner's.
+            *seen = fresh();
+        }
+
+        let ran = task.sum_exec_runtime.saturating_sub(seen.sum_exec_runtime);
+        let vruntime = weighted(ran, task.weight);
+
+        let usage = self.candidates.entry(owner.to_string()).or_default();
+        usage.cpu_ns += ran;
+        usage.vruntime += vruntime;
+        usage.nvcsw += task.nvcsw.saturating_sub(seen.nvcsw);
+
+        seen.sum_exec_runtime = task.sum_exec_runtime;
+        seen.nvcsw = task.nvcsw;
+        seen.vruntime += vruntime;
+
+        seen.vruntime
+    }
+
+    /// Forgets a task that's exited, after it's been charged for the last time.
+    pub fn exited(&mut self, pid: u32) {
+        self.tasks.remove(&pid);
+    }
+
+    /// What a candidate has used so far; `None` until one of its tasks has been queued.
+    pub fn usage(&self, owner: &str) -> Option<Usage> {
+        self.candidates.get(owner).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(pid: i32, sum_exec_runtime: u64, nvcsw: u64, weight: u64) -> QueuedTask {
+        QueuedTask::new(pid, 0, sum_exec_runtime, nvcsw, weight)
+    }
+
+    #[test]
+    fn candidates_are_charged_for_every_task() {
+        let mut accounting = Accounting::default();
+        assert_eq!(accounting.usage("summer1"), None);
+
+        // Whatever they'd run before we saw them is free.
+        assert_eq!(accounting.charge("summer1", &task(1, 5_000, 1, 100)), 0);
+        assert_eq!(accounting.charge("summer1", &task(2, 0, 0, 200)), 0);
+
+        assert_eq!(accounting.charge("summer1", &task(1, 8_000, 3, 100)), 3_000);
+        assert_eq!(accounting.charge("summer1", &task(2, 4_000, 0, 200)), 2_000);
+
+        assert_eq!(
+            accounting.usage("summer1"),
+            Some(Usage {
+                cpu_ns: 7_000,
+                vruntime: 5_000,
+                nvcsw: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn totals_outlive_tasks() {
+        let mut accounting = Accounting::default();
+        accounting.charge("summer1", &task(1, 0, 0, 100));
+        accounting.charge("summer1", &task(1, 1_000, 0, 100));
+        accounting.exited(1);
+
+        // The pid is reused by a new task of someone else's, and then a new one of summer1's.
+        accounting.charge("summer2", &task(1, 0, 0, 100));
+        accounting.charge("summer2", &task(1, 500, 0, 100));
+        accounting.charge("summer1", &task(1, 700, 0, 100));
+        accounting.charge("summer1", &task(1, 900, 0, 100));
+
+        assert_eq!(accounting.usage("summer1").unwrap().cpu_ns, 1_200);
+        assert_eq!(accounting.usage("summer2").unwrap().cpu_ns, 500);
+    }
+}
@@ -0,0 +1,150 @@
+//! Puts candidates' tasks under SCHED_EXT so the kernel hands them to us.
+//!
+//! Programs the scheduler launches switch themselves over, but processes we attach to (by pid, name or cgroup) are
+//! running under the normal scheduler and would never be seen. The attacher periodically walks /proc for tasks that
+//! belong to a candidate and switches over any it hasn't seen before. Threads and children started afterwards inherit
+//! SCHED_EXT by themselves; rescanning picks up new processes that match a candidate's name pattern or join its cgroup,
+//! and notices tasks that have left a candidate's cgroup.
+//!
+//! Nothing has to be switched back: when the scheduler detaches, the kernel moves every SCHED_EXT task back to the
+//! normal scheduler.
+
+use crate::group::Groups;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+// Defined in UAPI
+const SCHED_EXT: i32 = 7;
+
+pub struct Attacher {
+    proc_root: PathBuf,         // where to look for tasks
+    interval: Duration,         // how often to rescan
+    last_scan: Option<Instant>, // when we last scanned, if we have
+    switched: HashSet<u32>,     // tasks we've already switched, or tried to
+}
+
+impl Attacher {
+    pub fn new(proc_root: PathBuf, interval: Duration) -> Self {
+        Self {
+            proc_root,
+            interval,
+            last_scan: None,
+            switched: HashSet::new(),
+        }
+    }
+
+    /// Rescans if it's been at least an interval since the last scan.
+    pub fn poll(&mut self, groups: &mut Groups) {
+        if self
+            .last_scan
+            .is_some_and(|last_scan| last_scan.elapsed() < self.interval)
+        {
+            return;
+        }
+
+        self.scan(groups);
+    }
+
+    /// Switches every task belonging to a candidate that hasn't been switched yet over to SCHED_EXT, and returns how
+    /// many there were.
+    pub fn scan(&mut self, groups: &mut Groups) -> usize {
+        self.last_scan = Some(Instant::now());
+        groups.recheck_cgroups();
+
+        let live = tasks(&self.proc_root);
+        let mut switched = 0;
+
+        for &tid in &live {
+            if self.switched.contains(&tid) {
+                continue;
+            }
+
+            let Some(owner) = groups.owner(tid) else {
+                continue;
+            };
+
+            // Remembered even if it failed, so a task we're not allowed to touch isn't complained about every scan.
+            self.switched.insert(tid);
+
+            match use_sched_ext(tid) {
+                Ok(()) => {
+                    info!(pid = tid, owner = %owner, "Attached to task");
+                    switched += 1;
+                }
+                Err(e) if e.raw_os_error() == Some(libc::ESRCH) => {} // it exited under us
+                Err(e) => {
+                    warn!(pid = tid, owner = %owner, err = %e, "Could not switch task to SCHED_EXT; it won't be scheduled")
+                }
+            }
+        }
+
+        // Pids that have gone away might be reused by anyone.
+        self.switched.retain(|tid| live.contains(tid));
+        groups.forget_all_but(&live);
+
+        switched
+    }
+}
+
+// Every task (thread) on the system, from /proc/<pid>/task/<tid>.
+fn tasks(proc_root: &Path) -> HashSet<u32> {
+    let numbered = |dir: &Path| -> Vec<u32> {
+        std::fs::read_dir(dir)
+            .into_iter()
+            .flatten()
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect()
+    };
+
+    numbered(proc_root)
+        .into_iter()
+        .flat_map(|pid| numbered(&proc_root.join(pid.to_string()).join("task")))
+        .collect()
+}
+
+// Switches a single task to SCHED_EXT. Scheduling policy is per thread, so this doesn't touch the rest of its process.
+fn use_sched_ext(tid: u32) -> std::io::Result<()> {
+    let param = libc::sched_param { sched_priority: 0 };
+
+    if unsafe { libc::sched_setscheduler(tid as i32, SCHED_EXT, &param) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::group::Membership;
+
+    #[test]
+    fn tasks_belonging_to_nobody_are_left_alone() {
+        let dir = tempfile::tempdir().unwrap();
+        for (pid, tid) in [(10, 10), (10, 11), (20, 20)] {
+            std::fs::create_dir_all(dir.path().join(format!("{pid}/task/{tid}"))).unwrap();
+        }
+        std::fs::create_dir(dir.path().join("self")).unwrap();
+
+        assert_eq!(tasks(dir.path()), HashSet::from([10, 11, 20]));
+
+        // Nothing is bound, so there's nothing to switch (and no real task gets touched).
+        let mut groups = Groups::new(None);
+        let mut attacher = Attacher::new(dir.path().into(), Duration::from_secs(60));
+        assert_eq!(attacher.scan(&mut groups), 0);
+
+        // A task that's gone by the time we get to it is tried once and not again.
+        let gone = 999_999_999;
+        groups.bind("summer1", Membership::Tree(gone));
+        std::fs::create_dir_all(dir.path().join(format!("{gone}/task/{gone}"))).unwrap();
+        assert_eq!(attacher.scan(&mut groups), 0);
+        assert!(attacher.switched.contains(&gone));
+
+        // Once it's gone from /proc too, it's forgotten.
+        std::fs::remove_dir_all(dir.path().join(gone.to_string())).unwrap();
+        attacher.scan(&mut groups);
+        assert!(!attacher.switched.contains(&gone));
+    }
+}
@@ -0,0 +1,271 @@
+//! The scheduler's connection to the kernel.
+//!
+//! Everything the voting policy needs from sched_ext goes through [`SchedulerBackend`]. In production that's the
+//! [`BpfScheduler`], which needs a sched_ext enabled kernel and root just to construct; [`SimulatedBackend`] keeps
+//! everything in memory instead so the policy can be exercised on any machine.
+
+use crate::bpf::{BpfScheduler, DispatchedTask, QueuedTask};
+use anyhow::Result;
+use std::collections::VecDeque;
+
+pub trait SchedulerBackend {
+    /// Receives the next task waiting to be scheduled, if there is one. Errors are libbpf error codes.
+    fn dequeue_task(&mut self) -> Result<Option<QueuedTask>, i32>;
+
+    /// Hands a task to the kernel to run.
+    fn dispatch_task(&mut self, task: &DispatchedTask) -> Result<()>;
+
+    /// Tells the kernel how many tasks are still queued with and waiting to be dispatched by userspace.
+    fn update_tasks(&mut self, nr_queued: Option<u64>, nr_scheduled: Option<u64>);
+
+    /// The pid currently running on a CPU, or 0 if it's idle.
+    fn get_cpu_pid(&self, cpu: i32) -> u32;
+
+    /// Whether the kernel side of the scheduler has exited (or been kicked out).
+    fn exited(&mut self) -> bool;
+
+    /// The counters kept by the kernel side of the scheduler.
+    fn stats(&mut self) -> BpfStats;
+
+    /// Detaches the kernel side of the scheduler and logs why it exited. Errors if it exited because of an error.
+    fn shutdown_and_report(&mut self) -> Result<()>;
+}
+
+/// Counters kept by the BPF side of the scheduler since it was attached; see [`BpfStats::counters`] for what each one
+/// counts.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BpfStats {
+    pub nr_running: u64,
+    pub nr_queued: u64,
+    pub nr_scheduled: u64,
+    pub nr_user_dispatches: u64,
+    pub nr_kernel_dispatches: u64,
+    pub nr_cancel_dispatches: u64,
+    pub nr_bounce_dispatches: u64,
+    pub nr_failed_dispatches: u64,
+    pub nr_sched_congested: u64,
+}
+
+impl BpfStats {
+    /// Every counter, with its name and what it counts.
+    pub fn counters(&self) -> [(&'static str, &'static str, u64); 9] {
+        [
+            ("nr_running", "Tasks running right now", self.nr_running),
+            (
+                "nr_queued",
+                "Tasks queued to userspace that it hasn't picked up yet",
+                self.nr_queued,
+            ),
+            (
+                "nr_scheduled",
+                "Tasks userspace has picked up but not dispatched yet",
+                self.nr_scheduled,
+            ),
+            (
+                "nr_user_dispatches",
+                "Tasks dispatched by userspace",
+                self.nr_user_dispatches,
+            ),
+            (
+                "nr_kernel_dispatches",
+                "Tasks dispatched by the BPF side without asking userspace",
+                self.nr_kernel_dispatches,
+            ),
+            (
+                "nr_cancel_dispatches",
+                "Dispatches dropped because the task went away in the meantime",
+                self.nr_cancel_dispatches,
+            ),
+            (
+                "nr_bounce_dispatches",
+                "Dispatches bounced to the shared queue",
+                self.nr_bounce_dispatches,
+            ),
+            (
+                "nr_failed_dispatches",
+                "Dispatches that failed",
+                self.nr_failed_dispatches,
+            ),
+            (
+                "nr_sched_congested",
+                "Times the queue to userspace was full",
+                self.nr_sched_congested,
+            ),
+        ]
+    }
+}
+
+impl SchedulerBackend for BpfScheduler<'_> {
+    fn dequeue_task(&mut self) -> Result<Option<QueuedTask>, i32> {
+        BpfScheduler::dequeue_task(self)
+    }
+
+    fn dispatch_task(&mut self, task: &DispatchedTask) -> Result<()> {
+        Ok(BpfScheduler::dispatch_task(self, task)?)
+    }
+
+    fn update_tasks(&mut self, nr_queued: Option<u64>, nr_scheduled: Option<u64>) {
+        BpfScheduler::update_tasks(self, nr_queued, nr_scheduled)
+    }
+
+    fn get_cpu_pid(&self, cpu: i32) -> u32 {
+        BpfScheduler::get_cpu_pid(self, cpu)
+    }
+
+    fn exited(&mut self) -> bool {
+        BpfScheduler::exited(self)
+    }
+
+    fn stats(&mut self) -> BpfStats {
+        BpfStats {
+            nr_running: *self.nr_running_mut(),
+            nr_queued: *self.nr_queued_mut(),
+            nr_scheduled: *self.nr_scheduled_mut(),
+            nr_user_dispatches: *self.nr_user_dispatches_mut(),
+            nr_kernel_dispatches: *self.nr_kernel_dispatches_mut(),
+            nr_cancel_dispatches: *self.nr_cancel_dispatches_mut(),
+            nr_bounce_dispatches: *self.nr_bounce_dispatches_mut(),
+            nr_failed_dispatches: *self.nr_failed_dispatches_mut(),
+            nr_sched_congested: *self.nr_sched_congested_mut(),
+        }
+    }
+
+    fn shutdown_and_report(&mut self) -> Result<()> {
+        BpfScheduler::shutdown_and_report(self)
+    }
+}
+
+/// An in-memory stand-in for the kernel. Tasks are queued by hand with [`SimulatedBackend::enqueue`] and whatever the
+/// scheduler dispatches is recorded for inspection; a dispatched task is considered to be running on the CPU it was
+/// dispatched to (or the first idle one) until it's queued again.
+#[derive(Debug, Default)]
+pub struct SimulatedBackend {
+    queued: VecDeque<QueuedTask>,
+    dispatched: Vec<DispatchedTask>,
+    cpu_map: Vec<u32>,
+    nr_queued: u64,
+    nr_scheduled: u64,
+    nr_dispatched: u64,
+    exited: bool,
+}
+
+#[allow(dead_code)]
+impl SimulatedBackend {
+    pub fn new(nr_cpus: usize) -> Self {
+        Self {
+            cpu_map: vec![0; nr_cpus],
+            ..Default::default()
+        }
+    }
+
+    /// Queues a task as if the kernel had just asked for it to be scheduled.
+    pub fn enqueue(&mut self, task: QueuedTask) {
+        for pid in self
+            .cpu_map
+            .iter_mut()
+            .filter(|pid| **pid == task.pid as u32)
+        {
+            *pid = 0;
+        }
+        self.queued.push_back(task);
+    }
+
+    /// Returns everything dispatched since the last call.
+    pub fn take_dispatched(&mut self) -> Vec<DispatchedTask> {
+        std::mem::take(&mut self.dispatched)
+    }
+
+    /// The queued/scheduled counters most recently reported by the scheduler.
+    pub fn nr_queued_scheduled(&self) -> (u64, u64) {
+        (self.nr_queued, self.nr_scheduled)
+    }
+
+    /// Makes [`SchedulerBackend::exited`] report that the kernel side went away.
+    pub fn exit(&mut self) {
+        self.exited = true;
+    }
+}
+
+impl SchedulerBackend for SimulatedBackend {
+    fn dequeue_task(&mut self) -> Result<Option<QueuedTask>, i32> {
+        Ok(self.queued.pop_front())
+    }
+
+    fn dispatch_task(&mut self, task: &DispatchedTask) -> Result<()> {
+        let cpu = usize::try_from(task.cpu())
+            .ok()
+            .filter(|&cpu| cpu < self.cpu_map.len())
+            .or_else(|| self.cpu_map.iter().position(|&pid| pid == 0));
+
+        if let Some(cpu) = cpu {
+            self.cpu_map[cpu] = task.pid() as u32;
+        }
+
+        self.dispatched.push(task.clone());
+        self.nr_dispatched += 1;
+        Ok(())
+    }
+
+    fn update_tasks(&mut self, nr_queued: Option<u64>, nr_scheduled: Option<u64>) {
+        if let Some(queued) = nr_queued {
+            self.nr_queued = queued;
+        }
+        if let Some(scheduled) = nr_scheduled {
+            self.nr_scheduled = scheduled;
+        }
+    }
+
+    fn get_cpu_pid(&self, cpu: i32) -> u32 {
+        usize::try_from(cpu)
+            .ok()
+            .and_then(|cpu| self.cpu_map.get(cpu).copied())
+            .unwrap_or(0)
+    }
+
+    fn exited(&mut self) -> bool {
+        self.exited
+    }
+
+    fn stats(&mut self) -> BpfStats {
+        BpfStats {
+            nr_queued: self.nr_queued,
+            nr_scheduled: self.nr_scheduled,
+            nr_user_dispatches: self.nr_dispatched,
+            ..Default::default()
+        }
+    }
+
+    fn shutdown_and_report(&mut self) -> Result<()> {
+        self.exited = true;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dispatched_tasks_occupy_a_cpu() {
+        let mut backend = SimulatedBackend::new(2);
+        backend.enqueue(QueuedTask::new(10, 7, 0, 0, 100)); // no such CPU, so it lands on the first idle one
+        backend.enqueue(QueuedTask::new(11, 1, 0, 0, 100));
+
+        let first = backend.dequeue_task().unwrap().unwrap();
+        let second = backend.dequeue_task().unwrap().unwrap();
+        assert_eq!(backend.dequeue_task(), Ok(None));
+
+        backend
+            .dispatch_task(&DispatchedTask::new(&second))
+            .unwrap();
+        backend.dispatch_task(&DispatchedTask::new(&first)).unwrap();
+
+        assert_eq!(backend.get_cpu_pid(0), 10);
+        assert_eq!(backend.get_cpu_pid(1), 11);
+        assert_eq!(backend.take_dispatched().len(), 2);
+
+        // Queuing a task again means it's no longer running.
+        backend.enqueue(QueuedTask::new(11, 1, 0, 0, 100));
+        assert_eq!(backend.get_cpu_pid(1), 0);
+    }
+}
@@ -73,6 +73,21 @@ pub struct QueuedTask {
     cpumask_cnt: u64,          // cpumask generation counter (private)
 }
 
+impl QueuedTask {
+    // Create a QueuedTask that didn't come from the BPF component, e.g. to feed a simulated backend.
+    #[allow(dead_code)]
+    pub fn new(pid: i32, cpu: i32, sum_exec_runtime: u64, nvcsw: u64, weight: u64) -> Self {
+        QueuedTask {
+            pid,
+            cpu,
+            sum_exec_runtime,
+            nvcsw,
+            weight,
+            cpumask_cnt: 0,
+        }
+    }
+}
+
 // Task queued for dispatching to the BPF component (see bpf_intf::dispatched_task_ctx).
 #[derive(Debug, PartialEq, Eq, PartialOrd, Clone)]
 pub struct DispatchedTask {
@@ -115,6 +130,24 @@ impl DispatchedTask {
     pub fn set_slice_ns(&mut self, slice_ns: u64) {
         self.slice_ns = slice_ns;
     }
+
+    // Task the dispatch is for.
+    #[allow(dead_code)]
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    // CPU the task was dispatched to.
+    #[allow(dead_code)]
+    pub fn cpu(&self) -> i32 {
+        self.cpu
+    }
+
+    // Time slice assigned to the task (0 = default).
+    #[allow(dead_code)]
+    pub fn slice_ns(&self) -> u64 {
+        self.slice_ns
+    }
 }
 
 // Helpers used to submit tasks to the BPF user ring buffer.
@@ -299,55 +332,46 @@ impl<'cb> BpfScheduler<'cb> {
     }
 
     // Counter of currently running tasks.
-    #[allow(dead_code)]
     pub fn nr_running_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_running
     }
 
     // Counter of queued tasks.
-    #[allow(dead_code)]
     pub fn nr_queued_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_queued
     }
 
     // Counter of scheduled tasks.
-    #[allow(dead_code)]
     pub fn nr_scheduled_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_scheduled
     }
 
     // Counter of user dispatch events.
-    #[allow(dead_code)]
     pub fn nr_user_dispatches_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_user_dispatches
     }
 
     // Counter of user kernel events.
-    #[allow(dead_code)]
     pub fn nr_kernel_dispatches_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_kernel_dispatches
     }
 
     // Counter of cancel dispatch events.
-    #[allow(dead_code)]
     pub fn nr_cancel_dispatches_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_cancel_dispatches
     }
 
     // Counter of dispatches bounced to the shared DSQ.
-    #[allow(dead_code)]
     pub fn nr_bounce_dispatches_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_bounce_dispatches
     }
 
     // Counter of failed dispatch events.
-    #[allow(dead_code)]
     pub fn nr_failed_dispatches_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_failed_dispatches
     }
 
     // Counter of scheduler congestion events.
-    #[allow(dead_code)]
     pub fn nr_sched_congested_mut(&mut self) -> &mut u64 {
         &mut self.skel.bss_mut().nr_sched_congested
     }
@@ -376,12 +400,13 @@ impl<'cb> BpfScheduler<'cb> {
         res
     }
 
-    // Get the pid running on a certain CPU, if no tasks are running return 0.
-    #[allow(dead_code)]
+    // Get the pid running on a certain CPU, if no tasks are running (or there's no such CPU) return 0.
     pub fn get_cpu_pid(&self, cpu: i32) -> u32 {
-        let cpu_map_ptr = self.skel.bss().cpu_map.as_ptr();
+        let Ok(cpu) = usize::try_from(cpu) else {
+            return 0;
+        };
 
-        unsafe { *cpu_map_ptr.offset(cpu as isize) }
+        self.skel.bss().cpu_map.get(cpu).copied().unwrap_or(0)
     }
 
     // Receive a task to be scheduled from the BPF dispatcher.
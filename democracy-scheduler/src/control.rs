@@ -0,0 +1,243 @@
+//! The control socket, which `democracy-ctl` talks to; see [`democracy_proto::control`] for the protocol.
+//!
+//! Each connection gets a thread of its own, which hands requests to the scheduling loop and waits for the answer.
+//! The loop picks them up between decisions, so a request takes up to a schedule interval to be answered, but nothing
+//! about the scheduler has to be shared between threads and settings are only ever changed between decisions.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::ValueEnum;
+use democracy_proto::control::{Request, Response};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use tracing::{debug, info};
+
+// How long a client can sit on an open connection without asking anything.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How long to wait for the scheduling loop to answer before giving up on it. The schedule interval is kept well under
+// this; see `policy::MAX_SCHEDULE_INTERVAL_MS`.
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A request waiting for the scheduling loop to answer it.
+pub struct Pending {
+    pub request: Request,
+    answer: mpsc::Sender<Response>,
+}
+
+impl Pending {
+    pub fn answer(self, response: Response) {
+        // The client may have hung up in the meantime.
+        let _ = self.answer.send(response);
+    }
+}
+
+pub struct ControlSocket {
+    path: PathBuf,                     // removed again once we're done with it
+    requests: mpsc::Receiver<Pending>, // requests from every connection
+}
+
+impl ControlSocket {
+    /// Starts listening on `path`. A socket left behind by a scheduler that's no longer running is replaced, but one
+    /// that's still being listened on isn't, and neither is anything that isn't a socket.
+    pub fn bind(path: &Path) -> Result<Self> {
+        if let Ok(metadata) = std::fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                bail!(
+                    "{} already exists and isn't a socket; give --control-socket a path that's free",
+                    path.display()
+                );
+            }
+            if UnixStream::connect(path).is_ok() {
+                bail!(
+                    "Something is already listening on the control socket {}; is another scheduler running?",
+                    path.display()
+                );
+            }
+            std::fs::remove_file(path).with_context(|| {
+                format!("Could not remove stale control socket {}", path.display())
+            })?;
+        }
+
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Could not listen on control socket {}", path.display()))?;
+
+        let (requests, received) = mpsc::channel();
+        std::thread::Builder::new()
+            .name("control".into())
+            .spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    let requests = requests.clone();
+                    let _ = std::thread::Builder::new()
+                        .name("control-client".into())
+                        .spawn(move || {
+                            if let Err(e) = serve(stream, &requests) {
+                                debug!(err = %e, "Control connection ended");
+                            }
+                        });
+                }
+            })
+            .context("Could not start control socket")?;
+
+        info!(path = %path.display(), "listening for control requests");
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            requests: received,
+        })
+    }
+
+    /// Requests received since last time, without waiting for any more.
+    pub fn pending(&self) -> Vec<Pending> {
+        self.requests.try_iter().collect()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// What a setting is called on the command line (e.g. `winner-takes-all`), which is also how it's named over the
+/// control socket.
+pub fn name<T: ValueEnum>(value: T) -> String {
+    value
+        .to_possible_value()
+        .map_or_else(String::new, |value| value.get_name().to_string())
+}
+
+/// Reads a setting named as it would be on the command line; `what` is what it's a name of, for the error.
+pub fn parse<T: ValueEnum>(what: &str, name: &str) -> Result<T> {
+    T::from_str(name, true).map_err(|_| {
+        let names: Vec<String> = T::value_variants()
+            .iter()
+            .cloned()
+            .map(self::name)
+            .collect();
+        anyhow!(
+            "Unknown {} '{}'; expected one of {}",
+            what,
+            name,
+            names.join(", ")
+        )
+    })
+}
+
+// Answers a single client's requests until it hangs up.
+fn serve(stream: UnixStream, requests: &mpsc::Sender<Pending>) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+
+    let mut writer = &stream;
+    for line in BufReader::new(&stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => ask(requests, request),
+            Err(e) => Response::Error {
+                error: format!("Not a valid request: {}", e),
+            },
+        };
+
+        let mut json = serde_json::to_string(&response)?;
+        json.push('\n');
+        writer.write_all(json.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+// Hands a request to the scheduling loop and waits for it to answer.
+fn ask(requests: &mpsc::Sender<Pending>, request: Request) -> Response {
+    let (answer, answered) = mpsc::channel();
+    if requests.send(Pending { request, answer }).is_err() {
+        return Response::Error {
+            error: "The scheduler is shutting down".into(),
+        };
+    }
+
+    answered
+        .recv_timeout(ANSWER_TIMEOUT)
+        .unwrap_or_else(|_| Response::Error {
+            error: "The scheduler didn't answer in time".into(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use democracy_proto::control::PolicyChange;
+
+    #[test]
+    fn requests_are_answered_by_the_loop() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        let control = ControlSocket::bind(&path).unwrap();
+
+        // Stands in for the scheduling loop.
+        let answering = std::thread::spawn(move || {
+            let mut answered = 0;
+            while answered < 2 {
+                for pending in control.pending() {
+                    let response = match &pending.request {
+                        Request::Bpf => Response::Bpf { counters: vec![] },
+                        _ => Response::Error {
+                            error: "not in this test".into(),
+                        },
+                    };
+                    pending.answer(response);
+                    answered += 1;
+                }
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let stream = UnixStream::connect(&path).unwrap();
+        let mut lines = BufReader::new(&stream).lines();
+        let mut ask = |request: &str| {
+            (&stream).write_all(request.as_bytes()).unwrap();
+            serde_json::from_str::<Response>(&lines.next().unwrap().unwrap()).unwrap()
+        };
+
+        assert_eq!(
+            ask("{\"request\":\"bpf\"}\n"),
+            Response::Bpf { counters: vec![] }
+        );
+        assert!(matches!(ask("nonsense\n"), Response::Error { .. }));
+        let set = serde_json::to_string(&Request::Set(PolicyChange::default())).unwrap();
+        assert!(matches!(ask(&format!("{}\n", set)), Response::Error { .. }));
+
+        answering.join().unwrap();
+    }
+
+    #[test]
+    fn stale_sockets_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+
+        // Left behind by a scheduler that didn't get to clean up after itself.
+        drop(UnixListener::bind(&path).unwrap());
+        let control = ControlSocket::bind(&path).unwrap();
+
+        assert!(ControlSocket::bind(&path).is_err());
+
+        drop(control);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn only_sockets_are_replaced() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control.sock");
+        std::fs::write(&path, "important").unwrap();
+
+        assert!(ControlSocket::bind(&path).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "important");
+    }
+}
@@ -0,0 +1,223 @@
+//! Fair sharing for SCHED_EXT tasks that aren't standing in the election.
+//!
+//! Anything that switches itself to SCHED_EXT gets queued to us whether it's a candidate or not: other programs, tasks
+//! that inherited the class, and the scheduler itself. They aren't up for a vote, but if they never run the kernel's
+//! watchdog kicks the whole scheduler out. So they're kept in a queue ordered by vruntime, the CPU time each has had
+//! scaled by its weight (like CFS), and after the candidates have been dispatched everything waiting here is
+//! dispatched too, least vruntime first.
+//!
+//! Everything waiting is dispatched every time, so nobody waits long enough to trip the watchdog; fairness comes from
+//! the slices instead. Each task gets a short slice, plus however far it's behind the task that's furthest ahead, up to
+//! another slice's worth. Tasks that have had less than their share catch up over the next few decisions.
+
+use crate::accounting;
+use crate::bpf::QueuedTask;
+use std::collections::{BTreeSet, HashMap};
+
+// What we remember about a task between visits.
+struct Seen {
+    vruntime: u64,         // weighted CPU time it's had
+    sum_exec_runtime: u64, // CPU time it had last time it was queued
+}
+
+pub struct FairQueue {
+    slice_ns: u64,                    // slice every task is dispatched with
+    min_vruntime: u64,                // vruntime of the last task dispatched
+    seen: HashMap<u32, Seen>,         // every task queued so far that hasn't exited
+    queue: BTreeSet<(u64, u32)>,      // queued tasks by vruntime, then pid
+    queued: HashMap<u32, QueuedTask>, // queued tasks by pid
+}
+
+impl FairQueue {
+    pub fn new(slice_ns: u64) -> Self {
+        Self {
+            slice_ns,
+            min_vruntime: 0,
+            seen: HashMap::new(),
+            queue: BTreeSet::new(),
+            queued: HashMap::new(),
+        }
+    }
+
+    pub fn slice_ns(&self) -> u64 {
+        self.slice_ns
+    }
+
+    pub fn set_slice_ns(&mut self, slice_ns: u64) {
+        self.slice_ns = slice_ns;
+    }
+
+    /// Queues a task, charging it for the CPU time it's used since it was last queued.
+    pub fn push(&mut self, task: QueuedTask) {
+        let pid = task.pid as u32;
+
+        // New tasks start level with everyone else, and tasks that have been asleep a while only get a slice's worth
+        // of head start, so nobody can hoard credit.
+        let seen = self.seen.entry(pid).or_insert(Seen {
+            vruntime: self.min_vruntime,
+            sum_exec_runtime: task.sum_exec_runtime,
+        });
+        let ran = task.sum_exec_runtime.saturating_sub(seen.sum_exec_runtime);
+        seen.vruntime = (seen.vruntime + accounting::weighted(ran, task.weight))
+            .max(self.min_vruntime.saturating_sub(self.slice_ns));
+        seen.sum_exec_runtime = task.sum_exec_runtime;
+        let vruntime = seen.vruntime;
+
+        if let Some(old) = self.queued.insert(pid, task) {
+            self.queue.retain(|&(_, queued)| queued != old.pid as u32);
+        }
+        self.queue.insert((vruntime, pid));
+    }
+
+    /// Forgets a task, once it's exiting.
+    pub fn remove(&mut self, pid: u32) {
+        self.seen.remove(&pid);
+        if self.queued.remove(&pid).is_some() {
+            self.queue.retain(|&(_, queued)| queued != pid);
+        }
+    }
+
+    /// Takes every queued task, least vruntime first, along with the slice to dispatch it with.
+    pub fn drain(&mut self) -> Vec<(QueuedTask, u64)> {
+        let queue = std::mem::take(&mut self.queue);
+
+        if let Some(&(vruntime, _)) = queue.first() {
+            self.min_vruntime = self.min_vruntime.max(vruntime);
+        }
+        let max_vruntime = queue.last().map_or(0, |&(vruntime, _)| vruntime);
+
+        queue
+            .into_iter()
+            .filter_map(|(vruntime, pid)| {
+                let task = self.queued.remove(&pid)?;
+
+                // The lag is in vruntime, so it takes a heavier task longer to make up.
+                let lag = max_vruntime - vruntime;
+                let catch_up = (lag as u128 * task.weight as u128
+                    / accounting::DEFAULT_WEIGHT as u128)
+                    .min(self.slice_ns as u128) as u64;
+
+                Some((task, self.slice_ns + catch_up))
+            })
+            .collect()
+    }
+
+    /// How many tasks are waiting.
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(pid: i32, sum_exec_runtime: u64, weight: u64) -> QueuedTask {
+        QueuedTask::new(pid, 0, sum_exec_runtime, 0, weight)
+    }
+
+    fn pids(tasks: Vec<(QueuedTask, u64)>) -> Vec<i32> {
+        tasks.iter().map(|(task, _)| task.pid).collect()
+    }
+
+    #[test]
+    fn least_vruntime_goes_first() {
+        let mut queue = FairQueue::new(5_000_000);
+        for pid in [1, 2, 3] {
+            queue.push(task(pid, 0, 100));
+        }
+        assert_eq!(pids(queue.drain()), vec![1, 2, 3]);
+
+        // 1 ran the most, and 2 ran as much as 3 but counts for half as much because it's twice as heavy.
+        queue.push(task(1, 30_000_000, 100));
+        queue.push(task(2, 20_000_000, 200));
+        queue.push(task(3, 20_000_000, 100));
+        assert_eq!(pids(queue.drain()), vec![2, 3, 1]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn queueing_twice_keeps_one_entry() {
+        let mut queue = FairQueue::new(5_000_000);
+        queue.push(task(1, 0, 100));
+        queue.push(task(1, 1_000_000, 100));
+        queue.push(task(2, 0, 100));
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(pids(queue.drain()), vec![2, 1]);
+    }
+
+    #[test]
+    fn sleepers_only_get_a_slice_of_credit() {
+        let slice_ns = 5_000_000;
+        let mut queue = FairQueue::new(slice_ns);
+        queue.push(task(1, 0, 100));
+        queue.push(task(2, 0, 100));
+        queue.drain();
+
+        // 1 keeps running while 2 sleeps.
+        for ran in 1..=10 {
+            queue.push(task(1, ran * slice_ns, 100));
+            queue.drain();
+        }
+
+        queue.push(task(2, 0, 100));
+        queue.push(task(3, 0, 100));
+        let vruntime = |pid| queue.seen[&pid].vruntime;
+        assert_eq!(vruntime(2), vruntime(1) - slice_ns);
+        assert_eq!(vruntime(3), vruntime(1));
+    }
+
+    #[test]
+    fn tasks_that_are_behind_catch_up() {
+        let slice_ns = 5_000_000;
+        let mut queue = FairQueue::new(slice_ns);
+        queue.push(task(1, 0, 100));
+        queue.push(task(2, 0, 100));
+        queue.drain();
+
+        // 2 has already had two slices more than 1. Each decision, both run for as long as they're given.
+        let mut ran = HashMap::from([(1, 0), (2, 2 * slice_ns)]);
+        let mut slices = vec![];
+        for _ in 0..4 {
+            for (pid, ran) in &ran {
+                queue.push(task(*pid, *ran, 100));
+            }
+
+            let dispatched = queue.drain();
+            slices.push(
+                dispatched
+                    .iter()
+                    .map(|(task, slice_ns)| (task.pid, *slice_ns))
+                    .collect::<Vec<_>>(),
+            );
+            for (task, slice_ns) in dispatched {
+                *ran.get_mut(&task.pid).unwrap() += slice_ns;
+            }
+        }
+
+        // 1 gets an extra slice each time until it's level, then they share evenly.
+        assert_eq!(
+            slices,
+            vec![
+                vec![(1, 2 * slice_ns), (2, slice_ns)],
+                vec![(1, 2 * slice_ns), (2, slice_ns)],
+                vec![(1, slice_ns), (2, slice_ns)],
+                vec![(1, slice_ns), (2, slice_ns)],
+            ]
+        );
+        assert_eq!(ran[&1], ran[&2]);
+    }
+
+    #[test]
+    fn exiting_tasks_are_forgotten() {
+        let mut queue = FairQueue::new(5_000_000);
+        queue.push(task(1, 0, 100));
+        queue.push(task(2, 0, 100));
+        queue.remove(1);
+
+        assert_eq!(pids(queue.drain()), vec![2]);
+        assert!(!queue.seen.contains_key(&1));
+    }
+}
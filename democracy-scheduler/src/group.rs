@@ -0,0 +1,430 @@
+//! Works out which candidate, if any, a task belongs to.
+//!
+//! A candidate is more than the one process the scheduler launched for it: every thread of that process and every
+//! process it starts (and their threads) compete on its behalf too. Candidates are either bound to a process tree,
+//! found by following each task's thread group and parents through /proc, or to a cgroup v2 directory given in the
+//! roster, in which case anything in that cgroup or below it counts. The root of a process tree is the candidate's
+//! program, a pid from the roster, or any process whose name matches the roster's `comm` pattern.
+//!
+//! Looking a task up in /proc on every dispatch would be far too slow, so the answer is cached per pid until the task
+//! exits, the bindings change or the task moves to another cgroup. Cgroups are checked again on every rescan (see
+//! [`crate::attach`]).
+//!
+//! /proc is read by hand rather than through a procfs crate. A lookup needs no more than a line or two from each of
+//! three small files, and parsing only those lines means the tests can stand in a fake /proc that holds nothing else.
+
+use anyhow::{Context, Result};
+use democracy_proto::roster::Candidate;
+use regex::Regex;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+/// Where procfs is normally mounted.
+pub const PROC_ROOT: &str = "/proc";
+
+/// Where the cgroup v2 hierarchy is normally mounted. Cgroup paths in the roster are relative to this.
+pub const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Gives up walking up the process tree after this many parents, in case /proc changes under us and we go round in
+// circles.
+const MAX_DEPTH: usize = 256;
+
+/// How a candidate's tasks are recognised.
+#[derive(Debug, Clone)]
+pub enum Membership {
+    /// The process with this pid, its threads, and all of its descendants.
+    Tree(u32),
+
+    /// Every process with a matching name, their threads, and all of their descendants.
+    Comm(Regex),
+
+    /// Everything in this cgroup, or a cgroup below it.
+    Cgroup(String),
+}
+
+impl Membership {
+    /// How the roster says to recognise a candidate's tasks. Candidates that are only a command have nothing to go on
+    /// until their program is launched.
+    pub fn of(candidate: &Candidate) -> Result<Option<Self>> {
+        if let Some(pid) = candidate.pid {
+            return Ok(Some(Self::Tree(pid)));
+        }
+
+        if let Some(comm) = &candidate.comm {
+            let pattern = Regex::new(comm).with_context(|| {
+                format!(
+                    "Roster candidate '{}' has an invalid comm pattern",
+                    candidate.id
+                )
+            })?;
+            return Ok(Some(Self::Comm(pattern)));
+        }
+
+        Ok(candidate.cgroup.clone().map(Self::Cgroup))
+    }
+}
+
+// What we found out about a task when we looked it up.
+struct Cached {
+    owner: Option<String>,  // the candidate it belongs to, if any
+    cgroup: Option<String>, // the cgroup it was in, if any candidate is bound to a cgroup
+}
+
+pub struct Groups {
+    proc_root: Option<PathBuf>, // where to look tasks up; None only recognises tree roots
+    bindings: Vec<(String, Membership)>, // candidate id to how its tasks are recognised
+    owners: HashMap<u32, Cached>, // every pid seen so far
+}
+
+impl Groups {
+    /// Looks tasks up in `proc_root`, usually [`PROC_ROOT`]. Without one only the roots of process trees are
+    /// recognised, which is all the simulator needs since its tasks don't exist.
+    pub fn new(proc_root: Option<PathBuf>) -> Self {
+        Self {
+            proc_root,
+            bindings: vec![],
+            owners: HashMap::new(),
+        }
+    }
+
+    /// Binds a candidate's tasks, replacing however they were recognised before.
+    pub fn bind(&mut self, id: &str, membership: Membership) {
+        debug!(owner = %id, membership = ?membership, "Binding candidate");
+
+        self.bindings.retain(|(bound, _)| bound != id);
+        self.bindings.push((id.to_string(), membership));
+        self.owners.clear();
+    }
+
+    /// Stops recognising a candidate's tasks.
+    pub fn unbind(&mut self, id: &str) {
+        self.bindings.retain(|(bound, _)| bound != id);
+        self.owners.clear();
+    }
+
+    /// How a candidate's tasks are recognised, if they are at all.
+    pub fn membership(&self, id: &str) -> Option<&Membership> {
+        self.bindings
+            .iter()
+            .find(|(bound, _)| bound == id)
+            .map(|(_, membership)| membership)
+    }
+
+    /// The candidate the task with the given pid belongs to.
+    pub fn owner(&mut self, pid: u32) -> Option<String> {
+        if let Some(cached) = self.owners.get(&pid) {
+            return cached.owner.clone();
+        }
+
+        let cgroup = match &self.proc_root {
+            Some(proc_root) if self.binds_cgroups() => read_cgroup(proc_root, pid),
+            _ => None,
+        };
+        let owner = self.lookup(pid, cgroup.as_deref());
+        self.owners.insert(
+            pid,
+            Cached {
+                owner: owner.clone(),
+                cgroup,
+            },
+        );
+
+        owner
+    }
+
+    /// Drops what we know about every task that's moved to another cgroup since it was looked up, so it's looked up
+    /// again next time.
+    pub fn recheck_cgroups(&mut self) {
+        if !self.binds_cgroups() {
+            return;
+        }
+        let Some(proc_root) = &self.proc_root else {
+            return;
+        };
+
+        self.owners
+            .retain(|&pid, cached| read_cgroup(proc_root, pid) == cached.cgroup);
+    }
+
+    /// Drops what we know about a pid, once its task has exited and the pid might be reused.
+    pub fn forget(&mut self, pid: u32) {
+        self.owners.remove(&pid);
+    }
+
+    /// Drops what we know about every pid that isn't in `live`.
+    pub fn forget_all_but(&mut self, live: &HashSet<u32>) {
+        self.owners.retain(|pid, _| live.contains(pid));
+    }
+
+    // Whether any candidate is bound to a cgroup.
+    fn binds_cgroups(&self) -> bool {
+        self.bindings
+            .iter()
+            .any(|(_, membership)| matches!(membership, Membership::Cgroup(_)))
+    }
+
+    // `cgroup` is the one the task is in, if any candidate is bound to a cgroup.
+    fn lookup(&self, pid: u32, cgroup: Option<&str>) -> Option<String> {
+        if let Some(id) = self.owner_of_root(pid) {
+            return Some(id);
+        }
+
+        let proc_root = self.proc_root.as_deref()?;
+
+        if let Some(cgroup) = cgroup {
+            for (id, membership) in &self.bindings {
+                if let Membership::Cgroup(path) = membership {
+                    if in_cgroup(cgroup, path) {
+                        return Some(id.clone());
+                    }
+                }
+            }
+        }
+
+        // Threads are checked by their thread group, then we work our way up through the parents.
+        let mut current = pid;
+        for _ in 0..MAX_DEPTH {
+            let (tgid, ppid) = read_status(proc_root, current)?;
+
+            if let Some(id) = self.owner_of_root(tgid) {
+                return Some(id);
+            }
+            if let Some(id) = self.owner_by_name(proc_root, tgid) {
+                return Some(id);
+            }
+            if ppid <= 1 {
+                return None;
+            }
+
+            current = ppid;
+        }
+
+        None
+    }
+
+    // The candidate whose process tree is rooted at `pid`.
+    fn owner_of_root(&self, pid: u32) -> Option<String> {
+        self.bindings
+            .iter()
+            .find(|(_, membership)| matches!(membership, Membership::Tree(root) if *root == pid))
+            .map(|(id, _)| id.clone())
+    }
+
+    // The candidate whose comm pattern matches the name of the process `pid`.
+    fn owner_by_name(&self, proc_root: &Path, pid: u32) -> Option<String> {
+        if !self
+            .bindings
+            .iter()
+            .any(|(_, membership)| matches!(membership, Membership::Comm(_)))
+        {
+            return None;
+        }
+
+        let comm = read_comm(proc_root, pid)?;
+        self.bindings
+            .iter()
+            .find(|(_, membership)| matches!(membership, Membership::Comm(pattern) if pattern.is_match(&comm)))
+            .map(|(id, _)| id.clone())
+    }
+}
+
+/// Where a cgroup from the roster lives on disk. Paths already under [`CGROUP_ROOT`] are taken as they are.
+pub fn cgroup_dir(path: &str) -> PathBuf {
+    Path::new(CGROUP_ROOT).join(relative_cgroup(path))
+}
+
+// A cgroup path relative to the root of the hierarchy, without leading or trailing slashes.
+fn relative_cgroup(path: &str) -> &str {
+    path.strip_prefix(CGROUP_ROOT)
+        .unwrap_or(path)
+        .trim_matches('/')
+}
+
+// Whether `cgroup`, as read from /proc, is `path` or below it.
+fn in_cgroup(cgroup: &str, path: &str) -> bool {
+    let cgroup = cgroup.trim_matches('/');
+    let path = relative_cgroup(path);
+
+    cgroup == path
+        || cgroup
+            .strip_prefix(path)
+            .is_some_and(|rest| path.is_empty() || rest.starts_with('/'))
+}
+
+// The task's cgroup in the v2 hierarchy, from /proc/<pid>/cgroup.
+fn read_cgroup(proc_root: &Path, pid: u32) -> Option<String> {
+    let contents = std::fs::read_to_string(proc_root.join(pid.to_string()).join("cgroup")).ok()?;
+
+    contents
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(String::from)
+}
+
+// The task's name, from /proc/<pid>/comm.
+fn read_comm(proc_root: &Path, pid: u32) -> Option<String> {
+    let contents = std::fs::read_to_string(proc_root.join(pid.to_string()).join("comm")).ok()?;
+
+    Some(contents.trim_end_matches('\n').to_string())
+}
+
+// The task's thread group and parent, from /proc/<pid>/status. This works for threads as well as processes.
+fn read_status(proc_root: &Path, pid: u32) -> Option<(u32, u32)> {
+    let contents = std::fs::read_to_string(proc_root.join(pid.to_string()).join("status")).ok()?;
+
+    let field = |name: &str| {
+        contents
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.trim().parse().ok())
+    };
+
+    Some((field("Tgid:")?, field("PPid:")?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Builds just enough of /proc for the lookups above.
+    fn fake_proc(tasks: &[(u32, u32, u32, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+
+        for (pid, tgid, ppid, cgroup) in tasks {
+            let task = dir.path().join(pid.to_string());
+            std::fs::create_dir(&task).unwrap();
+            std::fs::write(
+                task.join("status"),
+                format!("Name:\tthingdoer\nTgid:\t{tgid}\nPid:\t{pid}\nPPid:\t{ppid}\n"),
+            )
+            .unwrap();
+            std::fs::write(task.join("cgroup"), format!("0::{cgroup}\n")).unwrap();
+            std::fs::write(task.join("comm"), "thingdoer\n").unwrap();
+        }
+
+        dir
+    }
+
+    #[test]
+    fn process_trees() {
+        let proc = fake_proc(&[
+            (10, 10, 1, "/"),  // the candidate's program
+            (11, 11, 10, "/"), // a child of it
+            (12, 11, 10, "/"), // a thread of the child
+            (13, 13, 12, "/"), // a grandchild, started from that thread
+            (20, 20, 1, "/"),  // something else entirely
+            (21, 20, 1, "/"),  // and its thread
+        ]);
+        let mut groups = Groups::new(Some(proc.path().into()));
+        groups.bind("summer1", Membership::Tree(10));
+
+        for pid in [10, 11, 12, 13] {
+            assert_eq!(groups.owner(pid).as_deref(), Some("summer1"), "pid {pid}");
+        }
+        for pid in [20, 21, 99] {
+            assert_eq!(groups.owner(pid), None, "pid {pid}");
+        }
+    }
+
+    #[test]
+    fn cgroups() {
+        let proc = fake_proc(&[
+            (10, 10, 1, "/democracy/summer1"),
+            (11, 11, 1, "/democracy/summer1/workers"),
+            (20, 20, 1, "/democracy/summer10"),
+            (30, 30, 1, "/democracy/summer2"),
+        ]);
+        let mut groups = Groups::new(Some(proc.path().into()));
+        groups.bind("summer1", Membership::Cgroup("democracy/summer1".into()));
+        groups.bind(
+            "summer2",
+            Membership::Cgroup("/sys/fs/cgroup/democracy/summer2/".into()),
+        );
+
+        assert_eq!(groups.owner(10).as_deref(), Some("summer1"));
+        assert_eq!(groups.owner(11).as_deref(), Some("summer1"));
+        assert_eq!(groups.owner(20), None);
+        assert_eq!(groups.owner(30).as_deref(), Some("summer2"));
+    }
+
+    #[test]
+    fn tasks_that_change_cgroup_are_looked_up_again() {
+        let proc = fake_proc(&[(10, 10, 1, "/democracy/summer1")]);
+        let mut groups = Groups::new(Some(proc.path().into()));
+        groups.bind("summer1", Membership::Cgroup("democracy/summer1".into()));
+        groups.bind("summer2", Membership::Cgroup("democracy/summer2".into()));
+        assert_eq!(groups.owner(10).as_deref(), Some("summer1"));
+
+        let move_to = |cgroup: &str| {
+            std::fs::write(proc.path().join("10/cgroup"), format!("0::{cgroup}\n")).unwrap()
+        };
+
+        move_to("/democracy/summer2");
+        assert_eq!(groups.owner(10).as_deref(), Some("summer1"));
+        groups.recheck_cgroups();
+        assert_eq!(groups.owner(10).as_deref(), Some("summer2"));
+
+        move_to("/");
+        groups.recheck_cgroups();
+        assert_eq!(groups.owner(10), None);
+    }
+
+    #[test]
+    fn process_names() {
+        let proc = fake_proc(&[
+            (10, 10, 1, "/"),  // a postgres
+            (11, 11, 10, "/"), // one of its workers
+            (12, 12, 1, "/"),  // something else
+        ]);
+        std::fs::write(proc.path().join("10/comm"), "postgres\n").unwrap();
+        std::fs::write(proc.path().join("11/comm"), "postgres: worker\n").unwrap();
+
+        let mut groups = Groups::new(Some(proc.path().into()));
+        groups.bind(
+            "summer1",
+            Membership::Comm(Regex::new("^postgres$").unwrap()),
+        );
+
+        assert_eq!(groups.owner(10).as_deref(), Some("summer1"));
+        assert_eq!(groups.owner(11).as_deref(), Some("summer1"));
+        assert_eq!(groups.owner(12), None);
+    }
+
+    #[test]
+    fn owners_are_remembered_until_forgotten_or_rebound() {
+        let proc = fake_proc(&[(10, 10, 1, "/"), (11, 11, 1, "/")]);
+        let mut groups = Groups::new(Some(proc.path().into()));
+        groups.bind("summer1", Membership::Tree(10));
+
+        assert_eq!(groups.owner(11), None);
+
+        // 11 exits and its pid is reused by a child of the candidate.
+        std::fs::write(proc.path().join("11/status"), "Tgid:\t11\nPPid:\t10\n").unwrap();
+        assert_eq!(groups.owner(11), None);
+        groups.forget(11);
+        assert_eq!(groups.owner(11).as_deref(), Some("summer1"));
+
+        groups.bind("summer1", Membership::Tree(20));
+        assert_eq!(groups.owner(11), None);
+
+        groups.bind("summer1", Membership::Tree(10));
+        assert_eq!(groups.owner(11).as_deref(), Some("summer1"));
+        groups.forget_all_but(&HashSet::from([10]));
+        std::fs::write(proc.path().join("11/status"), "Tgid:\t11\nPPid:\t1\n").unwrap();
+        assert_eq!(groups.owner(11), None);
+    }
+
+    #[test]
+    fn only_roots_without_procfs() {
+        let mut groups = Groups::new(None);
+        groups.bind("summer1", Membership::Tree(10));
+        groups.bind("summer2", Membership::Cgroup("democracy/summer2".into()));
+
+        assert_eq!(groups.owner(10).as_deref(), Some("summer1"));
+        assert_eq!(groups.owner(11), None);
+
+        groups.unbind("summer1");
+        assert_eq!(groups.owner(10), None);
+    }
+}
@@ -5,6 +5,55 @@ pub mod bpf_intf;
 mod bpf;
 use bpf::*;
 
+mod accounting;
+use accounting::Accounting;
+
+mod attach;
+use attach::Attacher;
+
+mod backend;
+use backend::SchedulerBackend;
+
+mod control;
+use control::ControlSocket;
+
+mod fallback;
+use fallback::FairQueue;
+
+mod group;
+use group::{Groups, Membership};
+
+mod metrics;
+use metrics::Metrics;
+
+mod policy;
+use policy::{
+    load_config, PolicyArgs, PolicyUpdate, SchedulingPolicy, MAX_SCHEDULE_INTERVAL_MS, MAX_SLICE_US,
+};
+
+mod proportional;
+use proportional::ProportionalShare;
+
+mod race;
+use race::{Race, RaceArgs, RaceReporter};
+
+mod sim;
+use sim::SimulateArgs;
+
+mod supervisor;
+use supervisor::{PidChange, RestartArgs, Supervisor};
+
+mod tiebreak;
+use tiebreak::TieBreaker;
+
+mod tui;
+use tui::{CandidateStatus, CpuOwner, Dashboard, LogBuffer, Status};
+
+mod votes;
+use votes::{
+    FileVoteSource, HttpVoteSource, StreamVoteSource, VotePoller, VoteSource, VoteSourceKind,
+};
+
 use scx_utils::Topology;
 use scx_utils::TopologyMap;
 use scx_utils::UserExitInfo;
@@ -17,18 +66,26 @@ use std::collections::HashMap;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
 use std::fs::File;
-use std::io::{self, Read};
+use std::io::{self, IsTerminal, Read};
+use std::net::SocketAddr;
 use std::path::Path;
+use std::path::PathBuf;
+use std::process::ExitCode;
 
 use anyhow::bail;
 use anyhow::Context;
 use anyhow::Result;
-use nix::sys::signal::{kill, Signal};
+use clap::{ArgAction, Parser, Subcommand};
+use democracy_proto::control::{
+    self as proto_control, PolicyChange, ProgramState, Request, Response,
+};
+use democracy_proto::roster::Roster;
+use democracy_proto::{RaceResult, Tally, WinnerResponse};
+use nix::sys::signal::{kill, sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal};
 use nix::unistd::Pid;
-use serde::Deserialize;
 use tracing::{debug, error, info, warn};
 use tracing_subscriber::filter::{EnvFilter, LevelFilter};
 
@@ -36,23 +93,167 @@ const SCHEDULER_NAME: &str = "democracy";
 
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-#[derive(Debug, Hash, Eq, PartialEq)]
-enum Competitors {
-    Summer1,
-    Summer2,
+// Exit code for when the BPF side of the scheduler stopped without being asked to (the kernel kicked us out, say).
+// Errors that make it out of main exit with 1.
+const EXIT_BPF_EXITED: u8 = 2;
+
+// How long to wait before reattaching after the BPF side exits under `--restart-on-bpf-exit`.
+const BPF_RESTART_DELAY: Duration = Duration::from_secs(1);
+
+// How long competitors get to exit after SIGTERM before they're killed.
+const COMPETITOR_STOP_GRACE: Duration = Duration::from_secs(2);
+
+// Set by SIGHUP to have the config file read again.
+static RELOAD_CONFIG: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Parser)]
+#[command(
+    version,
+    about = "A sched_ext scheduler that runs whichever program wins the vote"
+)]
+struct Args {
+    /// Path to the roster file listing the candidates in this election.
+    #[arg(long, env = "DEMOCRACY_ROSTER", default_value = "roster.toml")]
+    roster: PathBuf,
+
+    /// Check the configuration and report what would be run, without attaching to the kernel or launching anything.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Show a live dashboard in the terminal instead of logging, with the log underneath it. Falls back to logging
+    /// when stdout isn't a terminal.
+    #[arg(long, env = "DEMOCRACY_TUI")]
+    tui: bool,
+
+    #[command(flatten)]
+    policy: PolicyArgs,
+
+    /// TOML file of settings applied over the command line's, with the same keys as `democracy-ctl set` (e.g.
+    /// `policy = "round-robin"`). It's read again on SIGHUP, so the policy can be changed without detaching.
+    #[arg(long, env = "DEMOCRACY_CONFIG")]
+    config: Option<PathBuf>,
+
+    #[command(flatten)]
+    bpf: BpfArgs,
+
+    /// Reattach to the kernel whenever the BPF side of the scheduler exits on its own, instead of shutting down.
+    #[arg(long, env = "DEMOCRACY_RESTART_ON_BPF_EXIT")]
+    restart_on_bpf_exit: bool,
+
+    /// Leave the candidates' programs running when the scheduler exits, rather than stopping them.
+    #[arg(long, env = "DEMOCRACY_LEAVE_COMPETITORS_RUNNING")]
+    leave_competitors_running: bool,
+
+    #[command(flatten)]
+    restart: RestartArgs,
+
+    #[command(flatten)]
+    race: RaceArgs,
+
+    /// How often to look for new tasks belonging to the candidates and switch them to SCHED_EXT, in milliseconds.
+    /// This is how processes the scheduler attaches to, rather than launches, come under the election.
+    #[arg(
+        long,
+        env = "DEMOCRACY_ATTACH_SCAN_INTERVAL_MS",
+        default_value_t = 1000
+    )]
+    attach_scan_interval_ms: u64,
+
+    /// Where the election results come from.
+    #[arg(long, env = "DEMOCRACY_VOTE_SOURCE", value_enum, default_value_t = VoteSourceKind::Http)]
+    vote_source: VoteSourceKind,
+
+    /// Address of the ballot box, for `--vote-source http` and `--vote-source subscribe`. Race results are reported
+    /// here too.
+    #[arg(
+        long,
+        env = "DEMOCRACY_BALLOT_URL",
+        default_value = "http://localhost:8080"
+    )]
+    ballot_url: String,
+
+    /// How long to wait for the ballot box to answer before giving up, in milliseconds.
+    #[arg(long, env = "DEMOCRACY_BALLOT_TIMEOUT_MS", default_value_t = 1000)]
+    ballot_timeout_ms: u64,
+
+    /// Extra header to send to the ballot box, as `Name: value`. Can be given more than once. Race results are only
+    /// accepted with `Authorization: Bearer <token>`, where the token is the ballot box's `--race-token`.
+    #[arg(long = "ballot-header", value_name = "HEADER")]
+    ballot_headers: Vec<String>,
+
+    /// The results file for `--vote-source file`, or a fifo for `--vote-source stream` (which otherwise reads stdin).
+    #[arg(long, env = "DEMOCRACY_VOTES_FILE")]
+    votes_file: Option<PathBuf>,
+
+    /// How often to ask the vote source for results, in milliseconds. This happens in the background; the scheduler
+    /// always works from the last results received.
+    #[arg(long, env = "DEMOCRACY_POLL_INTERVAL_MS", default_value_t = 500)]
+    poll_interval_ms: u64,
+
+    /// How old the last results can get before we warn that the vote source isn't answering, in milliseconds.
+    #[arg(long, env = "DEMOCRACY_MAX_VOTE_AGE_MS", default_value_t = 5000)]
+    max_vote_age_ms: u64,
+
+    /// Address to serve Prometheus metrics on, at `/metrics`, e.g. `0.0.0.0:9100`. Metrics aren't served unless this
+    /// is given.
+    #[arg(long, env = "DEMOCRACY_METRICS_ADDRESS")]
+    metrics_address: Option<SocketAddr>,
+
+    /// Unix socket to listen on for `democracy-ctl`, which can ask how the scheduler is getting on and change its
+    /// settings while it runs.
+    #[arg(long, env = "DEMOCRACY_CONTROL_SOCKET", default_value = proto_control::DEFAULT_SOCKET)]
+    control_socket: PathBuf,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-impl Competitors {
-    fn from_str(input: &str) -> Result<Competitors> {
-        match input.to_lowercase().as_str() {
-            "summer1" => Ok(Competitors::Summer1),
-            "summer2" => Ok(Competitors::Summer2),
-            _ => bail!("Unknown competitor"),
-        }
-    }
+// Passed straight through to `BpfScheduler::init`. There's more on each of these at
+// https://github.com/sched-ext/scx/blob/main/scheds/rust/scx_rustland/src/main.rs#L85-L161
+#[derive(Debug, clap::Args)]
+struct BpfArgs {
+    /// Slice tasks get when the BPF side dispatches them without asking us, in microseconds.
+    #[arg(long, env = "DEMOCRACY_SLICE_US", default_value_t = 1_000_000)]
+    slice_us: u64,
+
+    /// Number of CPUs to schedule; defaults to every possible CPU on the machine.
+    #[arg(long, env = "DEMOCRACY_NR_CPUS")]
+    nr_cpus: Option<i32>,
+
+    /// Only schedule tasks that have asked for SCHED_EXT. Turning this off hands every task on the machine to the
+    /// scheduler, where anything that isn't a candidate has to make do with the fallback slices.
+    #[arg(long, env = "DEMOCRACY_PARTIAL", default_value_t = true, action = ArgAction::Set)]
+    partial: bool,
+
+    /// Size of the debug dump printed when the scheduler exits, in bytes; 0 uses the kernel's default.
+    #[arg(long, env = "DEMOCRACY_EXIT_DUMP_LEN", default_value_t = 0)]
+    exit_dump_len: u32,
+
+    /// Make every scheduling decision in userspace, rather than letting the BPF side short-cut some of them.
+    #[arg(long, env = "DEMOCRACY_FULL_USER", default_value_t = true, action = ArgAction::Set)]
+    full_user: bool,
+
+    /// Trade performance for power savings.
+    #[arg(long, env = "DEMOCRACY_LOW_POWER", default_value_t = false, action = ArgAction::Set)]
+    low_power: bool,
+
+    /// Let the BPF side fall back to plain FIFO when the machine is lightly loaded. This takes decisions away from the
+    /// vote, so it's off by default.
+    #[arg(long, env = "DEMOCRACY_FIFO_SCHED", default_value_t = false, action = ArgAction::Set)]
+    fifo_sched: bool,
+
+    /// Log every scheduling event to /sys/kernel/debug/tracing/trace_pipe.
+    #[arg(long, env = "DEMOCRACY_DEBUG", default_value_t = true, action = ArgAction::Set)]
+    debug: bool,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Run the scheduling policy against simulated tasks instead of the kernel and report how they fared. Doesn't
+    /// need sched_ext or root, so it's the quickest way to try out a policy.
+    Simulate(SimulateArgs),
 }
 
-// We could schedule this as a game which ever program gets to run the requisite amount of time is rewarded with the win
 // The votes are submitted by rank choice by http/json
 // We need to find some way to make the linux protections around the scheduler enable longer and we also need to make
 // sure that we can do things like display to the screen and offer an http endpoint.
@@ -61,84 +262,461 @@ impl Competitors {
 // against each other! WE need simplified rate-limiting to prevent people from calling curl a billion times to win.
 // Upon a special timer finishing(we can literally just have a "last voted time in a file somewhere that we reference")
 // The scheduler will specially allow that program to run for a certain amount of time.
-// The scheudler can then also track how long that program has been running for in vruntime
-// and print the winner via some other way(not sure on this yet) to make the scheduler spit out the winner in another way.
 // We should also make a live graph of who is winning.
 
+// Why the scheduler stopped running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Stopped {
+    Requested, // we were asked to shut down
+    BpfExited, // the BPF side went away by itself
+}
+
 #[derive(Debug, Clone)]
 struct Task {
-    pub vruntime: u64,
+    pub vruntime: u64, // CPU time it's had as a candidate's, scaled by its weight
     pub queued_task: QueuedTask,
+    pub owner: String, // the candidate it's competing for
 }
 
-// Main scheduler object
-struct Scheduler<'a> {
-    bpf: BpfScheduler<'a>,                // BPF connector
-    task_map: HashMap<u32, Option<Task>>, // pid to task
-    owner_map: HashMap<Competitors, u32>, // pid to binary owner
+// Main scheduler object. The backend is how we talk to the kernel: the real BPF connector when running for real or a
+// simulated one in tests, so none of the policy below depends on sched_ext being around.
+struct Scheduler<B: SchedulerBackend> {
+    backend: B,                      // BPF connector (or a stand-in)
+    roster: Roster,                  // candidates standing in the election
+    task_map: HashMap<u32, Task>,    // pid to task, for every candidate's queued tasks
+    groups: Groups,                  // which candidate each task belongs to
+    attacher: Option<Attacher>,      // puts candidates' tasks under SCHED_EXT
+    fallback: FairQueue,             // every other SCHED_EXT task, which runs outside the election
+    accounting: Accounting,          // CPU time each candidate has had
+    policy: SchedulingPolicy,        // how CPU time is handed out
+    interval: Duration,              // how long between decisions
+    winner_slice_ns: u64,            // slice the winner gets under winner-takes-all
+    tie_breaker: TieBreaker,         // picks who runs when the vote is tied
+    proportional: ProportionalShare, // slice sizes under the proportional policy
+    race: Option<Race>,              // the CPU-time race, if it's on
+    reporter: Option<RaceReporter>,  // tells the ballot box who won the race
+    metrics: Metrics,                // what we've been up to, for Prometheus
+    dashboard: Option<Dashboard>,    // shows what we've been up to in the terminal, under `--tui`
+    control: Option<ControlSocket>,  // where `democracy-ctl` asks us things
+    config: Option<PathBuf>,         // settings file, reloaded on SIGHUP
+    last_turn: Option<usize>,        // roster position of whoever went last under round-robin
+    programs: Vec<ProgramState>,     // whether each candidate's program is up, in roster order
+    started: Instant,                // when we started, for uptime
+    votes_stale: bool, // whether we've already warned that the results are out of date
 }
 
-impl<'a> Scheduler<'a> {
-    fn init() -> Result<Self> {
+// The number of CPUs to schedule, unless overridden on the command line.
+fn nr_cpus(args: &BpfArgs) -> i32 {
+    args.nr_cpus.unwrap_or_else(|| {
         // Initialize core mapping topology.
         let topo = Topology::new().expect("Failed to build host topology");
+        topo.nr_cpus_possible() as i32
+    })
+}
+
+// Attaches the BPF half of the scheduler to the kernel.
+fn init_bpf<'a>(args: &BpfArgs) -> Result<BpfScheduler<'a>> {
+    let nr_cpus = nr_cpus(args);
+
+    // This function is doing a lot of heavy lifting, it is our interface into the sched_ext hooks such that we
+    // can recieve and perform various scheudling events. See `BpfArgs` for what each of the parameters does.
+    let bpf = BpfScheduler::init(
+        args.slice_us,
+        nr_cpus,
+        args.partial,
+        args.exit_dump_len,
+        args.full_user,
+        args.low_power,
+        args.fifo_sched,
+        args.debug,
+    )?;
+
+    info!(name = SCHEDULER_NAME, cpus = nr_cpus, "scheduler attached");
 
+    Ok(bpf)
+}
+
+impl<B: SchedulerBackend> Scheduler<B> {
+    fn new(backend: B, roster: Roster, policy: &PolicyArgs) -> Self {
         // Scheduler task map to store tasks information.
         let task_map = HashMap::new();
-        let owner_map = HashMap::new();
-
-        let nr_cpus = topo.nr_cpus_possible();
 
-        // This function is doing a lot of heavy lifting, it is our interface into the sched_ext hooks such that we
-        // can recieve and perform various scheudling events. Let's explain some of the parameters it takes in.
-        // You can find a better explaination of these variables here: https://github.com/sched-ext/scx/blob/main/scheds/rust/scx_rustland/src/main.rs#L85-L161
+        // Candidates given a pid, name pattern or cgroup are bound to it from the start; the rest are bound to their
+        // program once it's launched.
+        let mut groups = Groups::new(Some(group::PROC_ROOT.into()));
+        for candidate in &roster.candidates {
+            match Membership::of(candidate) {
+                Ok(Some(membership)) => groups.bind(&candidate.id, membership),
+                Ok(None) => {}
+                Err(e) => {
+                    error!(err = %format!("{:#}", e), "Candidate's tasks won't be recognised")
+                }
+            }
+        }
 
-        let bpf = BpfScheduler::init(
-            1000000, // slice_us: How much time the task should be given to run in micro-seconds. 1000000 is 1s.
-            topo.nr_cpus_possible() as i32, // nr_cpus_online: Tells the scheduler how many CPUs they are and how many
-            true, // partial: Setting this to false tells BPF that we want to be responsible for how ALL tasks get scheudled, setting this to true says "only tasks that specifically set their scheduler to SCHED_EXT"
-            0, // exit_dump_len: Exit debug dump buffer length. 0 indicates default. I'll be honest i'm not exactly sure what this means, but if I had to guess I assume that this is the number in bytes of hte debug buffer which will be printed in debug mode when the scheudler is exited.
-            true, // full_user: Setting this to true tells BPF that we want all scheduler decisions to be made in user spaces, instead of it trying to optimize some of the decision making in kernel space
-            false, // low_power: This enables a bunch of settings that cause the CPU to operate in a way that saves power.
-            false, // fifo_sched: By default when there is low utilization the system will simply go into FIFO mode since that provides better performance. This turns that off since we want to control the scheduling.
-            true, // debug: Simply prints all events that occurred to /sys/kernel/debug/tracing/trace_pipe
-        )?;
+        // Until the supervisor says otherwise.
+        let programs = roster
+            .candidates
+            .iter()
+            .map(|candidate| {
+                if candidate.is_attached() {
+                    ProgramState::Attached
+                } else {
+                    ProgramState::Pending
+                }
+            })
+            .collect();
 
-        info!(name = SCHEDULER_NAME, cpus = nr_cpus, "scheduler attached");
+        let interval = Duration::from_millis(policy.schedule_interval_ms);
+        let proportional =
+            ProportionalShare::new(interval.as_nanos() as u64, roster.candidates.len());
 
-        Ok(Self {
-            bpf,
+        Self {
+            backend,
+            metrics: Metrics::new(&roster),
+            roster,
             task_map,
-            owner_map,
-        })
+            groups,
+            attacher: None,
+            fallback: FairQueue::new(policy.fallback_slice_us * 1000),
+            accounting: Accounting::default(),
+            policy: policy.policy,
+            interval,
+            winner_slice_ns: policy.winner_slice_us * 1000,
+            tie_breaker: TieBreaker::new(policy.tie_policy, policy.tie_seed),
+            proportional,
+            race: None,
+            reporter: None,
+            dashboard: None,
+            control: None,
+            config: None,
+            last_turn: None,
+            programs,
+            started: Instant::now(),
+            votes_stale: false,
+        }
+    }
+
+    // Starts scheduling the process tree rooted at `pid` on behalf of a candidate. Candidates in a cgroup are already
+    // bound to it, and their program is in there.
+    fn add_candidate(&mut self, id: &str, pid: u32) {
+        if self.groups.membership(id).is_none() {
+            self.groups.bind(id, Membership::Tree(pid));
+        }
+    }
+
+    // Stops scheduling a candidate's program, e.g. because it exited. Without a cgroup, whatever's left of its process
+    // tree has been orphaned and no longer counts either.
+    fn remove_candidate(&mut self, id: &str, pid: u32) {
+        self.task_map.remove(&pid);
+        self.groups.forget(pid);
+
+        if matches!(self.groups.membership(id), Some(Membership::Tree(root)) if *root == pid) {
+            self.groups.unbind(id);
+            self.task_map.retain(|_, task| task.owner != id);
+        }
+    }
+
+    // Keeps the pid maps in step with the supervisor as competitors exit and are restarted.
+    fn apply(&mut self, change: PidChange) {
+        match change {
+            PidChange::Started { id, pid } => self.add_candidate(&id, pid),
+            PidChange::Exited { id, pid } => self.remove_candidate(&id, pid),
+        }
+    }
+
+    fn schedule(&mut self, votes: &VotePoller) {
+        self.tick(votes);
+
+        std::thread::sleep(self.interval);
+
+        // Yield to avoid using too much CPU from the scheduler itself.
+        // thread::yield_now();
+    }
+
+    // Makes one scheduling decision from whatever results the poller last received; this never waits on the vote
+    // source. Until the first results arrive every candidate is treated as tied, same as before any votes are cast.
+    fn tick(&mut self, votes: &VotePoller) {
+        self.drain_queue();
+
+        let response = match votes.latest() {
+            Some(snapshot) => {
+                self.metrics.vote_poll(snapshot.latency, snapshot.stale);
+                if snapshot.stale != self.votes_stale {
+                    self.votes_stale = snapshot.stale;
+                    if snapshot.stale {
+                        warn!(age = ?snapshot.received.elapsed(), latency = ?snapshot.latency, "Results are out of date; carrying on with the last ones received");
+                    } else {
+                        info!("Results are up to date again");
+                    }
+                }
+                snapshot.results
+            }
+            None => WinnerResponse {
+                method: self.roster.method,
+                winner: None,
+                tied: self
+                    .roster
+                    .candidates
+                    .iter()
+                    .map(|c| c.id.clone())
+                    .collect(),
+                scores: vec![],
+                ballots: 0,
+                explanation: "No results have been received yet".into(),
+            },
+        };
+
+        self.dispatch(&response);
+
+        let cpu_time = self.cpu_time();
+        self.metrics
+            .observe(&self.backend.stats(), &cpu_time, &response);
+        self.update_dashboard(&response);
+        self.answer_control(&response);
+    }
+
+    // Answers whatever's been asked over the control socket since the last decision.
+    fn answer_control(&mut self, response: &WinnerResponse) {
+        let Some(pending) = self.control.as_ref().map(ControlSocket::pending) else {
+            return;
+        };
+
+        for pending in pending {
+            let answer = self.control_response(&pending.request, response);
+            pending.answer(answer);
+        }
+    }
+
+    fn control_response(&mut self, request: &Request, response: &WinnerResponse) -> Response {
+        match request {
+            Request::Stats => Response::Stats(proto_control::Stats {
+                uptime_ms: self.started.elapsed().as_millis() as u64,
+                winner: response.winner.clone(),
+                tied: response.tied.clone(),
+                ballots: response.ballots,
+                votes_stale: self.votes_stale,
+                queued_tasks: self.task_map.len() as u64,
+                candidate_dispatches: self
+                    .roster
+                    .candidates
+                    .iter()
+                    .map(|candidate| self.metrics.dispatches(&candidate.id))
+                    .sum(),
+                fallback_dispatches: self.metrics.fallback_dispatches(),
+            }),
+            Request::Policy => Response::Policy(self.policy_settings()),
+            Request::Candidates => Response::Candidates {
+                candidates: self
+                    .cpu_time()
+                    .into_iter()
+                    .zip(self.roster.candidates.iter().zip(&self.programs))
+                    .map(
+                        |((id, cpu_time), (candidate, program))| proto_control::CandidateStats {
+                            score: response
+                                .scores
+                                .iter()
+                                .find(|tally| tally.0.eq_ignore_ascii_case(&id))
+                                .map_or(0, |tally| tally.1),
+                            cpu_ms: cpu_time.as_millis() as u64,
+                            dispatches: self.metrics.dispatches(&id),
+                            queued_tasks: self.task_map.values().filter(|t| t.owner == id).count()
+                                as u64,
+                            program: *program,
+                            name: candidate.name.clone(),
+                            id,
+                        },
+                    )
+                    .collect(),
+            },
+            Request::Bpf => Response::Bpf {
+                counters: self
+                    .backend
+                    .stats()
+                    .counters()
+                    .iter()
+                    .map(|(name, _, value)| Tally(name.to_string(), *value))
+                    .collect(),
+            },
+            Request::Set(change) => match self.change_policy(change, "control socket") {
+                Ok(()) => Response::Policy(self.policy_settings()),
+                Err(e) => Response::Error {
+                    error: format!("{:#}", e),
+                },
+            },
+        }
+    }
+
+    // The current settings, as a change that would put them back.
+    fn policy_change(&self) -> PolicyChange {
+        let settings = self.policy_settings();
+        PolicyChange {
+            policy: Some(settings.policy),
+            tie_policy: Some(settings.tie_policy),
+            schedule_interval_ms: Some(settings.schedule_interval_ms),
+            winner_slice_us: Some(settings.winner_slice_us),
+            fallback_slice_us: Some(settings.fallback_slice_us),
+        }
+    }
+
+    // The settings deciding how votes become CPU time, as they are now.
+    fn policy_settings(&self) -> proto_control::Policy {
+        proto_control::Policy {
+            policy: control::name(self.policy),
+            tie_policy: control::name(self.tie_breaker.policy()),
+            schedule_interval_ms: self.interval.as_millis() as u64,
+            winner_slice_us: self.winner_slice_ns / 1000,
+            fallback_slice_us: self.fallback.slice_ns() / 1000,
+        }
+    }
+
+    // Changes settings while we run, from the control socket or the config file (`from` says which, for the log).
+    // They're all checked before any of them are changed, and the BPF side stays attached throughout.
+    fn change_policy(&mut self, change: &PolicyChange, from: &str) -> Result<()> {
+        let update = PolicyUpdate::parse(change)?;
+        if update.policy == Some(SchedulingPolicy::Race) && self.race.is_none() {
+            bail!("The race policy needs the race to be on; start the scheduler with --race-target-ms");
+        }
+
+        if let Some(policy) = update.policy.filter(|policy| *policy != self.policy) {
+            // Whatever was owed under proportional last time it was used is long out of date.
+            if policy == SchedulingPolicy::Proportional {
+                self.proportional = ProportionalShare::new(
+                    self.interval.as_nanos() as u64,
+                    self.roster.candidates.len(),
+                );
+            }
+            self.policy = policy;
+        }
+        if let Some(tie_policy) = update.tie_policy {
+            self.tie_breaker.set_policy(tie_policy);
+        }
+        if let Some(interval) = update.schedule_interval {
+            self.interval = interval;
+            self.proportional
+                .set_period_ns(self.interval.as_nanos() as u64);
+        }
+        if let Some(slice_ns) = update.winner_slice_ns {
+            self.winner_slice_ns = slice_ns;
+        }
+        if let Some(slice_ns) = update.fallback_slice_ns {
+            self.fallback.set_slice_ns(slice_ns);
+        }
+
+        info!(settings = ?self.policy_settings(), from = from, "Settings changed");
+
+        Ok(())
     }
 
-    fn schedule(&mut self) {
-        // First lets drain all the tasks from the queue and only keep track of the ones that we want to focus on
-        // scheduling.
+    // Applies the config file, if there is one. A bad config is logged and otherwise ignored, so a typo doesn't bring
+    // down a running scheduler.
+    fn reload_config(&mut self) {
+        let Some(path) = self.config.clone() else {
+            return;
+        };
+
+        if let Err(e) = load_config(&path).and_then(|change| self.change_policy(&change, "config"))
+        {
+            error!(path = %path.display(), err = %format!("{:#}", e), "Could not apply config file; carrying on as before");
+        }
+    }
+
+    // Shows the dashboard how things stand after a decision, if it's up.
+    fn update_dashboard(&mut self, response: &WinnerResponse) {
+        let Some(nr_cpus) = self.dashboard.as_ref().map(Dashboard::nr_cpus) else {
+            return;
+        };
+
+        let status = self.status(response, nr_cpus);
+        if let Some(dashboard) = &self.dashboard {
+            dashboard.update(status);
+        }
+    }
+
+    // How things stand, for the dashboard.
+    fn status(&mut self, response: &WinnerResponse, nr_cpus: usize) -> Status {
+        let candidates = self
+            .cpu_time()
+            .into_iter()
+            .zip(&self.roster.candidates)
+            .map(|((id, cpu_time), candidate)| CandidateStatus {
+                score: response
+                    .scores
+                    .iter()
+                    .find(|tally| tally.0.eq_ignore_ascii_case(&id))
+                    .map_or(0, |tally| tally.1),
+                dispatches: self.metrics.dispatches(&id),
+                name: candidate.name.clone(),
+                cpu_time,
+                id,
+            })
+            .collect();
+
+        let cpus = (0..nr_cpus)
+            .map(|cpu| match self.backend.get_cpu_pid(cpu as i32) {
+                0 => CpuOwner::Idle,
+                pid => match self.groups.owner(pid) {
+                    Some(id) => CpuOwner::Candidate(id),
+                    None => CpuOwner::Other(pid),
+                },
+            })
+            .collect();
+
+        Status {
+            method: response.method,
+            winner: response.winner.clone(),
+            tied: response.tied.clone(),
+            ballots: response.ballots,
+            votes_stale: self.votes_stale,
+            candidates,
+            cpus,
+            bpf: self.backend.stats(),
+        }
+    }
+
+    // First lets drain all the tasks from the queue and only keep track of the ones that we want to focus on
+    // scheduling.
+    fn drain_queue(&mut self) {
         loop {
-            match self.bpf.dequeue_task() {
+            match self.backend.dequeue_task() {
                 // We were able to get a new task to schedule.
                 Ok(Some(task)) => {
-                    // check if the pid is one we care about
-                    if !self.task_map.contains_key(&(task.pid as u32)) {
+                    let pid = task.pid as u32;
+
+                    // Exiting tasks come through one last time without a CPU; after this their pid can be reused.
+                    if task.cpu < 0 {
+                        self.task_map.remove(&pid);
+                        if let Some(owner) = self.groups.owner(pid) {
+                            self.accounting.charge(&owner, &task);
+                        }
+                        self.accounting.exited(pid);
+                        self.groups.forget(pid);
+                        self.fallback.remove(pid);
                         continue;
                     }
 
-                    // If it does grab it and stick it in the map
+                    // check if the task belongs to one of the candidates; if not it still has to run somehow.
+                    let Some(owner) = self.groups.owner(pid) else {
+                        self.fallback.push(task);
+                        continue;
+                    };
+
+                    // If it does charge the candidate for what it ran since last time, and stick it in the map
+                    let vruntime = self.accounting.charge(&owner, &task);
                     self.task_map.insert(
-                        task.pid as u32,
-                        Some(Task {
+                        pid,
+                        Task {
                             queued_task: task,
-                            vruntime: 0,
-                        }),
+                            vruntime,
+                            owner,
+                        },
                     );
                     continue;
                 }
 
                 // The queue is empty.
                 Ok(None) => {
-                    self.bpf.update_tasks(Some(0), Some(0));
+                    self.backend.update_tasks(Some(0), Some(0));
                     break;
                 }
 
@@ -149,71 +727,266 @@ impl<'a> Scheduler<'a> {
                 }
             }
         }
+    }
 
-        let winner = match get_current_winner() {
-            Ok(winner) => winner,
-            Err(e) => {
-                error!(err = %e, "There was no winner when we checked");
-                std::thread::sleep(std::time::Duration::from_secs(1));
-                return;
+    // Hands out the CPU according to the election results and the scheduling policy.
+    fn dispatch(&mut self, response: &WinnerResponse) {
+        match self.policy {
+            SchedulingPolicy::WinnerTakesAll => {
+                let winners = self.tie_breaker.decide(&self.roster, response);
+                if winners.is_empty() {
+                    error!(winner = ?response.winner, tied = ?response.tied, "Ballot box reported a winner not in the roster");
+                }
+
+                for winner in winners {
+                    self.dispatch_candidate(&winner, self.winner_slice_ns);
+                }
             }
-        };
+            SchedulingPolicy::Proportional => self.dispatch_proportionally(response),
+            SchedulingPolicy::RoundRobin => {
+                if let Some(id) = self.next_turn() {
+                    self.dispatch_candidate(&id, self.winner_slice_ns);
+                }
+            }
+            SchedulingPolicy::Race => {
+                let ids: Vec<String> = self
+                    .roster
+                    .candidates
+                    .iter()
+                    .map(|c| c.id.clone())
+                    .collect();
+                for id in ids {
+                    self.dispatch_candidate(&id, self.winner_slice_ns);
+                }
+            }
+        }
 
-        let winner_pid = self.owner_map.get(&winner).unwrap();
-        let winner_task = self.task_map.get(winner_pid).unwrap();
-        let winner_task = match winner_task.clone() {
-            Some(task) => task,
-            None => return,
-        };
+        self.dispatch_fallback();
+    }
 
-        let mut dispatched_task = DispatchedTask::new(&winner_task.queued_task);
-        dispatched_task.set_slice_ns(100000000);
+    // Whose turn it is under round-robin: the next candidate in roster order after whoever went last, skipping anyone
+    // with nothing to run.
+    fn next_turn(&mut self) -> Option<String> {
+        let count = self.roster.candidates.len();
+        let start = self.last_turn.map_or(0, |last| last + 1);
 
-        match self.bpf.dispatch_task(&dispatched_task) {
-            Ok(_) => {
-                info!(pid =  winner_pid, owner = ?winner, "Task successfully scheduled");
+        let turn = (start..start + count).map(|i| i % count).find(|&i| {
+            let id = &self.roster.candidates[i].id;
+            self.task_map.values().any(|task| &task.owner == id)
+        })?;
+        self.last_turn = Some(turn);
+
+        Some(self.roster.candidates[turn].id.clone())
+    }
+
+    // Dispatches everything that isn't a candidate, after the candidates so the election still comes first.
+    fn dispatch_fallback(&mut self) {
+        for (task, slice_ns) in self.fallback.drain() {
+            let mut dispatched_task = DispatchedTask::new(&task);
+            dispatched_task.set_slice_ns(slice_ns);
+
+            match self.backend.dispatch_task(&dispatched_task) {
+                Ok(_) => {
+                    debug!(pid = task.pid, "Non-candidate task scheduled");
+                    self.metrics.fallback_dispatched();
+                }
+                Err(e) => {
+                    error!(pid = task.pid, error = %e, "Could not schedule non-candidate task")
+                }
             }
-            Err(e) => {
-                error!(pid = winner_pid, owner = ?winner, error = %e, "Could not schedule task");
-                // If there is an error here in a real scheudler we would attempt to schedule
-                // the task again, but here we just error and continue.
+        }
+    }
+
+    // Dispatches every candidate with a slice sized by their share of the vote.
+    fn dispatch_proportionally(&mut self, response: &WinnerResponse) {
+        let mut votes = vec![0; self.roster.candidates.len()];
+        for tally in &response.scores {
+            if let Some(position) = self.roster.position(&tally.0) {
+                votes[position] = tally.1;
+            }
+        }
+
+        let runtime: Vec<Option<u64>> = self
+            .roster
+            .candidates
+            .iter()
+            .map(|candidate| Some(self.accounting.usage(&candidate.id)?.cpu_ns))
+            .collect();
+
+        let slices = self.proportional.slices(&votes, &runtime);
+
+        let ids: Vec<String> = self
+            .roster
+            .candidates
+            .iter()
+            .map(|c| c.id.clone())
+            .collect();
+        for (id, slice_ns) in ids.iter().zip(slices) {
+            // What the candidate is owed is shared between all of its queued tasks, or a candidate with more threads
+            // would get more than its share.
+            let queued = self.task_map.values().filter(|t| &t.owner == id).count() as u64;
+            debug!(owner = %id, slice_ns = slice_ns, queued = queued, "Proportional slice");
+            if slice_ns > 0 && queued > 0 {
+                self.dispatch_candidate(id, (slice_ns / queued).max(1));
             }
         }
+    }
 
-        // this is a hack so the other scheduler doesn't try to get to it before we do.
-        // let pidkill = Pid::from_raw(*winner_pid as i32);
-        // kill(pidkill, Signal::SIGCONT).unwrap();
+    // Dispatches every task the candidate has queued, so all of its threads and processes run together. The ones that
+    // have had the least CPU go first. Each is taken off the queue as it's dispatched, like the fallback queue does, so it
+    // isn't dispatched again until the BPF side queues it again.
+    fn dispatch_candidate(&mut self, winner: &str, slice_ns: u64) {
+        let mut winner_pids: Vec<(u64, u32)> = self
+            .task_map
+            .iter()
+            .filter(|(_, task)| task.owner == winner)
+            .map(|(pid, task)| (task.vruntime, *pid))
+            .collect();
+        winner_pids.sort_unstable();
 
-        let mut winner_task = winner_task.clone();
-        winner_task.vruntime += 1000000000;
+        // Nothing to run if the candidate's program is being restarted, or hasn't been queued yet.
+        if winner_pids.is_empty() {
+            debug!(owner = %winner, "Candidate has no queued tasks");
+            return;
+        }
 
-        self.task_map.insert(*winner_pid, Some(winner_task.clone()));
+        for (_, winner_pid) in winner_pids {
+            let Some(winner_task) = self.task_map.remove(&winner_pid) else {
+                continue;
+            };
 
-        std::thread::sleep(std::time::Duration::from_millis(500));
+            let mut dispatched_task = DispatchedTask::new(&winner_task.queued_task);
+            dispatched_task.set_slice_ns(slice_ns);
 
-        // Yield to avoid using too much CPU from the scheduler itself.
-        // thread::yield_now();
+            // this is a hack so the other scheduler doesn't try to get to it before we do.
+            // let pidkill = Pid::from_raw(*winner_pid as i32);
+            // kill(pidkill, Signal::SIGCONT).unwrap();
+
+            match self.backend.dispatch_task(&dispatched_task) {
+                Ok(_) => {
+                    debug!(pid = winner_pid, owner = %winner, "Task successfully scheduled");
+                    self.metrics.candidate_dispatched(winner);
+                }
+                Err(e) => {
+                    error!(pid = winner_pid, owner = %winner, error = %e, "Could not schedule task");
+                    // If there is an error here in a real scheudler we would attempt to schedule
+                    // the task again, but here we just error and continue.
+                }
+            }
+        }
     }
 
-    fn run(&mut self, shutdown: Arc<AtomicBool>) -> Result<()> {
+    fn run(
+        &mut self,
+        votes: &VotePoller,
+        supervisor: &mut Supervisor,
+        shutdown: &AtomicBool,
+    ) -> Stopped {
         while !shutdown.load(Ordering::Relaxed) {
+            if self.backend.exited() {
+                return Stopped::BpfExited;
+            }
+
+            for change in supervisor.poll() {
+                self.apply(change);
+            }
+            for (id, state) in supervisor.states() {
+                if let Some(position) = self.roster.position(&id) {
+                    self.programs[position] = state.into();
+                }
+            }
+
+            if let Some(attacher) = &mut self.attacher {
+                attacher.poll(&mut self.groups);
+            }
+
+            if RELOAD_CONFIG.swap(false, Ordering::Relaxed) {
+                info!("Reloading config");
+                self.reload_config();
+            }
+
             // Call the main scheduler body.
-            self.schedule();
+            self.schedule(votes);
+
+            if let Some(result) = self.check_race() {
+                if let Some(reporter) = &self.reporter {
+                    reporter.report(result);
+                }
+            }
         }
 
-        Ok(())
+        Stopped::Requested
+    }
+
+    // Returns the result of the CPU-time race if someone has just won it.
+    fn check_race(&mut self) -> Option<RaceResult> {
+        self.race.as_ref()?;
+
+        let cpu_time = self.cpu_time();
+        let result = self.race.as_mut()?.check(&cpu_time)?;
+        info!(round = result.round, winner = %result.winner, cpu_ms = ?result.cpu_ms, "Race won!");
+
+        Some(result)
+    }
+
+    // CPU time each candidate has had, in roster order.
+    fn cpu_time(&self) -> Vec<(String, Duration)> {
+        self.roster
+            .candidates
+            .iter()
+            .map(|candidate| {
+                let usage = self.accounting.usage(&candidate.id).unwrap_or_default();
+                (candidate.id.clone(), usage.cpu_time())
+            })
+            .collect()
+    }
+
+    // Detaches from the kernel and logs why the BPF side exited, and how the candidates fared.
+    fn shutdown(&mut self) -> Result<()> {
+        for (id, cpu_time) in self.cpu_time() {
+            info!(owner = %id, cpu_time = ?cpu_time, "CPU time");
+        }
+
+        self.backend.shutdown_and_report()
     }
 }
 
 // Unregister the scheduler.
-// impl<'a> Drop for Scheduler<'a> {
+// impl<B: SchedulerBackend> Drop for Scheduler<B> {
 //     fn drop(&mut self) {
 //         info!("Unregister {} scheduler", SCHEDULER_NAME);
 //     }
 // }
 
-fn main() -> Result<()> {
-    init_logger().unwrap();
+fn main() -> Result<ExitCode> {
+    let args = Args::parse();
+
+    if let Some(Command::Simulate(sim_args)) = &args.command {
+        // The scheduler logs every dispatch, which would drown out the report.
+        init_logger(LevelFilter::WARN, None).unwrap();
+
+        let roster = Roster::load(&args.roster)?;
+        let report = sim::simulate(&roster, &args.policy, sim_args)?;
+        print!("{}", report);
+
+        return Ok(ExitCode::SUCCESS);
+    }
+
+    // The dashboard takes over the terminal, so while it's up the log has to be kept for it to show instead.
+    let tui = args.tui && io::stdout().is_terminal();
+    let logs = LogBuffer::default();
+    init_logger(LevelFilter::DEBUG, tui.then(|| logs.clone())).unwrap();
+    if args.tui && !tui {
+        warn!("Not showing the dashboard since stdout isn't a terminal; logging instead");
+    }
+
+    let roster = Roster::load(&args.roster)?;
+    validate(&args, &roster)?;
+
+    if args.dry_run {
+        print_dry_run(&args, &roster);
+        return Ok(ExitCode::SUCCESS);
+    }
 
     info!("Managed Democracy scheduler is starting...");
 
@@ -223,30 +996,387 @@ fn main() -> Result<()> {
         shutdown_clone.store(true, Ordering::Relaxed);
     })
     .context("Error setting Ctrl-C handler")?;
+    if args.config.is_some() {
+        reload_on_sighup()?;
+    }
+
+    let votes = VotePoller::spawn(
+        vote_source(&args)?,
+        Duration::from_millis(args.poll_interval_ms),
+        Duration::from_millis(args.max_vote_age_ms),
+    );
+
+    // Listen before attaching, so that if another scheduler already has the socket or the address we give up before
+    // the BPF side has taken over anything.
+    let control = ControlSocket::bind(&args.control_socket)?;
+    let metrics = Metrics::new(&roster);
+    if let Some(address) = args.metrics_address {
+        metrics.serve(address)?;
+    }
+
+    let mut sched = Scheduler::new(init_bpf(&args.bpf)?, roster.clone(), &args.policy);
+    sched.control = Some(control);
+    sched.metrics = metrics;
+    if tui {
+        match Dashboard::spawn(nr_cpus(&args.bpf) as usize, logs, shutdown.clone()) {
+            Ok(dashboard) => sched.dashboard = Some(dashboard),
+            Err(e) => {
+                warn!(err = %format!("{:#}", e), "Could not show the dashboard; logging instead")
+            }
+        }
+    }
 
-    let mut sched = Scheduler::init()?;
+    let mut supervisor = Supervisor::new(&roster, &args.restart);
+    let result = supervisor
+        .start()
+        .and_then(|_| run_until_stopped(sched, &args, &roster, &votes, &mut supervisor, &shutdown));
 
-    let summer_1_pid = launch_process("thingdoer", "summer1");
-    let summer_2_pid = launch_process("thingdoer", "summer2");
+    if args.leave_competitors_running {
+        info!("Leaving competitors running");
+    } else {
+        supervisor.stop(COMPETITOR_STOP_GRACE);
+    }
 
-    sched.task_map.insert(summer_1_pid, None);
-    sched.task_map.insert(summer_2_pid, None);
+    result
+}
 
-    sched.owner_map.insert(Competitors::Summer1, summer_1_pid);
-    sched.owner_map.insert(Competitors::Summer2, summer_2_pid);
+// Schedules until we're asked to stop or the BPF side exits (and isn't being restarted), and works out what to exit
+// with.
+fn run_until_stopped(
+    mut sched: Scheduler<BpfScheduler>,
+    args: &Args,
+    roster: &Roster,
+    votes: &VotePoller,
+    supervisor: &mut Supervisor,
+    shutdown: &AtomicBool,
+) -> Result<ExitCode> {
+    for (id, pid) in supervisor.running() {
+        sched.add_candidate(&id, pid);
+    }
+    sched.attacher = Some(attacher(args));
+    sched.race = Race::new(&args.race);
+    sched.config = args.config.clone();
+    sched.reload_config();
+    if sched.race.is_some() {
+        sched.reporter = Some(RaceReporter::spawn(
+            &args.ballot_url,
+            Duration::from_millis(args.ballot_timeout_ms),
+            &args.ballot_headers,
+        )?);
+    }
 
     loop {
-        // Start the scheduler.
-        if let Err(e) = sched.run(shutdown.clone()) {
-            eprint!("scheduler has shutdown; {:#?}", e);
-            break;
+        let stopped = sched.run(votes, supervisor, shutdown);
+
+        // Put the terminal back before reporting how things went, unless we're about to carry on.
+        let restarting = stopped == Stopped::BpfExited
+            && args.restart_on_bpf_exit
+            && !shutdown.load(Ordering::Relaxed);
+        if !restarting {
+            sched.dashboard = None;
+        }
+
+        let report = sched.shutdown();
+        if let Err(e) = &report {
+            error!(err = %e, "BPF scheduler exited with an error");
+        }
+
+        match stopped {
+            Stopped::Requested => {
+                info!("Scheduler shut down");
+                return Ok(if report.is_ok() {
+                    ExitCode::SUCCESS
+                } else {
+                    ExitCode::FAILURE
+                });
+            }
+            Stopped::BpfExited if args.restart_on_bpf_exit && !shutdown.load(Ordering::Relaxed) => {
+                warn!(delay = ?BPF_RESTART_DELAY, "BPF scheduler exited; reattaching");
+
+                // The old scheduler has to be completely gone before a new one can attach. Its accounting (and so
+                // the race) carries on, since the candidates kept running.
+                let accounting = std::mem::take(&mut sched.accounting);
+                let race = sched.race.take();
+                let reporter = sched.reporter.take();
+                let metrics = sched.metrics.clone();
+                let dashboard = sched.dashboard.take();
+                let control = sched.control.take();
+                let started = sched.started;
+                let settings = sched.policy_change();
+                drop(sched);
+                thread::sleep(BPF_RESTART_DELAY);
+
+                sched = Scheduler::new(init_bpf(&args.bpf)?, roster.clone(), &args.policy);
+                sched.accounting = accounting;
+                sched.race = race;
+                sched.reporter = reporter;
+                sched.metrics = metrics;
+                sched.dashboard = dashboard;
+                sched.control = control;
+                sched.started = started;
+                sched.config = args.config.clone();
+                sched.change_policy(&settings, "before reattaching")?;
+                for (id, pid) in supervisor.running() {
+                    sched.add_candidate(&id, pid);
+                }
+
+                // Everything went back to the normal scheduler when the BPF side exited, so start from scratch.
+                sched.attacher = Some(attacher(args));
+            }
+            Stopped::BpfExited => {
+                error!("BPF scheduler exited; shutting down");
+                return Ok(ExitCode::from(EXIT_BPF_EXITED));
+            }
+        }
+    }
+}
+
+fn attacher(args: &Args) -> Attacher {
+    Attacher::new(
+        group::PROC_ROOT.into(),
+        Duration::from_millis(args.attach_scan_interval_ms),
+    )
+}
+
+// Catches configuration mistakes up front, before we've attached to the kernel or launched anything.
+fn validate(args: &Args, roster: &Roster) -> Result<()> {
+    for candidate in &roster.candidates {
+        Membership::of(candidate)?;
+
+        if let Some(pid) = candidate.pid {
+            if !Path::new(group::PROC_ROOT).join(pid.to_string()).is_dir() {
+                bail!(
+                    "Roster candidate '{}' is bound to pid {}, which isn't running",
+                    candidate.id,
+                    pid
+                );
+            }
+        }
+
+        let bin_name = match candidate.command.first() {
+            Some(bin_name) => Some(bin_name),
+            None if candidate.is_attached() => None,
+            None => bail!(
+                "Roster candidate '{}' has no command for the scheduler to launch, nor a pid, comm or cgroup to attach to",
+                candidate.id
+            ),
+        };
+
+        if let Some(bin_name) = bin_name.filter(|bin_name| find_binary(bin_name).is_none()) {
+            bail!(
+                "Can't find '{}' to launch for roster candidate '{}'",
+                bin_name,
+                candidate.id
+            );
+        }
+
+        if let Some(cgroup) = &candidate.cgroup {
+            let dir = group::cgroup_dir(cgroup);
+            if !dir.join("cgroup.procs").is_file() {
+                bail!(
+                    "Roster candidate '{}' is bound to cgroup '{}', but '{}' isn't a cgroup v2 directory",
+                    candidate.id,
+                    cgroup,
+                    dir.display()
+                );
+            }
+        }
+    }
+
+    if args.policy.schedule_interval_ms == 0 {
+        bail!("--schedule-interval-ms must be at least 1");
+    }
+    if args.policy.schedule_interval_ms > MAX_SCHEDULE_INTERVAL_MS {
+        bail!(
+            "--schedule-interval-ms can be at most {}",
+            MAX_SCHEDULE_INTERVAL_MS
+        );
+    }
+    if args.policy.winner_slice_us == 0 {
+        bail!("--winner-slice-us must be at least 1");
+    }
+    if args.policy.fallback_slice_us == 0 {
+        bail!("--fallback-slice-us must be at least 1");
+    }
+    if args.policy.winner_slice_us > MAX_SLICE_US {
+        bail!("--winner-slice-us can be at most {}", MAX_SLICE_US);
+    }
+    if args.policy.fallback_slice_us > MAX_SLICE_US {
+        bail!("--fallback-slice-us can be at most {}", MAX_SLICE_US);
+    }
+    if let Some(nr_cpus) = args.bpf.nr_cpus {
+        if nr_cpus < 1 {
+            bail!("--nr-cpus must be at least 1");
+        }
+
+        // The BPF side only keeps track of the CPUs that are possible on this machine.
+        let possible = Topology::new()
+            .context("Could not read the host topology")?
+            .nr_cpus_possible();
+        if nr_cpus as usize > possible {
+            bail!(
+                "--nr-cpus can be at most {}, the number of possible CPUs on this machine",
+                possible
+            );
+        }
+    }
+    if args.attach_scan_interval_ms == 0 {
+        bail!("--attach-scan-interval-ms must be at least 1");
+    }
+    if args.race.race_target_ms == Some(0) {
+        bail!("--race-target-ms must be at least 1");
+    }
+    let config = match &args.config {
+        Some(path) => PolicyUpdate::parse(&load_config(path)?)?,
+        None => PolicyUpdate::default(),
+    };
+    if config.policy.unwrap_or(args.policy.policy) == SchedulingPolicy::Race
+        && args.race.race_target_ms.is_none()
+    {
+        bail!("--policy race needs --race-target-ms");
+    }
+    if args.poll_interval_ms == 0 {
+        bail!("--poll-interval-ms must be at least 1");
+    }
+    let socket_dir = match args.control_socket.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if !socket_dir.is_dir() {
+        bail!(
+            "Can't create the control socket {}, since {} isn't a directory",
+            args.control_socket.display(),
+            socket_dir.display()
+        );
+    }
+    if args.vote_source == VoteSourceKind::File && args.votes_file.is_none() {
+        bail!("--vote-source file needs --votes-file");
+    }
+
+    Ok(())
+}
+
+// Where `bin_name` would be run from: as given if it's a path, otherwise the first match on $PATH.
+fn find_binary(bin_name: &str) -> Option<PathBuf> {
+    if bin_name.contains('/') {
+        let path = PathBuf::from(bin_name);
+        return path.is_file().then_some(path);
+    }
+
+    std::env::split_paths(&std::env::var_os("PATH")?)
+        .map(|dir| dir.join(bin_name))
+        .find(|path| path.is_file())
+}
+
+fn print_dry_run(args: &Args, roster: &Roster) {
+    println!("Configuration is valid; not attaching since this is a dry run.");
+    println!();
+    println!("Candidates ({}):", args.roster.display());
+    for candidate in &roster.candidates {
+        let what = match (&candidate.pid, &candidate.comm) {
+            (Some(pid), _) => format!("attach to pid {}", pid),
+            (_, Some(comm)) => format!("attach to processes named /{}/", comm),
+            _ if candidate.is_attached() => "attach to everything in its cgroup".into(),
+            _ => candidate.command.join(" "),
+        };
+        println!("  {} ({}): {}", candidate.id, candidate.name, what);
+        if let Some(cgroup) = &candidate.cgroup {
+            println!("    in cgroup {}", group::cgroup_dir(cgroup).display());
+        }
+    }
+    println!();
+    println!(
+        "Votes from: {:?} ({})",
+        args.vote_source,
+        match args.vote_source {
+            VoteSourceKind::Http | VoteSourceKind::Subscribe => args.ballot_url.clone(),
+            VoteSourceKind::File | VoteSourceKind::Stream => args
+                .votes_file
+                .as_ref()
+                .map_or("stdin".into(), |path| path.display().to_string()),
         }
+    );
+    if let Some(address) = args.metrics_address {
+        println!("Metrics served at http://{}{}", address, metrics::PATH);
+    }
+    if let Some(target_ms) = args.race.race_target_ms {
+        println!(
+            "CPU-time race: first to {:?} of CPU wins, then {:?}",
+            Duration::from_millis(target_ms),
+            args.race.race_finish
+        );
     }
+    println!(
+        "Control socket: {} (for democracy-ctl)",
+        args.control_socket.display()
+    );
+    if let Some(path) = &args.config {
+        println!(
+            "Config: {} (applied over the settings below, and reloaded on SIGHUP)",
+            path.display()
+        );
+    }
+    println!();
+
+    let policy = &args.policy;
+    println!("Policy: {}", control::name(policy.policy));
+    println!(
+        "Ties broken by: {} (seed {})",
+        control::name(policy.tie_policy),
+        policy.tie_seed
+    );
+    println!(
+        "Decisions every {:?}; winner slice {:?}, fallback slice {:?}",
+        Duration::from_millis(policy.schedule_interval_ms),
+        Duration::from_micros(policy.winner_slice_us),
+        Duration::from_micros(policy.fallback_slice_us)
+    );
+    println!();
+
+    let bpf = &args.bpf;
+    let yes_no = |on: bool| if on { "yes" } else { "no" };
+    println!("CPUs to schedule: {}", nr_cpus(bpf));
+    println!(
+        "Tasks scheduled: {}",
+        if bpf.partial {
+            "only those that asked for SCHED_EXT"
+        } else {
+            "every task on the machine"
+        }
+    );
+    println!("BPF slice: {:?}", Duration::from_micros(bpf.slice_us));
+    println!(
+        "Every decision made in userspace: {}",
+        yes_no(bpf.full_user)
+    );
+    println!("FIFO when lightly loaded: {}", yes_no(bpf.fifo_sched));
+    println!("Low power: {}", yes_no(bpf.low_power));
+    println!("Tracing to trace_pipe: {}", yes_no(bpf.debug));
+    match bpf.exit_dump_len {
+        0 => println!("Exit dump: the kernel's default size"),
+        len => println!("Exit dump: {} bytes", len),
+    }
+}
+
+// Has SIGHUP reload the config file. It replaces the handler Ctrl-C was given, which would otherwise shut us down.
+fn reload_on_sighup() -> Result<()> {
+    extern "C" fn reload(_: libc::c_int) {
+        RELOAD_CONFIG.store(true, Ordering::Relaxed);
+    }
+
+    let action = SigAction::new(
+        SigHandler::Handler(reload),
+        SaFlags::SA_RESTART,
+        SigSet::empty(),
+    );
+    // Safe since the handler only touches an atomic.
+    unsafe { sigaction(Signal::SIGHUP, &action) }.context("Error setting SIGHUP handler")?;
 
     Ok(())
 }
 
-fn init_logger() -> Result<()> {
+// Logs to stdout, or to `logs` for the dashboard to show while it's up.
+fn init_logger(level: LevelFilter, logs: Option<LogBuffer>) -> Result<()> {
     let filter = EnvFilter::from_default_env()
         // These directives filter out debug information that is too numerous and we generally don't need during
         // development.
@@ -258,60 +1388,797 @@ fn init_logger() -> Result<()> {
         .add_directive("reqwest=off".parse().expect("Invalid directive"))
         .add_directive("tungstenite=off".parse().expect("Invalid directive"))
         .add_directive("scx_utils=off".parse().expect("Invalid directive"))
-        .add_directive(LevelFilter::DEBUG.into()); // Accept debug level logs and above for everything else
+        .add_directive(level.into()); // Accept logs at `level` and above for everything else
 
-    tracing_subscriber::fmt()
+    let subscriber = tracing_subscriber::fmt()
         .with_env_filter(filter)
         .with_target(true)
-        .compact()
-        .init();
+        .compact();
+
+    match logs {
+        Some(logs) => subscriber.with_ansi(false).with_writer(logs).init(),
+        None => subscriber.init(),
+    }
 
     Ok(())
 }
 
-// Launches a process and returns the PID.
-fn launch_process(bin_name: &str, name: &str) -> u32 {
-    // Launch the process
-    let mut command = std::process::Command::new(bin_name);
-    command.arg(name);
-
-    let child = command
-        .stdout(std::process::Stdio::null()) // Don't overwhelm with stdout logs
-        .spawn()
-        .expect("Failed to start process");
+// Sets up wherever the election results are coming from. Counting is entirely up to the ballot box (see
+// `/api/winner`); what to do about ties is up to the scheduler's tie policy.
+fn vote_source(args: &Args) -> Result<Box<dyn VoteSource>> {
+    let source: Box<dyn VoteSource> = match (args.vote_source, &args.votes_file) {
+        (VoteSourceKind::Http, _) => Box::new(HttpVoteSource::new(
+            &args.ballot_url,
+            Duration::from_millis(args.ballot_timeout_ms),
+            &args.ballot_headers,
+        )?),
+        (VoteSourceKind::Subscribe, _) => Box::new(StreamVoteSource::subscribe(
+            &args.ballot_url,
+            Duration::from_millis(args.ballot_timeout_ms),
+            &args.ballot_headers,
+        )?),
+        (VoteSourceKind::File, Some(path)) => Box::new(FileVoteSource::new(path)),
+        (VoteSourceKind::File, None) => bail!("--vote-source file needs --votes-file"),
+        (VoteSourceKind::Stream, Some(path)) => Box::new(StreamVoteSource::fifo(path)),
+        (VoteSourceKind::Stream, None) => Box::new(StreamVoteSource::stdin()),
+    };
 
-    // Get the PID of the launched process
-    let pid = child.id();
-    info!(pid = pid, bin_name = bin_name, "Launched process");
+    info!(source = ?args.vote_source, "reading votes");
 
-    pid
+    Ok(source)
 }
 
-#[derive(Debug, Deserialize)]
-struct Vote(String, u32);
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::SimulatedBackend;
+    use democracy_proto::{CountingMethod, Tally};
+    use std::io::Write;
+    use std::net::TcpListener;
 
-#[derive(Debug, Deserialize)]
-struct CurrentWinnerResponse {
-    votes: Vec<Vote>,
-}
+    struct NoBallotBox;
+
+    impl VoteSource for NoBallotBox {
+        fn current(&mut self) -> Result<WinnerResponse> {
+            bail!("there's no ballot box")
+        }
+    }
+
+    // A supervisor that hasn't launched anything.
+    fn idle_supervisor(sched: &Scheduler<SimulatedBackend>) -> Supervisor {
+        Supervisor::new(
+            &sched.roster,
+            &RestartArgs {
+                competitor_max_restarts: 0,
+                competitor_backoff_ms: 0,
+            },
+        )
+    }
+
+    fn no_votes() -> VotePoller {
+        VotePoller::spawn(
+            Box::new(NoBallotBox),
+            Duration::from_secs(1),
+            Duration::from_secs(1),
+        )
+    }
+
+    fn scheduler(policy: SchedulingPolicy) -> Scheduler<SimulatedBackend> {
+        let roster = Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+
+            [[candidates]]
+            id = "summer2"
+            name = "Summer 2"
+            "#,
+        )
+        .unwrap();
+
+        let mut policy_args = PolicyArgs::parse_from(["democracy-scheduler"]);
+        policy_args.policy = policy;
+
+        let mut sched = Scheduler::new(SimulatedBackend::new(2), roster, &policy_args);
+        sched.groups = Groups::new(None);
+
+        for (id, pid) in [("summer1", 100), ("summer2", 200)] {
+            sched.add_candidate(id, pid);
+        }
+
+        sched
+    }
+
+    fn results(winner: Option<&str>, tied: &[&str], votes: [u64; 2]) -> WinnerResponse {
+        WinnerResponse {
+            method: CountingMethod::Plurality,
+            winner: winner.map(String::from),
+            tied: tied.iter().map(|id| id.to_string()).collect(),
+            scores: vec![
+                Tally("summer1".into(), votes[0]),
+                Tally("summer2".into(), votes[1]),
+            ],
+            ballots: votes.iter().sum(),
+            explanation: String::new(),
+        }
+    }
+
+    fn queue_everyone(sched: &mut Scheduler<SimulatedBackend>) {
+        for pid in [100, 200] {
+            sched.backend.enqueue(QueuedTask::new(pid, 0, 0, 0, 100));
+        }
+        sched.drain_queue();
+    }
+
+    #[test]
+    fn winner_takes_all_only_runs_the_winner() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        queue_everyone(&mut sched);
+
+        sched.dispatch(&results(Some("summer2"), &[], [1, 2]));
+
+        let dispatched = sched.backend.take_dispatched();
+        assert_eq!(dispatched.len(), 1);
+        assert_eq!(dispatched[0].pid(), 200);
+        assert_eq!(dispatched[0].slice_ns(), sched.winner_slice_ns);
+        assert_eq!(sched.backend.nr_queued_scheduled(), (0, 0));
+    }
+
+    #[test]
+    fn ties_go_through_the_tie_policy() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        queue_everyone(&mut sched);
+
+        let tie = results(None, &["summer1", "summer2"], [1, 1]);
+        sched.dispatch(&tie);
+        sched.dispatch(&tie);
+
+        let pids: Vec<i32> = sched
+            .backend
+            .take_dispatched()
+            .iter()
+            .map(|task| task.pid())
+            .collect();
+        assert_eq!(pids, vec![100, 200]);
+    }
 
-fn get_current_winner() -> Result<Competitors> {
-    let url = "http://localhost:8080/api/votes";
+    #[test]
+    fn proportional_runs_everyone_by_vote_share() {
+        let mut sched = scheduler(SchedulingPolicy::Proportional);
+        queue_everyone(&mut sched);
 
-    let winner = reqwest::blocking::Client::new()
-        .get(url)
-        .header("User-Agent", "scheduler")
-        .send()?;
+        sched.dispatch(&results(Some("summer2"), &[], [1, 3]));
 
-    let tallys: CurrentWinnerResponse = winner.json()?;
+        let slices: Vec<(i32, u64)> = sched
+            .backend
+            .take_dispatched()
+            .iter()
+            .map(|task| (task.pid(), task.slice_ns()))
+            .collect();
+        let period = sched.interval.as_nanos() as u64;
+        assert_eq!(slices, vec![(100, period / 4), (200, period * 3 / 4)]);
+    }
+
+    #[test]
+    fn proportional_shares_a_candidates_slice_between_its_threads() {
+        // 201 is a second thread of summer2's program.
+        let proc_root = tempfile::tempdir().unwrap();
+        let thread = proc_root.path().join("201");
+        std::fs::create_dir(&thread).unwrap();
+        std::fs::write(thread.join("status"), "Tgid:\t200\nPPid:\t1\n").unwrap();
+
+        let mut sched = scheduler(SchedulingPolicy::Proportional);
+        sched.groups = Groups::new(Some(proc_root.path().into()));
+        for (id, pid) in [("summer1", 100), ("summer2", 200)] {
+            sched.add_candidate(id, pid);
+        }
+        queue_everyone(&mut sched);
+        sched.backend.enqueue(QueuedTask::new(201, 0, 0, 0, 100));
+        sched.drain_queue();
+
+        sched.dispatch(&results(Some("summer2"), &[], [1, 3]));
+
+        let slices: Vec<(i32, u64)> = sched
+            .backend
+            .take_dispatched()
+            .iter()
+            .map(|task| (task.pid(), task.slice_ns()))
+            .collect();
+        let period = sched.interval.as_nanos() as u64;
+        assert_eq!(
+            slices,
+            vec![
+                (100, period / 4),
+                (200, period * 3 / 8),
+                (201, period * 3 / 8)
+            ]
+        );
+    }
+
+    // Serves `/api/winner` like the ballot box, but takes `delay` to answer every request.
+    fn slow_ballot_box(delay: Duration, results: WinnerResponse) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let body = serde_json::to_string(&results).unwrap();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let body = body.clone();
+
+                thread::spawn(move || {
+                    let mut request = Vec::new();
+                    let mut buf = [0; 1024];
+                    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                        match stream.read(&mut buf) {
+                            Ok(0) | Err(_) => return,
+                            Ok(n) => request.extend_from_slice(&buf[..n]),
+                        }
+                    }
+
+                    thread::sleep(delay);
+
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                });
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    fn slow_ballot_box_doesnt_hold_up_dispatch() {
+        let delay = Duration::from_secs(1);
+        let url = slow_ballot_box(delay, results(Some("summer2"), &[], [1, 2]));
+        let source = HttpVoteSource::new(&url, Duration::from_secs(10), &[]).unwrap();
+        let poller = VotePoller::spawn(
+            Box::new(source),
+            Duration::from_millis(10),
+            Duration::from_secs(10),
+        );
+
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+
+        // While the first request is still outstanding the candidates take turns, and every decision is immediate.
+        for expected in [100, 200, 100] {
+            queue_everyone(&mut sched);
+            let started = Instant::now();
+            sched.tick(&poller);
+            assert!(started.elapsed() < Duration::from_millis(100));
+
+            let dispatched = sched.backend.take_dispatched();
+            assert_eq!(dispatched.len(), 1);
+            assert_eq!(dispatched[0].pid(), expected);
+        }
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while poller.latest().is_none() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(poller.latest().unwrap().latency >= delay);
+
+        // Later polls are just as slow, but the scheduler carries on with the results it has.
+        for _ in 0..3 {
+            queue_everyone(&mut sched);
+            let started = Instant::now();
+            sched.tick(&poller);
+            assert!(started.elapsed() < Duration::from_millis(100));
+            assert_eq!(sched.backend.take_dispatched()[0].pid(), 200);
+        }
+    }
+
+    #[test]
+    fn every_knob_is_on_the_command_line() {
+        let args = Args::parse_from([
+            "democracy-scheduler",
+            "--slice-us",
+            "20000",
+            "--nr-cpus",
+            "4",
+            "--partial",
+            "false",
+            "--debug",
+            "false",
+            "--fifo-sched",
+            "true",
+            "--winner-slice-us",
+            "5000",
+            "--schedule-interval-ms",
+            "100",
+            "--dry-run",
+        ]);
+
+        assert_eq!(args.bpf.slice_us, 20000);
+        assert_eq!(nr_cpus(&args.bpf), 4);
+        assert!(!args.bpf.partial && !args.bpf.debug && args.bpf.fifo_sched);
+        assert!(args.bpf.full_user);
+        assert!(args.dry_run);
+
+        let roster = Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+            "#,
+        )
+        .unwrap();
+        let sched = Scheduler::new(SimulatedBackend::new(1), roster, &args.policy);
+        assert_eq!(sched.winner_slice_ns, 5_000_000);
+        assert_eq!(sched.interval, Duration::from_millis(100));
+    }
+
+    #[test]
+    fn validate_catches_missing_binaries() {
+        let roster = |command: &str| {
+            Roster::parse(&format!(
+                r#"
+                [[candidates]]
+                id = "summer1"
+                name = "Summer 1"
+                command = ["{command}"]
+                "#
+            ))
+            .unwrap()
+        };
+        let args = Args::parse_from(["democracy-scheduler"]);
+
+        assert!(validate(&args, &roster("sh")).is_ok());
+        assert!(validate(&args, &roster("/no/such/thingdoer")).is_err());
+        assert!(validate(&args, &roster("no-such-thingdoer")).is_err());
+
+        let args = Args::parse_from(["democracy-scheduler", "--vote-source", "file"]);
+        assert!(validate(&args, &roster("sh")).is_err());
+
+        let args = Args::parse_from([
+            "democracy-scheduler",
+            "--control-socket",
+            "/no/such/dir/democracy-scheduler.sock",
+        ]);
+        assert!(validate(&args, &roster("sh")).is_err());
+
+        // Far more CPUs than any machine this runs on could have.
+        let args = Args::parse_from(["democracy-scheduler", "--nr-cpus", "100000"]);
+        assert!(validate(&args, &roster("sh")).is_err());
+    }
+
+    #[test]
+    fn validate_checks_what_to_attach_to() {
+        let roster = |target: &str| {
+            Roster::parse(&format!(
+                r#"
+                [[candidates]]
+                id = "summer1"
+                name = "Summer 1"
+                {target}
+                "#
+            ))
+            .unwrap()
+        };
+        let args = Args::parse_from(["democracy-scheduler"]);
+
+        assert!(validate(&args, &roster(&format!("pid = {}", std::process::id()))).is_ok());
+        assert!(validate(&args, &roster("pid = 999999999")).is_err());
+        assert!(validate(&args, &roster(r#"comm = "^thingdoer$""#)).is_ok());
+        assert!(validate(&args, &roster(r#"comm = "(unclosed""#)).is_err());
+        assert!(validate(&args, &roster("")).is_err());
+    }
+
+    #[test]
+    fn run_stops_when_the_bpf_side_exits() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        let mut supervisor = idle_supervisor(&sched);
+        sched.backend.exit();
+
+        assert_eq!(
+            sched.run(&no_votes(), &mut supervisor, &AtomicBool::new(false)),
+            Stopped::BpfExited
+        );
+    }
+
+    #[test]
+    fn run_stops_when_asked() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        let mut supervisor = idle_supervisor(&sched);
+
+        assert_eq!(
+            sched.run(&no_votes(), &mut supervisor, &AtomicBool::new(true)),
+            Stopped::Requested
+        );
+    }
+
+    #[test]
+    fn restarted_competitors_are_followed_to_their_new_pid() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        queue_everyone(&mut sched);
+
+        sched.apply(PidChange::Exited {
+            id: "summer2".into(),
+            pid: 200,
+        });
+        sched.dispatch(&results(Some("summer2"), &[], [1, 2]));
+        assert!(sched.backend.take_dispatched().is_empty());
+        assert!(!sched.task_map.contains_key(&200));
+
+        sched.apply(PidChange::Started {
+            id: "summer2".into(),
+            pid: 201,
+        });
+        sched.backend.enqueue(QueuedTask::new(201, 0, 0, 0, 100));
+        sched.drain_queue();
+        sched.dispatch(&results(Some("summer2"), &[], [1, 2]));
+
+        let dispatched = sched.backend.take_dispatched();
+        assert_eq!(dispatched.len(), 1);
+        assert_eq!(dispatched[0].pid(), 201);
+    }
 
-    let mut winner = Vote(String::from(""), 0);
+    #[test]
+    fn every_thread_of_a_candidate_is_dispatched() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        sched.groups = Groups::new(Some(group::PROC_ROOT.into()));
 
-    for tally in tallys.votes {
-        if tally.1 > winner.1 {
-            winner = tally
+        // This test's own process stands in for the candidate's program, and a thread of ours for one of the
+        // program's threads.
+        let pid = std::process::id();
+        let (tid_sender, tid) = std::sync::mpsc::channel();
+        let (_finished, finish) = std::sync::mpsc::channel::<()>();
+        thread::spawn(move || {
+            tid_sender
+                .send(nix::unistd::gettid().as_raw() as u32)
+                .unwrap();
+            let _ = finish.recv();
+        });
+        let tid = tid.recv().unwrap();
+        sched.add_candidate("summer1", pid);
+
+        for pid in [pid, tid] {
+            sched
+                .backend
+                .enqueue(QueuedTask::new(pid as i32, 0, 0, 0, 100));
+        }
+        sched.drain_queue();
+        sched.dispatch(&results(Some("summer1"), &[], [2, 1]));
+
+        let mut pids: Vec<u32> = sched
+            .backend
+            .take_dispatched()
+            .iter()
+            .map(|task| task.pid() as u32)
+            .collect();
+        pids.sort();
+        assert_eq!(pids, vec![pid.min(tid), pid.max(tid)]);
+
+        // Once a thread exits it's no longer dispatched, even though the rest of the program is queued again.
+        sched
+            .backend
+            .enqueue(QueuedTask::new(pid as i32, 0, 0, 0, 100));
+        sched
+            .backend
+            .enqueue(QueuedTask::new(tid as i32, -1, 0, 0, 100));
+        sched.drain_queue();
+        sched.dispatch(&results(Some("summer1"), &[], [2, 1]));
+        assert_eq!(sched.backend.take_dispatched().len(), 1);
+    }
+
+    #[test]
+    fn attached_candidates_are_bound_from_the_start() {
+        let roster = Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+            pid = 100
+
+            [[candidates]]
+            id = "summer2"
+            name = "Summer 2"
+            command = ["thingdoer", "summer2"]
+            "#,
+        )
+        .unwrap();
+        let policy = PolicyArgs::parse_from(["democracy-scheduler"]);
+        let mut sched = Scheduler::new(SimulatedBackend::new(1), roster, &policy);
+        sched.groups = Groups::new(None);
+        sched.groups.bind(
+            "summer1",
+            Membership::of(&sched.roster.candidates[0])
+                .unwrap()
+                .unwrap(),
+        );
+
+        queue_everyone(&mut sched);
+        sched.dispatch(&results(Some("summer1"), &[], [1, 0]));
+        assert_eq!(sched.backend.take_dispatched()[0].pid(), 100);
+
+        // Nothing of summer2's is recognised until its program has been launched.
+        sched.dispatch(&results(Some("summer2"), &[], [0, 1]));
+        assert!(sched.backend.take_dispatched().is_empty());
+    }
+
+    #[test]
+    fn everything_else_runs_after_the_candidates() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        queue_everyone(&mut sched);
+        for pid in [300, 400] {
+            sched.backend.enqueue(QueuedTask::new(pid, 0, 0, 0, 100));
         }
+        sched.drain_queue();
+
+        sched.dispatch(&results(Some("summer2"), &[], [1, 2]));
+        let dispatched: Vec<(i32, u64)> = sched
+            .backend
+            .take_dispatched()
+            .iter()
+            .map(|task| (task.pid(), task.slice_ns()))
+            .collect();
+        let fallback_slice_ns = sched.fallback.slice_ns();
+        assert_eq!(
+            dispatched,
+            vec![
+                (200, sched.winner_slice_ns),
+                (300, fallback_slice_ns),
+                (400, fallback_slice_ns)
+            ]
+        );
+
+        // 300 used its slice, so 400 goes first next time. None of them, candidate or not, is dispatched again until
+        // it's queued again; 200 isn't, this time.
+        sched
+            .backend
+            .enqueue(QueuedTask::new(300, 0, fallback_slice_ns, 0, 100));
+        sched.backend.enqueue(QueuedTask::new(400, 0, 0, 0, 100));
+        sched.drain_queue();
+        sched.dispatch(&results(Some("summer2"), &[], [1, 2]));
+        sched.dispatch(&results(Some("summer2"), &[], [1, 2]));
+
+        let pids: Vec<i32> = sched
+            .backend
+            .take_dispatched()
+            .iter()
+            .map(|task| task.pid())
+            .collect();
+        assert_eq!(pids, vec![400, 300]);
     }
 
-    Competitors::from_str(&winner.0)
+    #[test]
+    fn cpu_time_is_counted_per_candidate() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+
+        for (pid, cpu, sum_exec_runtime) in [
+            (100, 0, 1_000_000),
+            (200, 0, 0),
+            (100, 0, 3_000_000),
+            (200, 0, 500_000),
+            // 100 exits having run another millisecond.
+            (100, -1, 4_000_000),
+        ] {
+            sched
+                .backend
+                .enqueue(QueuedTask::new(pid, cpu, sum_exec_runtime, 0, 100));
+        }
+        sched.drain_queue();
+
+        assert_eq!(
+            sched.cpu_time(),
+            vec![
+                ("summer1".to_string(), Duration::from_millis(3)),
+                ("summer2".to_string(), Duration::from_micros(500)),
+            ]
+        );
+        assert_eq!(sched.task_map[&200].vruntime, 500_000);
+        assert!(!sched.task_map.contains_key(&100));
+    }
+
+    #[test]
+    fn race_is_won_on_cpu_time() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        assert_eq!(sched.check_race(), None);
+
+        sched.race = Race::new(&RaceArgs {
+            race_target_ms: Some(2),
+            race_finish: race::RaceFinish::Freeze,
+        });
+
+        for (pid, sum_exec_runtime) in [(100, 0), (200, 0), (100, 1_000_000), (200, 2_500_000)] {
+            sched
+                .backend
+                .enqueue(QueuedTask::new(pid, 0, sum_exec_runtime, 0, 100));
+        }
+        sched.tick(&no_votes());
+
+        let result = sched.check_race().unwrap();
+        assert_eq!(result.winner, "summer2");
+        assert_eq!(
+            result.cpu_ms,
+            vec![Tally("summer1".into(), 1), Tally("summer2".into(), 2)]
+        );
+        assert_eq!(sched.check_race(), None);
+    }
+
+    #[test]
+    fn dispatches_are_counted_for_metrics() {
+        // With no votes in, everyone gets an equal share.
+        let mut sched = scheduler(SchedulingPolicy::Proportional);
+        queue_everyone(&mut sched);
+        sched.tick(&no_votes());
+
+        let rendered = sched.metrics.render();
+        for line in [
+            r#"democracy_candidate_dispatches_total{candidate="summer1"} 1"#,
+            r#"democracy_candidate_dispatches_total{candidate="summer2"} 1"#,
+            "democracy_bpf_nr_user_dispatches 2",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn status_shows_who_each_cpu_is_running() {
+        let mut sched = scheduler(SchedulingPolicy::Proportional);
+        sched.backend.enqueue(QueuedTask::new(100, 0, 0, 0, 100));
+        sched.tick(&no_votes());
+
+        // The simulated backend puts pid 100 on the first CPU, and has no CPU 2 to run anything on.
+        let status = sched.status(&results(Some("summer2"), &[], [1, 3]), 3);
+        assert_eq!(
+            status.cpus,
+            vec![
+                CpuOwner::Candidate("summer1".into()),
+                CpuOwner::Idle,
+                CpuOwner::Idle,
+            ]
+        );
+        assert_eq!(status.winner.as_deref(), Some("summer2"));
+        assert_eq!(
+            status
+                .candidates
+                .iter()
+                .map(|c| (c.id.as_str(), c.score, c.dispatches))
+                .collect::<Vec<_>>(),
+            vec![("summer1", 1, 1), ("summer2", 3, 0)]
+        );
+    }
+
+    #[test]
+    fn candidates_without_a_queued_task_are_skipped() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+
+        sched.dispatch(&results(Some("summer1"), &[], [1, 0]));
+
+        assert!(sched.backend.take_dispatched().is_empty());
+    }
+
+    #[test]
+    fn settings_can_be_changed_over_the_control_socket() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        let response = results(Some("summer2"), &[], [1, 3]);
+
+        let change = Request::Set(PolicyChange {
+            tie_policy: Some("keep-previous".into()),
+            winner_slice_us: Some(20_000),
+            ..Default::default()
+        });
+        assert_eq!(
+            sched.control_response(&change, &response),
+            Response::Policy(proto_control::Policy {
+                policy: "winner-takes-all".into(),
+                tie_policy: "keep-previous".into(),
+                schedule_interval_ms: 500,
+                winner_slice_us: 20_000,
+                fallback_slice_us: 5_000,
+            })
+        );
+
+        // Nothing changes unless everything asked for is valid.
+        let bad = Request::Set(PolicyChange {
+            fallback_slice_us: Some(1_000),
+            tie_policy: Some("coin-toss".into()),
+            ..Default::default()
+        });
+        assert!(matches!(
+            sched.control_response(&bad, &response),
+            Response::Error { error } if error.contains("keep-previous")
+        ));
+        assert_eq!(sched.fallback.slice_ns(), 5_000_000);
+
+        queue_everyone(&mut sched);
+        sched.dispatch(&response);
+        let dispatched = sched.backend.take_dispatched();
+        assert_eq!(dispatched.len(), 1);
+        assert_eq!(dispatched[0].slice_ns(), 20_000_000);
+
+        // summer2's program is being restarted by the supervisor.
+        sched.programs = vec![ProgramState::Running, ProgramState::Restarting];
+        let Response::Candidates { candidates } =
+            sched.control_response(&Request::Candidates, &response)
+        else {
+            panic!("expected the candidates");
+        };
+        assert_eq!(
+            candidates
+                .iter()
+                .map(|c| (c.id.as_str(), c.score, c.dispatches, c.program))
+                .collect::<Vec<_>>(),
+            vec![
+                ("summer1", 1, 0, ProgramState::Running),
+                ("summer2", 3, 1, ProgramState::Restarting)
+            ]
+        );
+    }
+
+    #[test]
+    fn policy_can_be_swapped_while_running() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        let response = results(Some("summer2"), &[], [1, 3]);
+        let swap = |policy: &str| {
+            Request::Set(PolicyChange {
+                policy: Some(policy.into()),
+                ..Default::default()
+            })
+        };
+        let decide = |sched: &mut Scheduler<SimulatedBackend>| {
+            queue_everyone(sched);
+            sched.dispatch(&response);
+            sched
+                .backend
+                .take_dispatched()
+                .iter()
+                .map(|task| task.pid())
+                .collect::<Vec<_>>()
+        };
+
+        // Turns are taken whatever the vote says.
+        sched.control_response(&swap("round-robin"), &response);
+        assert_eq!(sched.policy, SchedulingPolicy::RoundRobin);
+        assert_eq!(
+            (0..3).map(|_| decide(&mut sched)).collect::<Vec<_>>(),
+            vec![vec![100], vec![200], vec![100]]
+        );
+
+        // There's nothing to race for unless the race is on.
+        assert!(matches!(
+            sched.control_response(&swap("race"), &response),
+            Response::Error { .. }
+        ));
+        sched.race = Race::new(&RaceArgs {
+            race_target_ms: Some(1_000),
+            race_finish: race::RaceFinish::Freeze,
+        });
+        sched.control_response(&swap("race"), &response);
+        assert_eq!(decide(&mut sched), vec![100, 200]);
+
+        sched.control_response(&swap("winner-takes-all"), &response);
+        assert_eq!(decide(&mut sched), vec![200]);
+    }
+
+    #[test]
+    fn config_is_reloaded_unless_its_broken() {
+        let mut sched = scheduler(SchedulingPolicy::WinnerTakesAll);
+        let config = tempfile::NamedTempFile::new().unwrap();
+        sched.config = Some(config.path().to_path_buf());
+
+        std::fs::write(
+            config.path(),
+            "policy = \"proportional\"\nschedule_interval_ms = 100\n",
+        )
+        .unwrap();
+        sched.reload_config();
+        assert_eq!(sched.policy, SchedulingPolicy::Proportional);
+        assert_eq!(sched.interval, Duration::from_millis(100));
+
+        std::fs::write(
+            config.path(),
+            "policy = \"round-robin\"\nwinner_slice_us = 0\n",
+        )
+        .unwrap();
+        sched.reload_config();
+        assert_eq!(sched.policy, SchedulingPolicy::Proportional);
+    }
 }
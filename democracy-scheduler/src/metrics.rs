@@ -0,0 +1,340 @@
+//! Prometheus metrics for what the scheduler is doing, served from `/metrics` when `--metrics-address` is given.
+//!
+//! The scheduling loop updates them as it goes and a thread of their own serves them, so a scrape never touches the
+//! scheduler itself. What the scheduler counts for itself is exported as counters; numbers it reads from elsewhere (the
+//! BPF side's counters, the candidates' CPU time) are exported as gauges, since they start over whenever their source
+//! does.
+
+use crate::backend::BpfStats;
+use anyhow::{Context, Result};
+use democracy_proto::{roster::Roster, WinnerResponse};
+use prometheus::core::Collector;
+use prometheus::{
+    Encoder, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+    TextEncoder,
+};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Where the metrics are served from.
+pub const PATH: &str = "/metrics";
+
+// How long a client gets to send its request before we give up on it.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,                  // everything below, for rendering
+    bpf: Vec<IntGauge>,                  // the BPF side's counters, in `BpfStats::counters` order
+    candidate_dispatches: IntCounterVec, // tasks dispatched for each candidate
+    fallback_dispatches: IntCounter,     // non-candidate tasks dispatched
+    cpu_seconds: GaugeVec,               // CPU time each candidate has had
+    winner: IntGaugeVec,                 // 1 for whoever's winning the vote, 0 otherwise
+    vote_poll_latency: Gauge,            // how long the vote source last took to answer
+    votes_stale: IntGauge,               // 1 while the results are out of date
+}
+
+impl Metrics {
+    pub fn new(roster: &Roster) -> Self {
+        let registry =
+            Registry::new_custom(Some("democracy".into()), None).expect("Invalid metrics prefix");
+
+        let bpf = BpfStats::default()
+            .counters()
+            .iter()
+            .map(|(name, help, _)| {
+                register(
+                    &registry,
+                    IntGauge::new(format!("bpf_{}", name), *help).expect("Invalid metric"),
+                )
+            })
+            .collect();
+
+        let metrics = Self {
+            bpf,
+            candidate_dispatches: register(
+                &registry,
+                IntCounterVec::new(
+                    Opts::new(
+                        "candidate_dispatches_total",
+                        "Tasks dispatched for each candidate",
+                    ),
+                    &["candidate"],
+                )
+                .expect("Invalid metric"),
+            ),
+            fallback_dispatches: register(
+                &registry,
+                IntCounter::new(
+                    "fallback_dispatches_total",
+                    "Tasks dispatched that don't belong to any candidate",
+                )
+                .expect("Invalid metric"),
+            ),
+            cpu_seconds: register(
+                &registry,
+                GaugeVec::new(
+                    Opts::new(
+                        "candidate_cpu_seconds",
+                        "CPU time each candidate has had since the scheduler started",
+                    ),
+                    &["candidate"],
+                )
+                .expect("Invalid metric"),
+            ),
+            winner: register(
+                &registry,
+                IntGaugeVec::new(
+                    Opts::new(
+                        "winner",
+                        "1 for the candidate currently winning the vote, 0 for everyone else",
+                    ),
+                    &["candidate"],
+                )
+                .expect("Invalid metric"),
+            ),
+            vote_poll_latency: register(
+                &registry,
+                Gauge::new(
+                    "vote_poll_latency_seconds",
+                    "How long the vote source took to answer the last time it was asked",
+                )
+                .expect("Invalid metric"),
+            ),
+            votes_stale: register(
+                &registry,
+                IntGauge::new(
+                    "votes_stale",
+                    "1 while the election results are out of date",
+                )
+                .expect("Invalid metric"),
+            ),
+            registry,
+        };
+
+        // Every candidate shows up from the start, rather than once something first happens to them.
+        for candidate in &roster.candidates {
+            let labels = [candidate.id.as_str()];
+            metrics.candidate_dispatches.with_label_values(&labels);
+            metrics.cpu_seconds.with_label_values(&labels);
+            metrics.winner.with_label_values(&labels);
+        }
+
+        metrics
+    }
+
+    pub fn candidate_dispatched(&self, id: &str) {
+        self.candidate_dispatches.with_label_values(&[id]).inc();
+    }
+
+    /// Tasks dispatched for a candidate so far.
+    pub fn dispatches(&self, id: &str) -> u64 {
+        self.candidate_dispatches.with_label_values(&[id]).get()
+    }
+
+    pub fn fallback_dispatched(&self) {
+        self.fallback_dispatches.inc();
+    }
+
+    /// Non-candidate tasks dispatched so far.
+    pub fn fallback_dispatches(&self) -> u64 {
+        self.fallback_dispatches.get()
+    }
+
+    /// Records how long the vote source took to answer, and whether its results are out of date.
+    pub fn vote_poll(&self, latency: Duration, stale: bool) {
+        self.vote_poll_latency.set(latency.as_secs_f64());
+        self.votes_stale.set(stale as i64);
+    }
+
+    /// Records the BPF side's counters, each candidate's CPU time (in roster order) and who's winning the vote.
+    pub fn observe(
+        &self,
+        stats: &BpfStats,
+        cpu_time: &[(String, Duration)],
+        results: &WinnerResponse,
+    ) {
+        for (gauge, (_, _, value)) in self.bpf.iter().zip(stats.counters()) {
+            gauge.set(value as i64);
+        }
+
+        for (id, cpu_time) in cpu_time {
+            let winning = results
+                .winner
+                .as_deref()
+                .is_some_and(|winner| winner.eq_ignore_ascii_case(id));
+
+            self.cpu_seconds
+                .with_label_values(&[id])
+                .set(cpu_time.as_secs_f64());
+            self.winner.with_label_values(&[id]).set(winning as i64);
+        }
+    }
+
+    /// Everything, in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Could not encode metrics");
+
+        String::from_utf8(buffer).expect("Metrics aren't UTF-8")
+    }
+
+    /// Serves the metrics over HTTP from a background thread, and returns the address they're served on.
+    pub fn serve(&self, address: SocketAddr) -> Result<SocketAddr> {
+        let listener = TcpListener::bind(address)
+            .with_context(|| format!("Could not listen for metrics requests on {}", address))?;
+        let address = listener.local_addr()?;
+
+        let metrics = self.clone();
+        std::thread::Builder::new()
+            .name("metrics".into())
+            .spawn(move || {
+                // One at a time is plenty for the odd scrape.
+                for stream in listener.incoming().flatten() {
+                    if let Err(e) = respond(stream, &metrics) {
+                        debug!(err = %e, "Could not answer metrics request");
+                    }
+                }
+            })
+            .context("Could not start metrics server")?;
+
+        info!(addr = %address, path = PATH, "serving metrics");
+
+        Ok(address)
+    }
+}
+
+fn register<M: Collector + Clone + 'static>(registry: &Registry, metric: M) -> M {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("Metric registered twice");
+    metric
+}
+
+// Answers a single HTTP request.
+fn respond(mut stream: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+
+    let mut reader = BufReader::new(&stream);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+
+    // Nothing in the headers matters, but they have to be read before answering.
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim().is_empty() {
+        header.clear();
+    }
+
+    let encoder = TextEncoder::new();
+    let (status, content_type, body) = match request_line.split_whitespace().nth(1) {
+        Some(PATH) => ("200 OK", encoder.format_type(), metrics.render()),
+        _ => ("404 Not Found", "text/plain", "Not Found\n".to_string()),
+    };
+
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use democracy_proto::CountingMethod;
+
+    fn roster() -> Roster {
+        Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+
+            [[candidates]]
+            id = "summer2"
+            name = "Summer 2"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn results(winner: &str) -> WinnerResponse {
+        WinnerResponse {
+            method: CountingMethod::Plurality,
+            winner: Some(winner.into()),
+            tied: vec![],
+            scores: vec![],
+            ballots: 1,
+            explanation: "".into(),
+        }
+    }
+
+    #[test]
+    fn everyone_is_listed_from_the_start() {
+        let rendered = Metrics::new(&roster()).render();
+
+        for line in [
+            r#"democracy_candidate_dispatches_total{candidate="summer1"} 0"#,
+            r#"democracy_winner{candidate="summer2"} 0"#,
+            "democracy_bpf_nr_failed_dispatches 0",
+            "democracy_fallback_dispatches_total 0",
+        ] {
+            assert!(rendered.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+
+    #[test]
+    fn served_over_http() {
+        let metrics = Metrics::new(&roster());
+        metrics.candidate_dispatched("summer1");
+        metrics.candidate_dispatched("summer1");
+        metrics.fallback_dispatched();
+        metrics.vote_poll(Duration::from_millis(250), true);
+        metrics.observe(
+            &BpfStats {
+                nr_user_dispatches: 3,
+                nr_kernel_dispatches: 7,
+                ..Default::default()
+            },
+            &[
+                ("summer1".into(), Duration::from_millis(1_500)),
+                ("summer2".into(), Duration::ZERO),
+            ],
+            &results("SUMMER2"),
+        );
+
+        let address = metrics.serve("127.0.0.1:0".parse().unwrap()).unwrap();
+        let body = reqwest::blocking::get(format!("http://{}{}", address, PATH))
+            .unwrap()
+            .error_for_status()
+            .unwrap()
+            .text()
+            .unwrap();
+
+        for line in [
+            r#"democracy_candidate_dispatches_total{candidate="summer1"} 2"#,
+            r#"democracy_candidate_cpu_seconds{candidate="summer1"} 1.5"#,
+            r#"democracy_winner{candidate="summer1"} 0"#,
+            r#"democracy_winner{candidate="summer2"} 1"#,
+            "democracy_fallback_dispatches_total 1",
+            "democracy_bpf_nr_user_dispatches 3",
+            "democracy_bpf_nr_kernel_dispatches 7",
+            "democracy_vote_poll_latency_seconds 0.25",
+            "democracy_votes_stale 1",
+        ] {
+            assert!(body.lines().any(|l| l == line), "missing {}", line);
+        }
+
+        let status = reqwest::blocking::get(format!("http://{}/", address))
+            .unwrap()
+            .status();
+        assert_eq!(status, reqwest::StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,184 @@
+//! The ways the scheduler can turn an election into CPU time.
+//!
+//! The policy can be changed while the scheduler runs, over the control socket or by editing the `--config` file and
+//! sending SIGHUP; the BPF side stays attached throughout, so the candidates never drop back to the normal scheduler.
+
+use crate::control;
+use crate::tiebreak::TiePolicy;
+use anyhow::{anyhow, bail, Context, Result};
+use democracy_proto::control::PolicyChange;
+use std::path::Path;
+use std::time::Duration;
+
+/// The longest the scheduler can go between decisions, in milliseconds. Control requests are answered between
+/// decisions and the control socket gives up waiting for an answer after 5 seconds, so this stays well short of that.
+pub const MAX_SCHEDULE_INTERVAL_MS: u64 = 2_000;
+
+/// The longest slice anything can be dispatched with, in microseconds.
+pub const MAX_SLICE_US: u64 = 10_000_000;
+
+/// How the scheduler hands out CPU time to the candidates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SchedulingPolicy {
+    /// Only the winner of the election runs; ties are settled by the tie policy.
+    WinnerTakesAll,
+
+    /// Every candidate runs, with time slices sized by their share of the vote. See [`crate::proportional`].
+    Proportional,
+
+    /// The candidates take turns, one per decision in roster order, whatever the vote says.
+    RoundRobin,
+
+    /// Every candidate runs at once whatever the vote says, so the CPU-time race is down to the programs themselves.
+    /// Needs `--race-target-ms`.
+    Race,
+}
+
+/// Everything that decides how votes become CPU time, shared by the real scheduler and `simulate`.
+#[derive(Debug, Clone, clap::Parser)]
+pub struct PolicyArgs {
+    /// How CPU time is handed out to the candidates.
+    #[arg(long, env = "DEMOCRACY_POLICY", value_enum, default_value_t = SchedulingPolicy::WinnerTakesAll)]
+    pub policy: SchedulingPolicy,
+
+    /// Who gets to run when candidates are tied for first place (including before any votes have been cast).
+    #[arg(long, env = "DEMOCRACY_TIE_POLICY", value_enum, default_value_t = TiePolicy::RoundRobin)]
+    pub tie_policy: TiePolicy,
+
+    /// Seed for `--tie-policy random`, so that a run's tie-breaks can be reproduced.
+    #[arg(long, env = "DEMOCRACY_TIE_SEED", default_value_t = 0)]
+    pub tie_seed: u64,
+
+    /// How often the scheduler makes a decision, in milliseconds, up to 2000. Under `--policy proportional` this is
+    /// also the period CPU time is shared out over.
+    #[arg(long, env = "DEMOCRACY_SCHEDULE_INTERVAL_MS", default_value_t = 500)]
+    pub schedule_interval_ms: u64,
+
+    /// Slice the winner is dispatched with under `--policy winner-takes-all`, and every candidate that runs under
+    /// `--policy round-robin` and `--policy race`, in microseconds.
+    #[arg(long, env = "DEMOCRACY_WINNER_SLICE_US", default_value_t = 100_000)]
+    pub winner_slice_us: u64,
+
+    /// Slice SCHED_EXT tasks that aren't candidates are dispatched with, in microseconds. They share what's left of the
+    /// CPU fairly between themselves; see [`crate::fallback`].
+    #[arg(long, env = "DEMOCRACY_FALLBACK_SLICE_US", default_value_t = 5_000)]
+    pub fallback_slice_us: u64,
+}
+
+/// A [`PolicyChange`] that's been checked over, with the policies it names looked up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PolicyUpdate {
+    pub policy: Option<SchedulingPolicy>,
+    pub tie_policy: Option<TiePolicy>,
+    pub schedule_interval: Option<Duration>,
+    pub winner_slice_ns: Option<u64>,
+    pub fallback_slice_ns: Option<u64>,
+}
+
+impl PolicyUpdate {
+    pub fn parse(change: &PolicyChange) -> Result<Self> {
+        for (setting, value, max) in [
+            (
+                "schedule_interval_ms",
+                change.schedule_interval_ms,
+                MAX_SCHEDULE_INTERVAL_MS,
+            ),
+            ("winner_slice_us", change.winner_slice_us, MAX_SLICE_US),
+            ("fallback_slice_us", change.fallback_slice_us, MAX_SLICE_US),
+        ] {
+            match value {
+                Some(0) => bail!("{} must be at least 1", setting),
+                Some(value) if value > max => bail!("{} can be at most {}", setting, max),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            policy: match &change.policy {
+                Some(name) => Some(control::parse("policy", name)?),
+                None => None,
+            },
+            tie_policy: match &change.tie_policy {
+                Some(name) => Some(control::parse("tie policy", name)?),
+                None => None,
+            },
+            schedule_interval: change.schedule_interval_ms.map(Duration::from_millis),
+            winner_slice_ns: slice_ns("winner_slice_us", change.winner_slice_us)?,
+            fallback_slice_ns: slice_ns("fallback_slice_us", change.fallback_slice_us)?,
+        })
+    }
+}
+
+// A slice in nanoseconds, from the microseconds it's given in.
+fn slice_ns(setting: &str, us: Option<u64>) -> Result<Option<u64>> {
+    us.map(|us| {
+        us.checked_mul(1000)
+            .ok_or_else(|| anyhow!("{} is far too long", setting))
+    })
+    .transpose()
+}
+
+/// Reads the `--config` file: a TOML file of settings to change, with the same keys as a control socket `set`
+/// request, e.g. `policy = "proportional"`.
+pub fn load_config(path: &Path) -> Result<PolicyChange> {
+    let config = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read config file {}", path.display()))?;
+
+    toml::from_str(&config).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn config_uses_command_line_names() {
+        let mut config = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            config,
+            "policy = \"round-robin\"\ntie_policy = \"keep-previous\"\nwinner_slice_us = 20000\n"
+        )
+        .unwrap();
+
+        let update = PolicyUpdate::parse(&load_config(config.path()).unwrap()).unwrap();
+        assert_eq!(
+            update,
+            PolicyUpdate {
+                policy: Some(SchedulingPolicy::RoundRobin),
+                tie_policy: Some(TiePolicy::KeepPrevious),
+                winner_slice_ns: Some(20_000_000),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn mistakes_are_caught() {
+        for change in [
+            PolicyChange {
+                policy: Some("dictatorship".into()),
+                ..Default::default()
+            },
+            PolicyChange {
+                schedule_interval_ms: Some(0),
+                ..Default::default()
+            },
+            // The control socket would give up before the scheduler got round to answering.
+            PolicyChange {
+                schedule_interval_ms: Some(MAX_SCHEDULE_INTERVAL_MS + 1),
+                ..Default::default()
+            },
+            PolicyChange {
+                winner_slice_us: Some(u64::MAX),
+                ..Default::default()
+            },
+        ] {
+            assert!(PolicyUpdate::parse(&change).is_err());
+        }
+
+        let mut config = tempfile::NamedTempFile::new().unwrap();
+        writeln!(config, "polcy = \"race\"").unwrap();
+        assert!(load_config(config.path()).is_err());
+    }
+}
@@ -0,0 +1,161 @@
+//! Proportional representation: every candidate gets CPU time in proportion to their share of the vote.
+//!
+//! Handing out slices sized by vote share isn't enough on its own; a task might block before using its slice, or
+//! overrun it, and the error would pile up forever. Instead each candidate has a running balance of CPU time they're
+//! owed. Every period they're credited their share of the period and debited whatever they actually ran for (as
+//! measured by the kernel's `sum_exec_runtime`), and their next slice is whatever they're owed. A candidate that ran
+//! too long gets less next time and one that fell behind gets more, so over a window each candidate's CPU time stays
+//! within a period or so of their vote share.
+
+/// Hands out slice lengths so that CPU time tracks vote share.
+pub struct ProportionalShare {
+    period_ns: u64,
+
+    // CPU time each candidate is owed; negative once they've run more than their share.
+    owed_ns: Vec<i64>,
+
+    // Each candidate's sum_exec_runtime the last time we looked.
+    last_runtime_ns: Vec<Option<u64>>,
+}
+
+impl ProportionalShare {
+    /// A candidate that can't use its time (because it's blocked, say) stops building credit once it's owed this many
+    /// periods, so it can't come back and hog the CPU for ages.
+    const MAX_CREDIT_PERIODS: i64 = 2;
+
+    /// `period_ns` is how much CPU time is shared out each time [`Self::slices`] is called; it should match how often
+    /// the scheduler makes a decision.
+    pub fn new(period_ns: u64, candidates: usize) -> Self {
+        Self {
+            period_ns,
+            owed_ns: vec![0; candidates],
+            last_runtime_ns: vec![None; candidates],
+        }
+    }
+
+    /// Changes how much CPU time is shared out each period from the next period on. What each candidate is owed carries
+    /// over.
+    pub fn set_period_ns(&mut self, period_ns: u64) {
+        self.period_ns = period_ns;
+    }
+
+    /// Returns the slice length, in nanoseconds, each candidate should be dispatched with for the coming period.
+    ///
+    /// `votes` is each candidate's score in roster order and `runtime_ns` their total CPU time so far (`None` if we
+    /// haven't seen the candidate's task yet). If nobody has any votes everyone gets an equal share.
+    pub fn slices(&mut self, votes: &[u64], runtime_ns: &[Option<u64>]) -> Vec<u64> {
+        let total: u64 = votes.iter().sum();
+        let max_credit = self.period_ns as i64 * Self::MAX_CREDIT_PERIODS;
+
+        (0..self.owed_ns.len())
+            .map(|i| {
+                let entitled = match total {
+                    0 => self.period_ns / self.owed_ns.len() as u64,
+                    total => (self.period_ns as u128 * votes[i] as u128 / total as u128) as u64,
+                };
+
+                let used = match (self.last_runtime_ns[i], runtime_ns[i]) {
+                    (Some(last), Some(now)) => now.saturating_sub(last),
+                    _ => 0,
+                };
+                if runtime_ns[i].is_some() {
+                    self.last_runtime_ns[i] = runtime_ns[i];
+                }
+
+                self.owed_ns[i] = (self.owed_ns[i] - used as i64 + entitled as i64).min(max_credit);
+
+                self.owed_ns[i].max(0) as u64
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PERIOD_NS: u64 = 500_000_000;
+
+    // Runs the policy for a number of periods, with each task running for its whole slice scaled by `efficiency`
+    // (to model tasks that overrun or under use their slices), and returns the CPU time each task got.
+    fn simulate(votes: &[u64], efficiency: &[f64], periods: usize) -> Vec<u64> {
+        let mut policy = ProportionalShare::new(PERIOD_NS, votes.len());
+        let mut runtime = vec![0u64; votes.len()];
+
+        for _ in 0..periods {
+            let observed: Vec<Option<u64>> = runtime.iter().copied().map(Some).collect();
+            let slices = policy.slices(votes, &observed);
+
+            for i in 0..votes.len() {
+                runtime[i] += (slices[i] as f64 * efficiency[i]) as u64;
+            }
+        }
+
+        runtime
+    }
+
+    fn assert_tracks_vote_share(votes: &[u64], runtime: &[u64], tolerance: f64) {
+        let total_votes: u64 = votes.iter().sum();
+        let total_runtime: u64 = runtime.iter().sum();
+
+        for i in 0..votes.len() {
+            let expected = votes[i] as f64 / total_votes as f64;
+            let actual = runtime[i] as f64 / total_runtime as f64;
+            assert!(
+                (expected - actual).abs() <= tolerance,
+                "candidate {i} should have had {expected:.3} of the CPU but got {actual:.3}"
+            );
+        }
+    }
+
+    #[test]
+    fn slices_follow_vote_share() {
+        let mut policy = ProportionalShare::new(PERIOD_NS, 3);
+
+        assert_eq!(
+            policy.slices(&[1, 3, 0], &[None, None, None]),
+            vec![125_000_000, 375_000_000, 0]
+        );
+    }
+
+    #[test]
+    fn no_votes_shares_equally() {
+        let mut policy = ProportionalShare::new(PERIOD_NS, 2);
+
+        assert_eq!(
+            policy.slices(&[0, 0], &[None, None]),
+            vec![250_000_000, 250_000_000]
+        );
+    }
+
+    #[test]
+    fn cpu_time_tracks_vote_share() {
+        let votes = [5, 3, 2];
+        let runtime = simulate(&votes, &[1.0, 1.0, 1.0], 100);
+
+        assert_tracks_vote_share(&votes, &runtime, 0.01);
+    }
+
+    #[test]
+    fn overrunning_tasks_are_reined_in() {
+        // The first task always runs 50% past its slice, the last gives up half of every slice early; the balances
+        // should keep pulling them back to their share.
+        let votes = [1, 1, 2];
+        let runtime = simulate(&votes, &[1.5, 1.0, 0.5], 200);
+
+        assert_tracks_vote_share(&votes, &runtime, 0.05);
+    }
+
+    #[test]
+    fn blocked_candidates_dont_hoard_credit() {
+        let mut policy = ProportionalShare::new(PERIOD_NS, 2);
+
+        // The second candidate never runs.
+        for _ in 0..10 {
+            policy.slices(&[1, 1], &[Some(0), Some(0)]);
+        }
+
+        let slices = policy.slices(&[1, 1], &[Some(0), Some(0)]);
+        assert_eq!(slices[1], PERIOD_NS * 2);
+    }
+}
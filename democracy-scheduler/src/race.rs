@@ -0,0 +1,233 @@
+//! The CPU-time race: whichever candidate gets to run for the target amount of CPU time first wins.
+//!
+//! The vote still decides who runs; the race is a game played on top of it, with each candidate's CPU time as
+//! accounted for by [`crate::accounting`]. Once someone reaches the target the win is reported to the ballot box, and
+//! the race either freezes with that result or resets and starts a new round with everyone back at zero.
+
+use crate::votes;
+use anyhow::{Context, Result};
+use democracy_proto::{routes, RaceResult, Tally};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::time::{Duration, SystemTime};
+use tracing::{info, warn};
+
+#[derive(Debug, clap::Args)]
+pub struct RaceArgs {
+    /// Turns on the CPU-time race: the first candidate to have run for this long wins, in milliseconds.
+    #[arg(long, env = "DEMOCRACY_RACE_TARGET_MS")]
+    pub race_target_ms: Option<u64>,
+
+    /// What to do once the race has been won.
+    #[arg(long, env = "DEMOCRACY_RACE_FINISH", value_enum, default_value_t = RaceFinish::Freeze)]
+    pub race_finish: RaceFinish,
+}
+
+/// What happens to the race once someone has won.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum RaceFinish {
+    /// The first win stands and the race is over.
+    Freeze,
+
+    /// Start another round, with everyone's CPU time counted from zero again.
+    Reset,
+}
+
+pub struct Race {
+    target: Duration,                 // CPU time it takes to win
+    finish: RaceFinish,               // what to do once someone has won
+    run: u64,                         // tells this race's rounds apart from an earlier scheduler's
+    round: u64,                       // the round being run, counting from 1
+    start: HashMap<String, Duration>, // CPU time each candidate already had when the round started
+    finished: bool,                   // whether the race has been won and frozen
+}
+
+impl Race {
+    /// Returns `None` unless the race has been turned on.
+    pub fn new(args: &RaceArgs) -> Option<Self> {
+        Some(Self {
+            target: Duration::from_millis(args.race_target_ms?),
+            finish: args.race_finish,
+            // When the race started, in nanoseconds; no two runs of the scheduler will start in the same one.
+            run: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |since| since.as_nanos() as u64),
+            round: 1,
+            start: HashMap::new(),
+            finished: false,
+        })
+    }
+
+    /// Checks everyone's CPU time (in roster order) against the target, and returns the result if the round has just
+    /// been won. If several candidates got past the target since the last check, whoever got furthest wins, and ties
+    /// go to whoever's first in the roster.
+    pub fn check(&mut self, cpu_time: &[(String, Duration)]) -> Option<RaceResult> {
+        if self.finished {
+            return None;
+        }
+
+        let progress: Vec<(&str, Duration)> = cpu_time
+            .iter()
+            .map(|(id, cpu_time)| {
+                let start = self.start.get(id).copied().unwrap_or_default();
+                (id.as_str(), cpu_time.saturating_sub(start))
+            })
+            .collect();
+
+        let mut winner = None;
+        for &(id, ran) in &progress {
+            if ran >= self.target && !matches!(winner, Some((_, best)) if best >= ran) {
+                winner = Some((id, ran));
+            }
+        }
+        let (winner, _) = winner?;
+
+        let result = RaceResult {
+            run: self.run,
+            round: self.round,
+            winner: winner.to_string(),
+            target_ms: self.target.as_millis() as u64,
+            cpu_ms: progress
+                .iter()
+                .map(|(id, ran)| Tally(id.to_string(), ran.as_millis() as u64))
+                .collect(),
+        };
+
+        match self.finish {
+            RaceFinish::Freeze => self.finished = true,
+            RaceFinish::Reset => {
+                self.start = cpu_time.iter().cloned().collect();
+                self.round += 1;
+            }
+        }
+
+        Some(result)
+    }
+}
+
+/// Sends race results to the ballot box from a background thread, so the scheduler never waits on it.
+pub struct RaceReporter {
+    results: mpsc::Sender<RaceResult>,
+}
+
+impl RaceReporter {
+    /// `base_url` is where the ballot box is listening, e.g. `http://localhost:8080`. Each header is given as
+    /// `Name: value`.
+    pub fn spawn(base_url: &str, timeout: Duration, headers: &[String]) -> Result<Self> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .user_agent("scheduler")
+            .default_headers(votes::parse_headers(headers)?)
+            .build()
+            .context("Could not build HTTP client")?;
+        let url = format!("{}{}", base_url.trim_end_matches('/'), routes::RACE);
+
+        let (results, received) = mpsc::channel::<RaceResult>();
+        std::thread::spawn(move || {
+            // Ends once the reporter is dropped.
+            for result in received {
+                let sent = client
+                    .post(&url)
+                    .json(&result)
+                    .send()
+                    .and_then(|response| response.error_for_status());
+
+                match sent {
+                    Ok(_) => {
+                        info!(round = result.round, winner = %result.winner, "Reported race result")
+                    }
+                    Err(e) => {
+                        warn!(round = result.round, winner = %result.winner, err = %e, "Could not report race result")
+                    }
+                }
+            }
+        });
+
+        Ok(Self { results })
+    }
+
+    pub fn report(&self, result: RaceResult) {
+        let _ = self.results.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn race_to(target_ms: u64, finish: RaceFinish) -> Race {
+        Race::new(&RaceArgs {
+            race_target_ms: Some(target_ms),
+            race_finish: finish,
+        })
+        .unwrap()
+    }
+
+    fn cpu_time(summer1_ms: u64, summer2_ms: u64) -> Vec<(String, Duration)> {
+        vec![
+            ("summer1".into(), Duration::from_millis(summer1_ms)),
+            ("summer2".into(), Duration::from_millis(summer2_ms)),
+        ]
+    }
+
+    #[test]
+    fn off_unless_theres_a_target() {
+        assert!(Race::new(&RaceArgs {
+            race_target_ms: None,
+            race_finish: RaceFinish::Freeze,
+        })
+        .is_none());
+    }
+
+    #[test]
+    fn first_past_the_target_wins() {
+        let mut race = race_to(1_000, RaceFinish::Freeze);
+        assert_eq!(race.check(&cpu_time(600, 999)), None);
+
+        let result = race.check(&cpu_time(1_200, 1_500)).unwrap();
+        assert_eq!(result.round, 1);
+        assert_eq!(result.winner, "summer2");
+        assert_eq!(result.target_ms, 1_000);
+        assert_eq!(
+            result.cpu_ms,
+            vec![
+                Tally("summer1".into(), 1_200),
+                Tally("summer2".into(), 1_500)
+            ]
+        );
+
+        // Dead heats go to whoever's first in the roster.
+        assert_eq!(
+            race_to(1_000, RaceFinish::Freeze)
+                .check(&cpu_time(1_000, 1_000))
+                .unwrap()
+                .winner,
+            "summer1"
+        );
+    }
+
+    #[test]
+    fn frozen_races_stay_won() {
+        let mut race = race_to(1_000, RaceFinish::Freeze);
+        assert!(race.check(&cpu_time(1_000, 0)).is_some());
+        assert_eq!(race.check(&cpu_time(1_000, 5_000)), None);
+    }
+
+    #[test]
+    fn reset_races_start_everyone_from_zero() {
+        let mut race = race_to(1_000, RaceFinish::Reset);
+        assert_eq!(race.check(&cpu_time(1_000, 400)).unwrap().winner, "summer1");
+
+        // summer2 is ahead overall, but not by enough this round.
+        assert_eq!(race.check(&cpu_time(1_500, 1_300)), None);
+
+        let result = race.check(&cpu_time(1_600, 1_400)).unwrap();
+        assert_eq!(result.round, 2);
+        assert_eq!(result.run, race.run);
+        assert_eq!(result.winner, "summer2");
+        assert_eq!(
+            result.cpu_ms,
+            vec![Tally("summer1".into(), 600), Tally("summer2".into(), 1_000)]
+        );
+    }
+}
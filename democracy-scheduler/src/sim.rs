@@ -0,0 +1,693 @@
+//! A discrete-event simulator for trying out scheduling policies without a sched_ext kernel.
+//!
+//! The real [`Scheduler`] runs against a [`SimulatedBackend`]; this module plays the part of the kernel around it.
+//! Every candidate gets one task (plus any number of background tasks that don't belong to anyone), each task runs in
+//! bursts of CPU time with optional sleeps in between, and votes follow a timeline given on the command line. The
+//! scheduler makes a decision every `--schedule-interval-ms` of simulated time, exactly like it would for real, and at
+//! the end we report how much CPU each task got, how long it waited to run and how fair the whole thing was.
+//!
+//! The kernel side is deliberately simple: dispatched tasks go into a single FIFO and run on the first idle CPU until
+//! their slice or burst runs out, at which point they're queued back to the scheduler. Dispatching a task that isn't
+//! waiting to run is counted as a cancelled dispatch, like the BPF side does.
+
+use crate::backend::SimulatedBackend;
+use crate::bpf::{DispatchedTask, QueuedTask};
+use crate::group::Groups;
+use crate::policy::PolicyArgs;
+use crate::Scheduler;
+use anyhow::{bail, Context, Result};
+use democracy_proto::{roster::Roster, CountingMethod, Tally, WinnerResponse};
+use std::collections::VecDeque;
+use std::fmt;
+use std::str::FromStr;
+
+const NSEC_PER_MSEC: u64 = 1_000_000;
+
+// Slice used when the scheduler dispatches a task without giving it one, which it doesn't at the moment. The same as
+// the default `--slice-us`, but `--slice-us` itself isn't passed to the simulator.
+const DEFAULT_SLICE_NS: u64 = 1_000_000_000;
+
+// First pids handed out to candidate and background tasks.
+const CANDIDATE_PID_BASE: i32 = 1000;
+const BACKGROUND_PID_BASE: i32 = 2000;
+
+#[derive(Debug, Clone, clap::Args)]
+pub struct SimulateArgs {
+    /// Number of CPUs to simulate.
+    #[arg(long, default_value_t = 1)]
+    pub cpus: usize,
+
+    /// How much simulated time to run for, in milliseconds.
+    #[arg(long, default_value_t = 10_000)]
+    pub duration_ms: u64,
+
+    /// How long each task runs for before it blocks, in milliseconds.
+    #[arg(long, default_value_t = 50)]
+    pub burst_ms: u64,
+
+    /// How long each task blocks for between bursts, in milliseconds; 0 keeps every task CPU bound.
+    #[arg(long, default_value_t = 0)]
+    pub sleep_ms: u64,
+
+    /// Number of extra SCHED_EXT tasks that don't belong to any candidate.
+    #[arg(long, default_value_t = 0)]
+    pub background_tasks: usize,
+
+    /// The vote totals from a point in simulated time onwards, e.g. `2500:summer1=3,summer2=1`. Repeat to build up a
+    /// timeline; candidates left out of an entry keep their previous total. With no votes everyone is tied.
+    #[arg(long = "votes", value_name = "AT_MS:ID=COUNT,...")]
+    pub votes: Vec<VoteChange>,
+}
+
+/// A point in the vote timeline.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VoteChange {
+    pub at_ms: u64,
+    pub votes: Vec<(String, u64)>,
+}
+
+impl FromStr for VoteChange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (at_ms, votes) = s.split_once(':').context("Expected AT_MS:ID=COUNT,...")?;
+
+        let at_ms = at_ms
+            .trim()
+            .parse()
+            .with_context(|| format!("'{}' is not a time in milliseconds", at_ms))?;
+
+        let votes = votes
+            .split(',')
+            .map(|vote| {
+                let (id, count) = vote
+                    .split_once('=')
+                    .with_context(|| format!("Expected ID=COUNT but got '{}'", vote))?;
+                let count = count
+                    .trim()
+                    .parse()
+                    .with_context(|| format!("'{}' is not a vote count", count))?;
+
+                Ok((id.trim().to_string(), count))
+            })
+            .collect::<Result<_>>()?;
+
+        Ok(Self { at_ms, votes })
+    }
+}
+
+/// How a single simulated task fared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TaskReport {
+    pub pid: i32,
+
+    /// The candidate the task belongs to; `None` for background tasks.
+    pub owner: Option<String>,
+
+    /// The candidate's share of the vote at the end of the run.
+    pub vote_share: Option<f64>,
+
+    pub cpu_ns: u64,
+
+    /// Number of times the task got on a CPU.
+    pub runs: u64,
+
+    /// Time between becoming runnable and getting on a CPU.
+    pub mean_latency_ns: u64,
+    pub max_latency_ns: u64,
+
+    /// Set if the task was still waiting to run at the end, with how long it had been waiting.
+    pub waiting_ns: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    pub duration_ns: u64,
+    pub nr_cpus: usize,
+    pub tasks: Vec<TaskReport>,
+
+    /// Dispatches for tasks that weren't waiting to run.
+    pub cancelled_dispatches: u64,
+}
+
+impl Report {
+    /// Share of the total CPU time each task got, in task order.
+    pub fn cpu_shares(&self) -> Vec<f64> {
+        let total: u64 = self.tasks.iter().map(|task| task.cpu_ns).sum();
+
+        self.tasks
+            .iter()
+            .map(|task| match total {
+                0 => 0.0,
+                total => task.cpu_ns as f64 / total as f64,
+            })
+            .collect()
+    }
+
+    /// How much of the machine's CPU time was actually used.
+    pub fn utilization(&self) -> f64 {
+        let used: u64 = self.tasks.iter().map(|task| task.cpu_ns).sum();
+        used as f64 / (self.duration_ns as f64 * self.nr_cpus as f64)
+    }
+
+    /// Jain's fairness index of CPU time across every task: 1.0 when everyone got the same, down to 1/n when a single
+    /// task got everything.
+    pub fn fairness(&self) -> f64 {
+        let cpu: Vec<f64> = self.tasks.iter().map(|task| task.cpu_ns as f64).collect();
+        let sum: f64 = cpu.iter().sum();
+        let sum_of_squares: f64 = cpu.iter().map(|cpu| cpu * cpu).sum();
+
+        if sum_of_squares == 0.0 {
+            return 1.0;
+        }
+
+        sum * sum / (cpu.len() as f64 * sum_of_squares)
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |ns: u64| ns as f64 / NSEC_PER_MSEC as f64;
+
+        writeln!(
+            f,
+            "{:>6}  {:<16} {:>7} {:>12} {:>7} {:>6} {:>14} {:>14}",
+            "pid", "owner", "votes", "cpu (ms)", "cpu", "runs", "mean lat (ms)", "max lat (ms)"
+        )?;
+
+        for (task, share) in self.tasks.iter().zip(self.cpu_shares()) {
+            let max_latency = task.waiting_ns.map_or(task.max_latency_ns, |waiting| {
+                waiting.max(task.max_latency_ns)
+            });
+
+            writeln!(
+                f,
+                "{:>6}  {:<16} {:>7} {:>12.1} {:>6.1}% {:>6} {:>14.1} {:>13.1}{}",
+                task.pid,
+                task.owner.as_deref().unwrap_or("-"),
+                task.vote_share
+                    .map_or("-".to_string(), |share| format!("{:.1}%", share * 100.0)),
+                ms(task.cpu_ns),
+                share * 100.0,
+                task.runs,
+                ms(task.mean_latency_ns),
+                ms(max_latency),
+                if task.waiting_ns.is_some() { "+" } else { " " },
+            )?;
+        }
+
+        writeln!(f)?;
+        writeln!(
+            f,
+            "simulated {:.1}s on {} cpu(s): {:.1}% utilization, fairness index {:.3}, {} cancelled dispatch(es)",
+            ms(self.duration_ns) / 1000.0,
+            self.nr_cpus,
+            self.utilization() * 100.0,
+            self.fairness(),
+            self.cancelled_dispatches
+        )?;
+
+        if self.tasks.iter().any(|task| task.waiting_ns.is_some()) {
+            writeln!(f, "+ still waiting to run when the simulation ended")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Runs the simulation described by `args` through the real scheduler using the given policies.
+pub fn simulate(roster: &Roster, policy: &PolicyArgs, args: &SimulateArgs) -> Result<Report> {
+    if args.cpus == 0 {
+        bail!("Need at least one CPU to simulate");
+    }
+    if args.burst_ms == 0 {
+        bail!("Tasks need to run for at least a millisecond at a time");
+    }
+    for change in &args.votes {
+        if let Some((id, _)) = change.votes.iter().find(|(id, _)| roster.get(id).is_none()) {
+            bail!(
+                "Vote timeline mentions '{}' which isn't in the roster; Must be one of {}",
+                id,
+                roster.id_list()
+            );
+        }
+    }
+
+    let mut timeline = args.votes.clone();
+    timeline.sort_by_key(|change| change.at_ms);
+
+    let mut sched = Scheduler::new(SimulatedBackend::new(args.cpus), roster.clone(), policy);
+    // None of the simulated tasks exist, so there's nothing to look up in /proc.
+    sched.groups = Groups::new(None);
+
+    let mut sim = Simulation {
+        now: 0,
+        burst_ns: args.burst_ms * NSEC_PER_MSEC,
+        sleep_ns: args.sleep_ms * NSEC_PER_MSEC,
+        cpus: vec![None; args.cpus],
+        dsq: VecDeque::new(),
+        tasks: vec![],
+        cancelled_dispatches: 0,
+    };
+
+    for (i, candidate) in roster.candidates.iter().enumerate() {
+        let pid = CANDIDATE_PID_BASE + i as i32;
+        sched.add_candidate(&candidate.id, pid as u32);
+        sim.tasks
+            .push(SimTask::new(pid, Some(candidate.id.clone()), sim.burst_ns));
+    }
+
+    for i in 0..args.background_tasks {
+        sim.tasks.push(SimTask::new(
+            BACKGROUND_PID_BASE + i as i32,
+            None,
+            sim.burst_ns,
+        ));
+    }
+
+    // Everyone starts off runnable.
+    for i in 0..sim.tasks.len() {
+        sim.wake(i, &mut sched.backend);
+    }
+
+    let duration_ns = args.duration_ms * NSEC_PER_MSEC;
+    let period_ns = sched.interval.as_nanos() as u64;
+
+    while sim.now < duration_ns {
+        sched.drain_queue();
+        sched.dispatch(&results_at(roster, &timeline, sim.now));
+
+        for task in sched.backend.take_dispatched() {
+            sim.accept(&task);
+        }
+
+        let next_decision = (sim.now + period_ns).min(duration_ns);
+        sim.run_until(next_decision, &mut sched.backend);
+    }
+
+    Ok(sim.report(
+        roster,
+        &results_at(roster, &timeline, duration_ns),
+        duration_ns,
+    ))
+}
+
+/// What the ballot box would report at `now_ns` for the given timeline, counting the totals as plurality votes.
+fn results_at(roster: &Roster, timeline: &[VoteChange], now_ns: u64) -> WinnerResponse {
+    let mut votes = vec![0u64; roster.candidates.len()];
+
+    for change in timeline
+        .iter()
+        .take_while(|change| change.at_ms * NSEC_PER_MSEC <= now_ns)
+    {
+        for (id, count) in &change.votes {
+            if let Some(position) = roster.position(id) {
+                votes[position] = *count;
+            }
+        }
+    }
+
+    let top = votes.iter().copied().max().unwrap_or(0);
+    let leaders: Vec<String> = roster
+        .candidates
+        .iter()
+        .zip(&votes)
+        .filter(|(_, &count)| count == top)
+        .map(|(candidate, _)| candidate.id.clone())
+        .collect();
+
+    let (winner, tied) = match leaders.as_slice() {
+        [winner] => (Some(winner.clone()), vec![]),
+        _ => (None, leaders),
+    };
+
+    WinnerResponse {
+        method: CountingMethod::Plurality,
+        winner,
+        tied,
+        scores: roster
+            .candidates
+            .iter()
+            .zip(&votes)
+            .map(|(candidate, &count)| Tally(candidate.id.clone(), count))
+            .collect(),
+        ballots: votes.iter().sum(),
+        explanation: "simulated".into(),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TaskState {
+    /// Queued to the scheduler, waiting to be dispatched.
+    Queued,
+
+    /// Dispatched, waiting in the FIFO for a free CPU.
+    Dispatched,
+
+    Running {
+        cpu: usize,
+        started: u64,
+        until: u64,
+    },
+
+    Sleeping {
+        until: u64,
+    },
+}
+
+struct SimTask {
+    pid: i32,
+    owner: Option<String>,
+    state: TaskState,
+    last_cpu: usize,
+    burst_left_ns: u64,
+    runnable_since: u64,
+
+    // What the kernel would report in QueuedTask.
+    sum_exec_runtime: u64,
+    nvcsw: u64,
+
+    runs: u64,
+    total_latency_ns: u64,
+    max_latency_ns: u64,
+}
+
+impl SimTask {
+    fn new(pid: i32, owner: Option<String>, burst_ns: u64) -> Self {
+        Self {
+            pid,
+            owner,
+            state: TaskState::Sleeping { until: 0 },
+            last_cpu: 0,
+            burst_left_ns: burst_ns,
+            runnable_since: 0,
+            sum_exec_runtime: 0,
+            nvcsw: 0,
+            runs: 0,
+            total_latency_ns: 0,
+            max_latency_ns: 0,
+        }
+    }
+}
+
+struct Simulation {
+    now: u64,
+    burst_ns: u64,
+    sleep_ns: u64,
+    cpus: Vec<Option<usize>>,    // index of the task running on each CPU
+    dsq: VecDeque<(usize, u64)>, // dispatched tasks waiting for a CPU, with their slice
+    tasks: Vec<SimTask>,
+    cancelled_dispatches: u64,
+}
+
+impl Simulation {
+    /// Makes a task runnable and queues it to the scheduler.
+    fn wake(&mut self, i: usize, backend: &mut SimulatedBackend) {
+        let task = &mut self.tasks[i];
+        task.state = TaskState::Queued;
+        task.runnable_since = self.now;
+
+        backend.enqueue(QueuedTask::new(
+            task.pid,
+            task.last_cpu as i32,
+            task.sum_exec_runtime,
+            task.nvcsw,
+            100,
+        ));
+    }
+
+    /// Takes a dispatch from the scheduler.
+    fn accept(&mut self, dispatched: &DispatchedTask) {
+        let Some(i) = self
+            .tasks
+            .iter()
+            .position(|task| task.pid == dispatched.pid())
+        else {
+            self.cancelled_dispatches += 1;
+            return;
+        };
+
+        if self.tasks[i].state != TaskState::Queued {
+            self.cancelled_dispatches += 1;
+            return;
+        }
+
+        let slice_ns = match dispatched.slice_ns() {
+            0 => DEFAULT_SLICE_NS,
+            slice_ns => slice_ns,
+        };
+
+        self.tasks[i].state = TaskState::Dispatched;
+        self.dsq.push_back((i, slice_ns));
+    }
+
+    /// Runs the machine until `end`, processing every event on the way.
+    fn run_until(&mut self, end: u64, backend: &mut SimulatedBackend) {
+        loop {
+            for cpu in 0..self.cpus.len() {
+                if self.cpus[cpu].is_none() {
+                    if let Some((i, slice_ns)) = self.dsq.pop_front() {
+                        self.start(i, cpu, slice_ns);
+                    }
+                }
+            }
+
+            let next = self
+                .tasks
+                .iter()
+                .filter_map(|task| match task.state {
+                    TaskState::Running { until, .. } | TaskState::Sleeping { until } => Some(until),
+                    _ => None,
+                })
+                .min();
+
+            match next {
+                Some(next) if next <= end => self.now = next,
+                _ => {
+                    self.now = end;
+                    return;
+                }
+            }
+
+            for i in 0..self.tasks.len() {
+                match self.tasks[i].state {
+                    TaskState::Running { until, .. } if until == self.now => self.stop(i, backend),
+                    TaskState::Sleeping { until } if until == self.now => self.wake(i, backend),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn start(&mut self, i: usize, cpu: usize, slice_ns: u64) {
+        let now = self.now;
+        let task = &mut self.tasks[i];
+
+        let latency = now - task.runnable_since;
+        task.runs += 1;
+        task.total_latency_ns += latency;
+        task.max_latency_ns = task.max_latency_ns.max(latency);
+
+        task.last_cpu = cpu;
+        task.state = TaskState::Running {
+            cpu,
+            started: now,
+            until: now + slice_ns.min(task.burst_left_ns),
+        };
+        self.cpus[cpu] = Some(i);
+    }
+
+    // A task came off the CPU, either because its slice ran out or because its burst is over.
+    fn stop(&mut self, i: usize, backend: &mut SimulatedBackend) {
+        let TaskState::Running {
+            cpu,
+            started,
+            until,
+        } = self.tasks[i].state
+        else {
+            return;
+        };
+
+        self.cpus[cpu] = None;
+
+        let task = &mut self.tasks[i];
+        let ran = until - started;
+        task.sum_exec_runtime += ran;
+        task.burst_left_ns -= ran;
+
+        if task.burst_left_ns > 0 {
+            self.wake(i, backend);
+            return;
+        }
+
+        task.burst_left_ns = self.burst_ns;
+        if self.sleep_ns == 0 {
+            self.wake(i, backend);
+        } else {
+            task.nvcsw += 1;
+            task.state = TaskState::Sleeping {
+                until: self.now + self.sleep_ns,
+            };
+        }
+    }
+
+    fn report(&self, roster: &Roster, results: &WinnerResponse, duration_ns: u64) -> Report {
+        let total_votes: u64 = results.scores.iter().map(|tally| tally.1).sum();
+
+        let tasks = self
+            .tasks
+            .iter()
+            .map(|task| {
+                // Count the slice in progress so a task hogging a CPU at the end isn't under-reported.
+                let running_ns = match task.state {
+                    TaskState::Running { started, .. } => self.now - started,
+                    _ => 0,
+                };
+
+                let vote_share = task.owner.as_ref().map(|owner| {
+                    let votes = roster
+                        .position(owner)
+                        .map_or(0, |position| results.scores[position].1);
+                    match total_votes {
+                        0 => 1.0 / roster.candidates.len() as f64,
+                        total => votes as f64 / total as f64,
+                    }
+                });
+
+                let waiting = matches!(task.state, TaskState::Queued | TaskState::Dispatched);
+
+                TaskReport {
+                    pid: task.pid,
+                    owner: task.owner.clone(),
+                    vote_share,
+                    cpu_ns: task.sum_exec_runtime + running_ns,
+                    runs: task.runs,
+                    mean_latency_ns: task.total_latency_ns.checked_div(task.runs).unwrap_or(0),
+                    max_latency_ns: task.max_latency_ns,
+                    waiting_ns: waiting.then(|| self.now - task.runnable_since),
+                }
+            })
+            .collect();
+
+        Report {
+            duration_ns,
+            nr_cpus: self.cpus.len(),
+            tasks,
+            cancelled_dispatches: self.cancelled_dispatches,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::policy::SchedulingPolicy;
+    use clap::Parser;
+
+    fn roster() -> Roster {
+        Roster::parse(
+            r#"
+            [[candidates]]
+            id = "summer1"
+            name = "Summer 1"
+
+            [[candidates]]
+            id = "summer2"
+            name = "Summer 2"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn args(votes: &[&str]) -> SimulateArgs {
+        SimulateArgs {
+            cpus: 1,
+            duration_ms: 10_000,
+            burst_ms: 50,
+            sleep_ms: 0,
+            background_tasks: 0,
+            votes: votes.iter().map(|votes| votes.parse().unwrap()).collect(),
+        }
+    }
+
+    fn run(policy: SchedulingPolicy, args: &SimulateArgs) -> Report {
+        let mut policy_args = PolicyArgs::parse_from(["simulate"]);
+        policy_args.policy = policy;
+
+        simulate(&roster(), &policy_args, args).unwrap()
+    }
+
+    #[test]
+    fn parse_vote_change() {
+        assert_eq!(
+            "2500:summer1=3, summer2=1".parse::<VoteChange>().unwrap(),
+            VoteChange {
+                at_ms: 2500,
+                votes: vec![("summer1".into(), 3), ("summer2".into(), 1)],
+            }
+        );
+        assert!("summer1=3".parse::<VoteChange>().is_err());
+        assert!("0:summer1".parse::<VoteChange>().is_err());
+    }
+
+    #[test]
+    fn reject_unknown_candidates() {
+        let result = simulate(
+            &roster(),
+            &PolicyArgs::parse_from(["simulate"]),
+            &args(&["0:winter1=1"]),
+        );
+
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn winner_takes_all_cpu() {
+        let report = run(
+            SchedulingPolicy::WinnerTakesAll,
+            &args(&["0:summer1=2,summer2=1"]),
+        );
+
+        assert!(report.tasks[0].cpu_ns > 0);
+        assert_eq!(report.tasks[1].cpu_ns, 0);
+        assert!(report.tasks[1].waiting_ns.is_some());
+    }
+
+    #[test]
+    fn vote_timeline_changes_the_winner() {
+        let report = run(
+            SchedulingPolicy::WinnerTakesAll,
+            &args(&["0:summer1=2,summer2=1", "5000:summer2=3"]),
+        );
+        let shares = report.cpu_shares();
+
+        assert!((shares[0] - 0.5).abs() < 0.05, "shares were {:?}", shares);
+    }
+
+    #[test]
+    fn proportional_cpu_tracks_votes() {
+        // CPU bound tasks, so that they use all of the slice they're given.
+        let mut args = args(&["0:summer1=3,summer2=1"]);
+        args.burst_ms = 1000;
+        let report = run(SchedulingPolicy::Proportional, &args);
+        let shares = report.cpu_shares();
+
+        assert!((shares[0] - 0.75).abs() < 0.05, "shares were {:?}", shares);
+        assert!(report.utilization() > 0.9);
+    }
+
+    #[test]
+    fn background_tasks_are_reported() {
+        let mut args = args(&[]);
+        args.background_tasks = 2;
+        let report = run(SchedulingPolicy::WinnerTakesAll, &args);
+
+        assert_eq!(report.tasks.len(), 4);
+        assert_eq!(report.tasks[2].owner, None);
+        assert!(report.fairness() <= 1.0);
+
+        // They aren't in the election, but they still get to run, and evenly.
+        assert!(report.tasks[2].cpu_ns > 0);
+        assert_eq!(report.tasks[2].cpu_ns, report.tasks[3].cpu_ns);
+    }
+}
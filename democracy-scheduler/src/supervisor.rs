@@ -0,0 +1,414 @@
+//! Keeps the candidates' programs running.
+//!
+//! The scheduler only knows a candidate by the pid of its program, so a competitor that crashes (or is killed by an
+//! overzealous voter) would otherwise leave the candidate unable to run for the rest of the election. The supervisor
+//! owns every competitor, notices when one exits, and restarts it after a backoff that grows with every consecutive
+//! crash. A competitor that keeps crashing is eventually given up on and marked as failed.
+//!
+//! Candidates the scheduler attaches to instead of launching aren't supervised; they're whatever's already running.
+//!
+//! Each change of pid is reported back as a [`PidChange`] so the scheduler can update its maps in one go.
+
+use crate::group;
+use anyhow::{Context, Result};
+use democracy_proto::control::ProgramState;
+use democracy_proto::roster::Roster;
+use nix::sys::signal::{kill, Signal};
+use nix::unistd::Pid;
+use std::process::Child;
+use std::time::{Duration, Instant};
+use tracing::{error, info, warn};
+
+/// A competitor that stays up this long is considered healthy again, and its next crash starts the backoff over.
+const STABLE_AFTER: Duration = Duration::from_secs(30);
+
+/// The longest we'll wait before restarting a competitor.
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How the supervisor deals with competitors that exit.
+#[derive(Debug, Clone, clap::Args)]
+pub struct RestartArgs {
+    /// How many times in a row a competitor can exit and be restarted before it's given up on.
+    #[arg(long, env = "DEMOCRACY_COMPETITOR_MAX_RESTARTS", default_value_t = 5)]
+    pub competitor_max_restarts: u32,
+
+    /// How long to wait before restarting a competitor that exited, in milliseconds. This doubles with every
+    /// consecutive restart, up to 30 seconds.
+    #[arg(long, env = "DEMOCRACY_COMPETITOR_BACKOFF_MS", default_value_t = 500)]
+    pub competitor_backoff_ms: u64,
+}
+
+/// Where a competitor is in its life.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompetitorState {
+    /// Not launched yet.
+    Pending,
+
+    /// Up and running as `pid`.
+    Running { pid: u32 },
+
+    /// Exited; it'll be restarted once the backoff runs out. `attempt` counts consecutive restarts.
+    Restarting { attempt: u32 },
+
+    /// Exited too many times in a row, or stopped on purpose; it won't be restarted.
+    Failed,
+}
+
+impl From<CompetitorState> for ProgramState {
+    fn from(state: CompetitorState) -> Self {
+        match state {
+            CompetitorState::Pending => Self::Pending,
+            CompetitorState::Running { .. } => Self::Running,
+            CompetitorState::Restarting { .. } => Self::Restarting,
+            CompetitorState::Failed => Self::Failed,
+        }
+    }
+}
+
+/// A competitor's pid changing, for the scheduler to mirror in its pid maps.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PidChange {
+    Started { id: String, pid: u32 },
+    Exited { id: String, pid: u32 },
+}
+
+struct Competitor {
+    id: String,
+    command: Vec<String>,
+    cgroup: Option<String>,
+    state: CompetitorState,
+    child: Option<Child>,
+    started: Instant,       // when it was last launched
+    restart_at: Instant,    // when to next launch it while restarting
+    consecutive_exits: u32, // exits since it was last stable
+}
+
+pub struct Supervisor {
+    competitors: Vec<Competitor>,
+    max_restarts: u32,
+    backoff: Duration,
+}
+
+impl Supervisor {
+    pub fn new(roster: &Roster, args: &RestartArgs) -> Self {
+        let now = Instant::now();
+
+        Self {
+            competitors: roster
+                .candidates
+                .iter()
+                .filter(|candidate| !candidate.is_attached())
+                .map(|candidate| Competitor {
+                    id: candidate.id.clone(),
+                    command: candidate.command.clone(),
+                    cgroup: candidate.cgroup.clone(),
+                    state: CompetitorState::Pending,
+                    child: None,
+                    started: now,
+                    restart_at: now,
+                    consecutive_exits: 0,
+                })
+                .collect(),
+            max_restarts: args.competitor_max_restarts,
+            backoff: Duration::from_millis(args.competitor_backoff_ms),
+        }
+    }
+
+    /// Launches every competitor for the first time. Failing to launch one at all is an error, since it almost
+    /// certainly means the roster is wrong; the ones launched before it are left for [`Self::stop`] to clean up.
+    pub fn start(&mut self) -> Result<Vec<PidChange>> {
+        let mut changes = vec![];
+
+        for competitor in &mut self.competitors {
+            let child = launch_process(&competitor.command, competitor.cgroup.as_deref())?;
+            changes.push(competitor.running(child));
+        }
+
+        Ok(changes)
+    }
+
+    /// Reaps any competitors that have exited and restarts any whose backoff has run out. Never blocks.
+    pub fn poll(&mut self) -> Vec<PidChange> {
+        let mut changes = vec![];
+        let now = Instant::now();
+
+        for competitor in &mut self.competitors {
+            match competitor.state {
+                CompetitorState::Running { pid } => {
+                    let status = match competitor.child.as_mut().map(Child::try_wait) {
+                        Some(Ok(Some(status))) => status.to_string(),
+                        Some(Ok(None)) => continue,
+                        Some(Err(e)) => e.to_string(),
+                        None => "lost track of it".into(),
+                    };
+
+                    competitor.child = None;
+                    changes.push(PidChange::Exited {
+                        id: competitor.id.clone(),
+                        pid,
+                    });
+
+                    if now.duration_since(competitor.started) >= STABLE_AFTER {
+                        competitor.consecutive_exits = 0;
+                    }
+                    competitor.exited(self.max_restarts, self.backoff, &status);
+                }
+                CompetitorState::Restarting { attempt } if now >= competitor.restart_at => {
+                    match launch_process(&competitor.command, competitor.cgroup.as_deref()) {
+                        Ok(child) => {
+                            info!(owner = %competitor.id, pid = child.id(), attempt, "Competitor restarted");
+                            changes.push(competitor.running(child));
+                        }
+                        Err(e) => {
+                            competitor.exited(self.max_restarts, self.backoff, &e.to_string())
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        changes
+    }
+
+    /// Every competitor that's currently running, and its pid.
+    pub fn running(&self) -> Vec<(String, u32)> {
+        self.competitors
+            .iter()
+            .filter_map(|competitor| match competitor.state {
+                CompetitorState::Running { pid } => Some((competitor.id.clone(), pid)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Every competitor's state, in roster order.
+    pub fn states(&self) -> Vec<(String, CompetitorState)> {
+        self.competitors
+            .iter()
+            .map(|competitor| (competitor.id.clone(), competitor.state))
+            .collect()
+    }
+
+    /// Asks every competitor to exit with SIGTERM, then kills any that are still around after `grace`. Nothing is
+    /// restarted after this.
+    pub fn stop(&mut self, grace: Duration) {
+        for child in self.competitors.iter().filter_map(|c| c.child.as_ref()) {
+            // They haven't been reaped yet, so the pid can't have been reused even if they've already exited.
+            let _ = kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM);
+        }
+
+        let deadline = Instant::now() + grace;
+        for competitor in &mut self.competitors {
+            competitor.state = CompetitorState::Failed;
+
+            let Some(mut child) = competitor.child.take() else {
+                continue;
+            };
+
+            loop {
+                match child.try_wait() {
+                    Ok(Some(status)) => {
+                        info!(owner = %competitor.id, pid = child.id(), status = %status, "Competitor stopped");
+                        break;
+                    }
+                    Ok(None) if Instant::now() >= deadline => {
+                        warn!(owner = %competitor.id, pid = child.id(), "Competitor ignored SIGTERM; killing it");
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        break;
+                    }
+                    Ok(None) => std::thread::sleep(Duration::from_millis(10)),
+                    Err(e) => {
+                        error!(owner = %competitor.id, pid = child.id(), err = %e, "Could not wait for competitor");
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Competitor {
+    fn running(&mut self, child: Child) -> PidChange {
+        let pid = child.id();
+
+        self.child = Some(child);
+        self.state = CompetitorState::Running { pid };
+        self.started = Instant::now();
+
+        PidChange::Started {
+            id: self.id.clone(),
+            pid,
+        }
+    }
+
+    fn exited(&mut self, max_restarts: u32, backoff: Duration, reason: &str) {
+        self.consecutive_exits += 1;
+
+        if self.consecutive_exits > max_restarts {
+            error!(owner = %self.id, reason, exits = self.consecutive_exits, "Competitor keeps exiting; giving up on it");
+            self.state = CompetitorState::Failed;
+            return;
+        }
+
+        let delay = backoff
+            .saturating_mul(1 << (self.consecutive_exits - 1).min(16))
+            .min(MAX_BACKOFF);
+        warn!(owner = %self.id, reason, delay = ?delay, "Competitor exited; restarting it");
+
+        self.restart_at = Instant::now() + delay;
+        self.state = CompetitorState::Restarting {
+            attempt: self.consecutive_exits,
+        };
+    }
+}
+
+// Launches a process, in `cgroup` if there is one. The first element of `command` is the binary, the rest are its
+// arguments.
+fn launch_process(command: &[String], cgroup: Option<&str>) -> Result<Child> {
+    let bin_name = &command[0];
+
+    // Launch the process
+    let mut process = std::process::Command::new(bin_name);
+    process.args(&command[1..]);
+
+    let child = process
+        .stdout(std::process::Stdio::null()) // Don't overwhelm with stdout logs
+        .spawn()
+        .with_context(|| format!("Failed to start '{}'", bin_name))?;
+
+    info!(pid = child.id(), bin_name = bin_name, "Launched process");
+
+    // Anything it started before being moved stays behind, but that's at most a few instructions' worth.
+    if let Some(cgroup) = cgroup {
+        let procs = group::cgroup_dir(cgroup).join("cgroup.procs");
+        if let Err(e) = std::fs::write(&procs, child.id().to_string()) {
+            warn!(pid = child.id(), cgroup, err = %e, "Could not move process into its cgroup; it won't be scheduled");
+        }
+    }
+
+    Ok(child)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roster(commands: &[&str]) -> Roster {
+        let mut toml = String::new();
+        for (i, command) in commands.iter().enumerate() {
+            toml += &format!(
+                "[[candidates]]\nid = \"summer{}\"\nname = \"Summer {}\"\ncommand = [\"sh\", \"-c\", {:?}]\n",
+                i + 1,
+                i + 1,
+                command
+            );
+        }
+
+        Roster::parse(&toml).unwrap()
+    }
+
+    fn supervisor(commands: &[&str], max_restarts: u32) -> Supervisor {
+        Supervisor::new(
+            &roster(commands),
+            &RestartArgs {
+                competitor_max_restarts: max_restarts,
+                competitor_backoff_ms: 10,
+            },
+        )
+    }
+
+    // Polls until `done` says so, collecting every change along the way.
+    fn poll_until(
+        supervisor: &mut Supervisor,
+        done: impl Fn(&Supervisor) -> bool,
+    ) -> Vec<PidChange> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut changes = vec![];
+
+        while !done(supervisor) {
+            assert!(
+                Instant::now() < deadline,
+                "gave up waiting on the supervisor"
+            );
+            changes.extend(supervisor.poll());
+            std::thread::sleep(Duration::from_millis(5));
+        }
+
+        changes
+    }
+
+    #[test]
+    fn crashing_competitors_are_restarted_then_given_up_on() {
+        let mut supervisor = supervisor(&["exit 1"], 2);
+
+        let started = supervisor.start().unwrap();
+        assert!(matches!(started[..], [PidChange::Started { .. }]));
+
+        let changes = poll_until(&mut supervisor, |s| {
+            s.states()[0].1 == CompetitorState::Failed
+        });
+
+        let restarts = changes
+            .iter()
+            .filter(|change| matches!(change, PidChange::Started { .. }))
+            .count();
+        let exits = changes
+            .iter()
+            .filter(|change| matches!(change, PidChange::Exited { .. }))
+            .count();
+        assert_eq!((restarts, exits), (2, 3));
+        assert!(supervisor.running().is_empty());
+    }
+
+    #[test]
+    fn restarted_competitors_get_a_new_pid() {
+        let mut supervisor = supervisor(&["sleep 30"], 1);
+        supervisor.start().unwrap();
+        let (_, first_pid) = supervisor.running()[0].clone();
+
+        kill(Pid::from_raw(first_pid as i32), Signal::SIGKILL).unwrap();
+
+        let changes = poll_until(
+            &mut supervisor,
+            |s| matches!(s.states()[0].1, CompetitorState::Running { pid } if pid != first_pid),
+        );
+        let (_, second_pid) = supervisor.running()[0].clone();
+
+        assert_eq!(
+            changes,
+            vec![
+                PidChange::Exited {
+                    id: "summer1".into(),
+                    pid: first_pid
+                },
+                PidChange::Started {
+                    id: "summer1".into(),
+                    pid: second_pid
+                },
+            ]
+        );
+
+        supervisor.stop(Duration::from_secs(1));
+    }
+
+    #[test]
+    fn stop_kills_competitors_that_ignore_sigterm() {
+        let mut supervisor = supervisor(&["sleep 30", "trap '' TERM; exec sleep 30"], 5);
+        supervisor.start().unwrap();
+
+        // Give the shell a chance to set up its trap.
+        std::thread::sleep(Duration::from_millis(100));
+
+        let started = Instant::now();
+        supervisor.stop(Duration::from_millis(200));
+
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert!(supervisor.running().is_empty());
+
+        // Nothing comes back after being stopped.
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(supervisor.poll().is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+//! Deciding who gets the CPU when the election doesn't produce an outright winner.
+//!
+//! The ballot box does all the counting (see `/api/winner`) and reports either a winner or the candidates sharing
+//! first place. Before any votes have been cast it reports every candidate as tied, so "no votes yet" is just the
+//! biggest possible tie and goes through the same policy; the candidates still get to run instead of the scheduler
+//! sitting idle until the first vote comes in.
+
+use democracy_proto::{roster::Roster, WinnerResponse};
+
+/// What to do when candidates are tied for first place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum TiePolicy {
+    /// Take turns: each decision goes to the next tied candidate (in roster order) after the last one picked.
+    RoundRobin,
+
+    /// Run every tied candidate at once so they share the CPU equally.
+    Proportional,
+
+    /// Stick with the last candidate picked if they're among those tied, otherwise the first tied in roster order.
+    KeepPrevious,
+
+    /// Pick one of the tied candidates at random. The sequence is reproducible for a given seed.
+    Random,
+}
+
+pub struct TieBreaker {
+    policy: TiePolicy,
+    previous: Option<usize>, // roster position of the last candidate picked
+    rng: SplitMix64,
+}
+
+impl TieBreaker {
+    pub fn new(policy: TiePolicy, seed: u64) -> Self {
+        Self {
+            policy,
+            previous: None,
+            rng: SplitMix64(seed),
+        }
+    }
+
+    pub fn policy(&self) -> TiePolicy {
+        self.policy
+    }
+
+    /// Switches to another policy from the next tie on; whoever was picked last still counts.
+    pub fn set_policy(&mut self, policy: TiePolicy) {
+        self.policy = policy;
+    }
+
+    /// Returns the ids of the candidates that should run, in roster order. This is only ever more than one candidate
+    /// under [`TiePolicy::Proportional`], and only ever empty if the ballot box reports nobody we recognize.
+    pub fn decide(&mut self, roster: &Roster, response: &WinnerResponse) -> Vec<String> {
+        let mut leaders: Vec<usize> = match &response.winner {
+            Some(winner) => roster.position(winner).into_iter().collect(),
+            None => response
+                .tied
+                .iter()
+                .filter_map(|id| roster.position(id))
+                .collect(),
+        };
+        leaders.sort_unstable();
+        leaders.dedup();
+
+        let picked = match (leaders.as_slice(), self.policy) {
+            ([], _) => vec![],
+            ([winner], _) => vec![*winner],
+            (_, TiePolicy::Proportional) => leaders,
+            (_, TiePolicy::RoundRobin) => {
+                let next = self
+                    .previous
+                    .and_then(|previous| leaders.iter().find(|&&i| i > previous))
+                    .unwrap_or(&leaders[0]);
+                vec![*next]
+            }
+            (_, TiePolicy::KeepPrevious) => match self.previous {
+                Some(previous) if leaders.contains(&previous) => vec![previous],
+                _ => vec![leaders[0]],
+            },
+            (_, TiePolicy::Random) => {
+                vec![leaders[(self.rng.next() % leaders.len() as u64) as usize]]
+            }
+        };
+
+        if let [only] = picked.as_slice() {
+            self.previous = Some(*only);
+        }
+
+        picked
+            .into_iter()
+            .map(|i| roster.candidates[i].id.clone())
+            .collect()
+    }
+}
+
+// Small, seedable PRNG so tie-breaks can be replayed without pulling in a dependency. See
+// https://prng.di.unimi.it/splitmix64.c
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use democracy_proto::CountingMethod;
+
+    fn roster() -> Roster {
+        Roster::parse(
+            r#"
+            [[candidates]]
+            id = "a"
+            name = "A"
+
+            [[candidates]]
+            id = "b"
+            name = "B"
+
+            [[candidates]]
+            id = "c"
+            name = "C"
+            "#,
+        )
+        .unwrap()
+    }
+
+    fn response(winner: Option<&str>, tied: &[&str], ballots: u64) -> WinnerResponse {
+        WinnerResponse {
+            method: CountingMethod::Plurality,
+            winner: winner.map(String::from),
+            tied: tied.iter().map(|id| id.to_string()).collect(),
+            scores: vec![],
+            ballots,
+            explanation: String::new(),
+        }
+    }
+
+    fn no_votes() -> WinnerResponse {
+        response(None, &["a", "b", "c"], 0)
+    }
+
+    #[test]
+    fn outright_winner_ignores_policy() {
+        for policy in [
+            TiePolicy::RoundRobin,
+            TiePolicy::Proportional,
+            TiePolicy::KeepPrevious,
+            TiePolicy::Random,
+        ] {
+            let mut tie_breaker = TieBreaker::new(policy, 0);
+
+            assert_eq!(
+                tie_breaker.decide(&roster(), &response(Some("B"), &[], 3)),
+                vec!["b"]
+            );
+        }
+    }
+
+    #[test]
+    fn round_robin_takes_turns() {
+        let mut tie_breaker = TieBreaker::new(TiePolicy::RoundRobin, 0);
+        let tie = response(None, &["c", "a"], 2);
+
+        assert_eq!(tie_breaker.decide(&roster(), &tie), vec!["a"]);
+        assert_eq!(tie_breaker.decide(&roster(), &tie), vec!["c"]);
+        assert_eq!(tie_breaker.decide(&roster(), &tie), vec!["a"]);
+    }
+
+    #[test]
+    fn round_robin_continues_after_outright_winner() {
+        let mut tie_breaker = TieBreaker::new(TiePolicy::RoundRobin, 0);
+
+        tie_breaker.decide(&roster(), &response(Some("b"), &[], 1));
+
+        assert_eq!(
+            tie_breaker.decide(&roster(), &response(None, &["a", "b", "c"], 3)),
+            vec!["c"]
+        );
+    }
+
+    #[test]
+    fn proportional_runs_everyone_tied() {
+        let mut tie_breaker = TieBreaker::new(TiePolicy::Proportional, 0);
+
+        assert_eq!(
+            tie_breaker.decide(&roster(), &response(None, &["c", "a"], 2)),
+            vec!["a", "c"]
+        );
+    }
+
+    #[test]
+    fn keep_previous_holds_on_while_still_tied() {
+        let mut tie_breaker = TieBreaker::new(TiePolicy::KeepPrevious, 0);
+
+        tie_breaker.decide(&roster(), &response(Some("b"), &[], 1));
+        assert_eq!(
+            tie_breaker.decide(&roster(), &response(None, &["a", "b"], 2)),
+            vec!["b"]
+        );
+
+        // b fell out of the tie, so fall back to roster order.
+        assert_eq!(
+            tie_breaker.decide(&roster(), &response(None, &["a", "c"], 4)),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn random_is_reproducible_for_a_seed() {
+        let picks = |seed| {
+            let mut tie_breaker = TieBreaker::new(TiePolicy::Random, seed);
+            (0..32)
+                .map(|_| tie_breaker.decide(&roster(), &no_votes()).remove(0))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(picks(7), picks(7));
+        assert_ne!(picks(7), picks(8));
+        assert!(["a", "b", "c"]
+            .iter()
+            .all(|id| picks(7).iter().any(|pick| pick == id)));
+    }
+
+    #[test]
+    fn no_votes_is_a_tie_between_everyone() {
+        let mut round_robin = TieBreaker::new(TiePolicy::RoundRobin, 0);
+        let mut keep_previous = TieBreaker::new(TiePolicy::KeepPrevious, 0);
+        let mut proportional = TieBreaker::new(TiePolicy::Proportional, 0);
+
+        assert_eq!(round_robin.decide(&roster(), &no_votes()), vec!["a"]);
+        assert_eq!(round_robin.decide(&roster(), &no_votes()), vec!["b"]);
+        assert_eq!(keep_previous.decide(&roster(), &no_votes()), vec!["a"]);
+        assert_eq!(keep_previous.decide(&roster(), &no_votes()), vec!["a"]);
+        assert_eq!(
+            proportional.decide(&roster(), &no_votes()),
+            vec!["a", "b", "c"]
+        );
+    }
+
+    #[test]
+    fn unknown_candidates_are_ignored() {
+        let mut tie_breaker = TieBreaker::new(TiePolicy::RoundRobin, 0);
+
+        assert_eq!(
+            tie_breaker.decide(&roster(), &response(Some("winter1"), &[], 1)),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            tie_breaker.decide(&roster(), &response(None, &["winter1", "c"], 2)),
+            vec!["c"]
+        );
+    }
+}
@@ -0,0 +1,519 @@
+//! `--tui`: a live dashboard in the terminal, for running the scheduler in front of an audience.
+//!
+//! The scheduler hands the dashboard a [`Status`] after every decision and a thread of its own draws the latest one a
+//! few times a second, so drawing never holds up dispatching. While the dashboard is up, log lines are kept in a
+//! [`LogBuffer`] and shown underneath it rather than written over it. When stdout isn't a terminal, or the dashboard
+//! can't be started, the scheduler logs as usual.
+
+use crate::backend::BpfStats;
+use anyhow::{Context, Result};
+use democracy_proto::CountingMethod;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Direction, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Cell, Paragraph, Row, Table, Wrap};
+use ratatui::Frame;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing_subscriber::fmt::MakeWriter;
+
+// How often the dashboard is redrawn.
+const REFRESH: Duration = Duration::from_millis(250);
+
+// How far back dispatch rates are measured over.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+// How many log lines are kept for the dashboard.
+const LOG_LINES: usize = 200;
+
+// Colours candidates are shown in, by roster position.
+const PALETTE: [Color; 6] = [
+    Color::Cyan,
+    Color::Magenta,
+    Color::Yellow,
+    Color::Green,
+    Color::Blue,
+    Color::Red,
+];
+
+/// Everything the dashboard shows, as of the scheduler's last decision.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub method: CountingMethod,
+    pub winner: Option<String>,
+    pub tied: Vec<String>,
+    pub ballots: u64,
+    pub votes_stale: bool,                // whether the results are out of date
+    pub candidates: Vec<CandidateStatus>, // in roster order
+    pub cpus: Vec<CpuOwner>,              // what each CPU is running
+    pub bpf: BpfStats,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CandidateStatus {
+    pub id: String,
+    pub name: String,
+    pub score: u64,         // under the counting method
+    pub cpu_time: Duration, // CPU time they've had
+    pub dispatches: u64,    // tasks dispatched for them so far
+}
+
+/// Who a CPU is running a task for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CpuOwner {
+    Idle,
+    Candidate(String),
+    Other(u32), // a task that isn't standing, by pid
+}
+
+/// Draws the dashboard on its own thread until dropped.
+pub struct Dashboard {
+    nr_cpus: usize,
+    status: Arc<Mutex<Option<Status>>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Dashboard {
+    /// Takes over the terminal. Log lines go to `logs` until the dashboard is dropped, and quitting from the dashboard
+    /// sets `shutdown`.
+    pub fn spawn(nr_cpus: usize, logs: LogBuffer, shutdown: Arc<AtomicBool>) -> Result<Self> {
+        let mut terminal = ratatui::try_init().context("Could not set up the terminal")?;
+
+        let status: Arc<Mutex<Option<Status>>> = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        logs.capture(true);
+
+        let thread_status = status.clone();
+        let thread_stop = stop.clone();
+        let thread = std::thread::Builder::new()
+            .name("dashboard".into())
+            .spawn(move || {
+                let mut view = View::default();
+
+                while !thread_stop.load(Ordering::Relaxed) {
+                    if let Some(status) = thread_status.lock().unwrap().take() {
+                        view.update(status);
+                    }
+
+                    let lines = logs.lines();
+                    if terminal.draw(|frame| view.draw(frame, &lines)).is_err() {
+                        break;
+                    }
+
+                    if quit_requested(REFRESH) {
+                        shutdown.store(true, Ordering::Relaxed);
+                    }
+                }
+
+                let _ = ratatui::try_restore();
+                logs.capture(false);
+            });
+
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                let _ = ratatui::try_restore();
+                return Err(e).context("Could not start the dashboard");
+            }
+        };
+
+        Ok(Self {
+            nr_cpus,
+            status,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn nr_cpus(&self) -> usize {
+        self.nr_cpus
+    }
+
+    /// Replaces what's shown with `status`, as of the next redraw.
+    pub fn update(&self, status: Status) {
+        *self.status.lock().unwrap() = Some(status);
+    }
+}
+
+impl Drop for Dashboard {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+// Waits up to `timeout` for a key press, and returns whether it was one asking to quit. The terminal is in raw mode,
+// so Ctrl-C arrives here rather than as a signal.
+fn quit_requested(timeout: Duration) -> bool {
+    if !event::poll(timeout).unwrap_or(false) {
+        return false;
+    }
+
+    match event::read() {
+        Ok(Event::Key(key)) if key.kind == KeyEventKind::Press => {
+            matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL))
+        }
+        _ => false,
+    }
+}
+
+// The latest status, and what's been worked out from the ones before it.
+#[derive(Default)]
+struct View {
+    status: Option<Status>,
+    sample: Option<(Instant, Vec<u64>)>, // when dispatch rates were last measured, and the counts then
+    rates: Vec<f64>,                     // dispatches per second for each candidate
+}
+
+impl View {
+    fn update(&mut self, status: Status) {
+        let now = Instant::now();
+        let counts: Vec<u64> = status.candidates.iter().map(|c| c.dispatches).collect();
+
+        match &self.sample {
+            Some((then, before)) if before.len() == counts.len() => {
+                let elapsed = now.duration_since(*then);
+                if elapsed >= RATE_WINDOW {
+                    self.rates = counts
+                        .iter()
+                        .zip(before)
+                        .map(|(now, before)| {
+                            now.saturating_sub(*before) as f64 / elapsed.as_secs_f64()
+                        })
+                        .collect();
+                    self.sample = Some((now, counts));
+                }
+            }
+            _ => {
+                self.rates = vec![0.0; counts.len()];
+                self.sample = Some((now, counts));
+            }
+        }
+
+        self.status = Some(status);
+    }
+
+    fn draw(&self, frame: &mut Frame, logs: &[String]) {
+        let Some(status) = &self.status else {
+            frame.render_widget(
+                Paragraph::new("Waiting for the scheduler...").block(title_block()),
+                frame.area(),
+            );
+            return;
+        };
+
+        let [header, candidates, middle, log] = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([
+                Constraint::Length(3),
+                Constraint::Length(status.candidates.len() as u16 + 3),
+                Constraint::Length(BpfStats::default().counters().len() as u16 + 2),
+                Constraint::Min(3),
+            ])
+            .areas(frame.area());
+        let [cpus, bpf] = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .areas(middle);
+
+        frame.render_widget(self.header(status), header);
+        frame.render_widget(self.candidates(status), candidates);
+        frame.render_widget(cpu_map(status), cpus);
+        frame.render_widget(bpf_counters(&status.bpf), bpf);
+        frame.render_widget(log_tail(logs, log), log);
+    }
+
+    fn header(&self, status: &Status) -> Paragraph<'static> {
+        let mut spans = match (&status.winner, status.tied.as_slice()) {
+            (Some(winner), _) => vec![
+                Span::raw("Winning: "),
+                Span::styled(
+                    winner.clone(),
+                    Style::default().add_modifier(Modifier::BOLD),
+                ),
+            ],
+            (None, []) => vec![Span::raw("No votes yet")],
+            (None, tied) => vec![Span::raw(format!("Tied: {}", tied.join(", ")))],
+        };
+        spans.push(Span::raw(format!(
+            "  ({:?}, {} ballots)",
+            status.method, status.ballots
+        )));
+        if status.votes_stale {
+            spans.push(Span::styled(
+                "  results are out of date",
+                Style::default().fg(Color::Red),
+            ));
+        }
+
+        Paragraph::new(Line::from(spans)).block(title_block())
+    }
+
+    fn candidates(&self, status: &Status) -> Table<'static> {
+        let total: u64 = status.candidates.iter().map(|c| c.score).sum();
+
+        let rows = status.candidates.iter().enumerate().map(|(i, candidate)| {
+            let share = match total {
+                0 => 0.0,
+                total => candidate.score as f64 * 100.0 / total as f64,
+            };
+
+            Row::new([
+                Cell::from(candidate.id.clone()).style(Style::default().fg(colour(i))),
+                Cell::from(candidate.name.clone()),
+                Cell::from(candidate.score.to_string()),
+                Cell::from(format!("{:.1}%", share)),
+                Cell::from(format!("{:.2?}", candidate.cpu_time)),
+                Cell::from(format!(
+                    "{:.1}",
+                    self.rates.get(i).copied().unwrap_or_default()
+                )),
+            ])
+        });
+
+        Table::new(
+            rows,
+            [
+                Constraint::Length(12),
+                Constraint::Min(12),
+                Constraint::Length(8),
+                Constraint::Length(8),
+                Constraint::Length(12),
+                Constraint::Length(14),
+            ],
+        )
+        .header(
+            Row::new([
+                "Candidate",
+                "Name",
+                "Votes",
+                "Share",
+                "CPU time",
+                "Dispatches/s",
+            ])
+            .style(Style::default().add_modifier(Modifier::BOLD)),
+        )
+        .block(Block::default().borders(Borders::ALL).title("Candidates"))
+    }
+}
+
+fn title_block() -> Block<'static> {
+    Block::default()
+        .borders(Borders::ALL)
+        .title("democracy (q to quit)")
+}
+
+fn colour(position: usize) -> Color {
+    PALETTE[position % PALETTE.len()]
+}
+
+fn cpu_map(status: &Status) -> Paragraph<'static> {
+    let position = |id: &str| status.candidates.iter().position(|c| c.id == id);
+
+    let spans: Vec<Span> = status
+        .cpus
+        .iter()
+        .enumerate()
+        .map(|(cpu, owner)| match owner {
+            CpuOwner::Idle => Span::styled(
+                format!("cpu{} idle  ", cpu),
+                Style::default().fg(Color::DarkGray),
+            ),
+            CpuOwner::Candidate(id) => Span::styled(
+                format!("cpu{} {}  ", cpu, id),
+                Style::default().fg(position(id).map_or(Color::Reset, colour)),
+            ),
+            CpuOwner::Other(pid) => Span::raw(format!("cpu{} pid {}  ", cpu, pid)),
+        })
+        .collect();
+
+    Paragraph::new(Line::from(spans))
+        .wrap(Wrap { trim: true })
+        .block(Block::default().borders(Borders::ALL).title("CPUs"))
+}
+
+fn bpf_counters(stats: &BpfStats) -> Table<'static> {
+    let rows = stats
+        .counters()
+        .into_iter()
+        .map(|(name, _, value)| Row::new([name.to_string(), value.to_string()]));
+
+    Table::new(rows, [Constraint::Length(22), Constraint::Min(8)])
+        .block(Block::default().borders(Borders::ALL).title("BPF"))
+}
+
+// As many of the most recent log lines as fit in `area`.
+fn log_tail(logs: &[String], area: Rect) -> Paragraph<'static> {
+    let fits = area.height.saturating_sub(2) as usize;
+    let lines: Vec<Line> = logs[logs.len().saturating_sub(fits)..]
+        .iter()
+        .map(|line| Line::raw(line.clone()))
+        .collect();
+
+    Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("Log"))
+}
+
+/// Where log lines go: straight to stdout, or kept for the dashboard while it's up.
+#[derive(Clone, Default)]
+pub struct LogBuffer {
+    lines: Arc<Mutex<VecDeque<String>>>,
+    captured: Arc<AtomicBool>,
+}
+
+impl LogBuffer {
+    fn capture(&self, captured: bool) {
+        self.captured.store(captured, Ordering::Relaxed);
+    }
+
+    /// The most recent lines kept, oldest first.
+    pub fn lines(&self) -> Vec<String> {
+        self.lines.lock().unwrap().iter().cloned().collect()
+    }
+
+    fn push(&self, text: &str) {
+        let mut lines = self.lines.lock().unwrap();
+        lines.extend(text.lines().map(str::to_string));
+
+        let excess = lines.len().saturating_sub(LOG_LINES);
+        lines.drain(..excess);
+    }
+}
+
+impl<'a> MakeWriter<'a> for LogBuffer {
+    type Writer = LogWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        LogWriter {
+            buffer: self.clone(),
+            pending: vec![],
+        }
+    }
+}
+
+/// Writes a single log event; see [`LogBuffer`].
+pub struct LogWriter {
+    buffer: LogBuffer,
+    pending: Vec<u8>, // what's been written while captured, kept until the whole event is in
+}
+
+impl Write for LogWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.buffer.captured.load(Ordering::Relaxed) {
+            self.pending.extend_from_slice(buf);
+            Ok(buf.len())
+        } else {
+            io::stdout().write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stdout().flush()
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        if !self.pending.is_empty() {
+            self.buffer.push(&String::from_utf8_lossy(&self.pending));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn status() -> Status {
+        Status {
+            method: CountingMethod::Plurality,
+            winner: Some("summer2".into()),
+            tied: vec![],
+            ballots: 4,
+            votes_stale: true,
+            candidates: vec![
+                CandidateStatus {
+                    id: "summer1".into(),
+                    name: "Summer 1".into(),
+                    score: 1,
+                    cpu_time: Duration::from_millis(1_500),
+                    dispatches: 10,
+                },
+                CandidateStatus {
+                    id: "summer2".into(),
+                    name: "Summer 2".into(),
+                    score: 3,
+                    cpu_time: Duration::from_millis(4_000),
+                    dispatches: 30,
+                },
+            ],
+            cpus: vec![
+                CpuOwner::Candidate("summer2".into()),
+                CpuOwner::Idle,
+                CpuOwner::Other(42),
+            ],
+            bpf: BpfStats {
+                nr_user_dispatches: 40,
+                ..Default::default()
+            },
+        }
+    }
+
+    #[test]
+    fn everything_is_on_screen() {
+        let mut view = View::default();
+        view.update(status());
+
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        terminal
+            .draw(|frame| view.draw(frame, &["INFO Task successfully scheduled".into()]))
+            .unwrap();
+
+        let screen: String = terminal
+            .backend()
+            .buffer()
+            .content()
+            .iter()
+            .map(|cell| cell.symbol())
+            .collect();
+
+        for expected in [
+            "Winning: summer2",
+            "results are out of date",
+            "Summer 1",
+            "75.0%",
+            "4.00s",
+            "cpu0 summer2",
+            "cpu1 idle",
+            "cpu2 pid 42",
+            "nr_user_dispatches",
+            "Task successfully scheduled",
+        ] {
+            assert!(screen.contains(expected), "missing {}", expected);
+        }
+    }
+
+    #[test]
+    fn logs_are_kept_while_captured() {
+        let logs = LogBuffer::default();
+        logs.capture(true);
+
+        for i in 0..LOG_LINES + 5 {
+            writeln!(logs.make_writer(), "line {}", i).unwrap();
+        }
+
+        let lines = logs.lines();
+        assert_eq!(lines.len(), LOG_LINES);
+        assert_eq!(lines.last().unwrap(), &format!("line {}", LOG_LINES + 4));
+    }
+}
@@ -0,0 +1,453 @@
+//! Where the scheduler gets election results from.
+//!
+//! Normally that's the ballot box, either by asking `/api/winner` or by subscribing to `/api/votes/stream`, but the
+//! results can also come from a file (handy for replaying a recorded election; the file is re-read whenever it changes)
+//! or from a stream of JSON lines on stdin or a fifo, for air-gapped demos. The file and stream sources take exactly what `/api/winner` returns, so
+//! `curl http://localhost:8080/api/winner > results.json` is a valid input.
+//!
+//! Whatever the source, the scheduler never asks it directly: a [`VotePoller`] asks on its own thread and the scheduler
+//! only ever looks at the last answer, so a slow or unreachable ballot box can't hold up dispatching.
+
+use anyhow::{bail, Context, Result};
+use democracy_proto::{routes, TallyEvent, WinnerResponse};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, ACCEPT};
+use std::io::{BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+use tracing::{error, info, warn};
+
+/// Which [`VoteSource`] to use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum VoteSourceKind {
+    /// Ask the ballot box over HTTP.
+    Http,
+
+    /// Subscribe to the ballot box's live stream of results, so they arrive as soon as votes are counted.
+    Subscribe,
+
+    /// Read the results from a file, re-reading it whenever it changes.
+    File,
+
+    /// Read a stream of results, one JSON document per line, from stdin or a fifo.
+    Stream,
+}
+
+pub trait VoteSource: Send {
+    /// Returns the latest election results.
+    fn current(&mut self) -> Result<WinnerResponse>;
+}
+
+/// Asks the ballot box for the results every time.
+pub struct HttpVoteSource {
+    client: reqwest::blocking::Client,
+    url: String,
+}
+
+impl HttpVoteSource {
+    /// `base_url` is where the ballot box is listening, e.g. `http://localhost:8080`. Each header is given as
+    /// `Name: value`.
+    pub fn new(base_url: &str, timeout: Duration, headers: &[String]) -> Result<Self> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(timeout)
+            .user_agent("scheduler")
+            .default_headers(parse_headers(headers)?)
+            .build()
+            .context("Could not build HTTP client")?;
+
+        Ok(Self {
+            client,
+            url: format!("{}{}", base_url.trim_end_matches('/'), routes::WINNER),
+        })
+    }
+}
+
+impl VoteSource for HttpVoteSource {
+    fn current(&mut self) -> Result<WinnerResponse> {
+        let response = self
+            .client
+            .get(&self.url)
+            .send()?
+            .error_for_status()?
+            .json()?;
+
+        Ok(response)
+    }
+}
+
+pub fn parse_headers(headers: &[String]) -> Result<HeaderMap> {
+    let mut map = HeaderMap::new();
+
+    for header in headers {
+        let (name, value) = header
+            .split_once(':')
+            .with_context(|| format!("Header '{}' should look like 'Name: value'", header))?;
+
+        map.insert(
+            HeaderName::from_bytes(name.trim().as_bytes())
+                .with_context(|| format!("'{}' is not a valid header name", name.trim()))?,
+            HeaderValue::from_str(value.trim())
+                .with_context(|| format!("Header '{}' has an invalid value", name.trim()))?,
+        );
+    }
+
+    Ok(map)
+}
+
+/// Reads the results from a file, only re-reading it when its modification time changes.
+pub struct FileVoteSource {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    latest: Option<WinnerResponse>,
+}
+
+impl FileVoteSource {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            modified: None,
+            latest: None,
+        }
+    }
+}
+
+impl VoteSource for FileVoteSource {
+    fn current(&mut self) -> Result<WinnerResponse> {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .with_context(|| format!("Could not read votes file '{}'", self.path.display()))?;
+
+        if self.latest.is_none() || self.modified != Some(modified) {
+            let contents = std::fs::read_to_string(&self.path)
+                .with_context(|| format!("Could not read votes file '{}'", self.path.display()))?;
+
+            // Don't hold on to a stale copy if the file is mid-rewrite; the next call will pick it up.
+            let results = serde_json::from_str(&contents)
+                .with_context(|| format!("Could not parse votes file '{}'", self.path.display()))?;
+
+            info!(path = %self.path.display(), "loaded votes file");
+            self.latest = Some(results);
+            self.modified = Some(modified);
+        }
+
+        Ok(self.latest.clone().unwrap())
+    }
+}
+
+/// Reads results, one JSON document per line, on a background thread and always reports the most recent one.
+pub struct StreamVoteSource {
+    latest: Arc<Mutex<Option<WinnerResponse>>>,
+}
+
+impl StreamVoteSource {
+    /// Reads from stdin until it's closed.
+    pub fn stdin() -> Self {
+        Self::spawn(|latest| read_lines(std::io::stdin().lock(), &latest, parse_results))
+    }
+
+    /// Subscribes to the ballot box's `/api/votes/stream`, reconnecting whenever the connection drops. Takes the same
+    /// arguments as [`HttpVoteSource::new`], except the timeout only covers connecting.
+    pub fn subscribe(
+        base_url: &str,
+        connect_timeout: Duration,
+        headers: &[String],
+    ) -> Result<Self> {
+        // No overall timeout; the response never finishes, it just keeps delivering events.
+        let client = reqwest::blocking::Client::builder()
+            .connect_timeout(connect_timeout)
+            .timeout(None)
+            .user_agent("scheduler")
+            .default_headers(parse_headers(headers)?)
+            .build()
+            .context("Could not build HTTP client")?;
+        let url = format!("{}{}", base_url.trim_end_matches('/'), routes::VOTES_STREAM);
+
+        Ok(Self::spawn(move |latest| loop {
+            let response = client
+                .get(&url)
+                .header(ACCEPT, "text/event-stream")
+                .send()
+                .and_then(|response| response.error_for_status());
+
+            match response {
+                Ok(response) => {
+                    info!(url = %url, "subscribed to results");
+                    read_lines(response, &latest, parse_event);
+                    warn!(url = %url, "results stream ended; resubscribing");
+                }
+                Err(e) => warn!(url = %url, err = %e, "could not subscribe to results; retrying"),
+            }
+
+            std::thread::sleep(Duration::from_secs(1));
+        }))
+    }
+
+    /// Reads from a fifo (or any file). Writers can come and go: when one closes the fifo we just open it again and
+    /// wait for the next.
+    pub fn fifo(path: &Path) -> Self {
+        let path = path.to_path_buf();
+
+        Self::spawn(move |latest| loop {
+            match std::fs::File::open(&path) {
+                Ok(file) => read_lines(file, &latest, parse_results),
+                Err(e) => {
+                    warn!(path = %path.display(), err = %e, "could not open votes fifo; retrying");
+                    std::thread::sleep(Duration::from_secs(1));
+                }
+            }
+        })
+    }
+
+    /// Reads from any reader until it runs dry.
+    #[allow(dead_code)]
+    pub fn from_reader<R: Read + Send + 'static>(reader: R) -> Self {
+        Self::spawn(move |latest| read_lines(reader, &latest, parse_results))
+    }
+
+    fn spawn<F>(read: F) -> Self
+    where
+        F: FnOnce(Arc<Mutex<Option<WinnerResponse>>>) + Send + 'static,
+    {
+        let latest = Arc::new(Mutex::new(None));
+
+        let thread_latest = latest.clone();
+        std::thread::Builder::new()
+            .name("vote-stream".into())
+            .spawn(move || read(thread_latest))
+            .expect("Failed to start vote stream reader");
+
+        Self { latest }
+    }
+}
+
+impl VoteSource for StreamVoteSource {
+    fn current(&mut self) -> Result<WinnerResponse> {
+        match self.latest.lock().unwrap().clone() {
+            Some(results) => Ok(results),
+            None => bail!("No results have been received yet"),
+        }
+    }
+}
+
+/// The last results a [`VotePoller`] received.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub results: WinnerResponse,
+    pub received: Instant,
+    pub latency: Duration, // how long the source took to answer
+    pub stale: bool,       // whether it's been too long since the source last answered
+}
+
+/// Asks a [`VoteSource`] for results on a background thread and keeps hold of the latest answer.
+pub struct VotePoller {
+    latest: Arc<Mutex<Option<(WinnerResponse, Instant, Duration)>>>,
+    max_age: Duration,
+    stop: Arc<AtomicBool>,
+}
+
+impl VotePoller {
+    /// Polls `source` every `interval`. Results older than `max_age` are still handed out (they're the best we've got)
+    /// but are marked stale.
+    pub fn spawn(mut source: Box<dyn VoteSource>, interval: Duration, max_age: Duration) -> Self {
+        let latest = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let thread_latest = latest.clone();
+        let thread_stop = stop.clone();
+        std::thread::Builder::new()
+            .name("vote-poller".into())
+            .spawn(move || {
+                while !thread_stop.load(Ordering::Relaxed) {
+                    let started = Instant::now();
+
+                    match source.current() {
+                        Ok(results) => {
+                            *thread_latest.lock().unwrap() =
+                                Some((results, Instant::now(), started.elapsed()))
+                        }
+                        Err(e) => error!(err = %e, "Could not get the current results"),
+                    }
+
+                    std::thread::sleep(interval.saturating_sub(started.elapsed()));
+                }
+            })
+            .expect("Failed to start vote poller");
+
+        Self {
+            latest,
+            max_age,
+            stop,
+        }
+    }
+
+    /// The most recent results, or `None` if the source hasn't answered yet. Never blocks on the source.
+    pub fn latest(&self) -> Option<Snapshot> {
+        let (results, received, latency) = self.latest.lock().unwrap().clone()?;
+
+        Some(Snapshot {
+            results,
+            received,
+            latency,
+            stale: received.elapsed() > self.max_age,
+        })
+    }
+}
+
+impl Drop for VotePoller {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+// Feeds every line through `parse`, keeping the latest results it finds. Lines `parse` returns `None` for are skipped.
+fn read_lines<R: Read>(
+    reader: R,
+    latest: &Mutex<Option<WinnerResponse>>,
+    parse: fn(&str) -> Option<serde_json::Result<WinnerResponse>>,
+) {
+    for line in BufReader::new(reader).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                warn!(err = %e, "could not read from vote stream");
+                return;
+            }
+        };
+
+        match parse(&line) {
+            Some(Ok(results)) => *latest.lock().unwrap() = Some(results),
+            Some(Err(e)) => warn!(err = %e, "skipping unreadable line in vote stream"),
+            None => {}
+        }
+    }
+}
+
+// A line of JSON results, as `/api/winner` returns them.
+fn parse_results(line: &str) -> Option<serde_json::Result<WinnerResponse>> {
+    if line.trim().is_empty() {
+        return None;
+    }
+
+    Some(serde_json::from_str(line))
+}
+
+// A line of a Server-Sent Events stream from `/api/votes/stream`. Only `data` lines carry events; the rest are
+// separators, keep-alive comments and the like.
+fn parse_event(line: &str) -> Option<serde_json::Result<WinnerResponse>> {
+    let data = line.strip_prefix("data:")?;
+
+    Some(serde_json::from_str::<TallyEvent>(data.trim()).map(|event| event.winner))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use democracy_proto::{CountingMethod, Tally};
+
+    fn results(winner: &str) -> WinnerResponse {
+        WinnerResponse {
+            method: CountingMethod::Plurality,
+            winner: Some(winner.into()),
+            tied: vec![],
+            scores: vec![Tally(winner.into(), 1)],
+            ballots: 1,
+            explanation: String::new(),
+        }
+    }
+
+    #[test]
+    fn parse_header_list() {
+        let headers = parse_headers(&["Authorization: Bearer abc".into()]).unwrap();
+
+        assert_eq!(headers["authorization"], "Bearer abc");
+        assert!(parse_headers(&["no colon".into()]).is_err());
+    }
+
+    #[test]
+    fn file_source_picks_up_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("results.json");
+        let mut source = FileVoteSource::new(&path);
+
+        assert!(source.current().is_err());
+
+        std::fs::write(&path, serde_json::to_string(&results("summer1")).unwrap()).unwrap();
+        assert_eq!(source.current().unwrap(), results("summer1"));
+
+        // Make sure the modification time actually moves on filesystems with coarse timestamps.
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        std::fs::write(&path, serde_json::to_string(&results("summer2")).unwrap()).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(5))
+            .unwrap();
+
+        assert_eq!(source.current().unwrap(), results("summer2"));
+    }
+
+    // Answers once, then never again.
+    struct OneShot(Option<WinnerResponse>);
+
+    impl VoteSource for OneShot {
+        fn current(&mut self) -> Result<WinnerResponse> {
+            self.0.take().context("ballot box went away")
+        }
+    }
+
+    #[test]
+    fn poller_marks_old_results_stale() {
+        let poller = VotePoller::spawn(
+            Box::new(OneShot(Some(results("summer1")))),
+            Duration::from_millis(5),
+            Duration::from_millis(50),
+        );
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let first = loop {
+            if let Some(snapshot) = poller.latest() {
+                break snapshot;
+            }
+            assert!(Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(first.results, results("summer1"));
+
+        std::thread::sleep(Duration::from_millis(100));
+
+        let later = poller.latest().unwrap();
+        assert_eq!(later.results, results("summer1"));
+        assert!(later.stale);
+    }
+
+    #[test]
+    fn read_server_sent_events() {
+        let event = TallyEvent {
+            changed: vec![Tally("summer2".into(), 1)],
+            winner: results("summer2"),
+        };
+        let input = format!(
+            ": keep-alive\n\ndata: {}\n\ndata: not json\n\n",
+            serde_json::to_string(&event).unwrap()
+        );
+        let latest = Mutex::new(None);
+
+        read_lines(std::io::Cursor::new(input), &latest, parse_event);
+
+        assert_eq!(latest.into_inner().unwrap(), Some(results("summer2")));
+    }
+
+    #[test]
+    fn stream_source_keeps_the_latest_line() {
+        let input = format!(
+            "{}\nnot json\n\n{}\n",
+            serde_json::to_string(&results("summer1")).unwrap(),
+            serde_json::to_string(&results("summer2")).unwrap()
+        );
+        let mut source = StreamVoteSource::from_reader(std::io::Cursor::new(input));
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while source.current().ok() != Some(results("summer2")) {
+            assert!(std::time::Instant::now() < deadline);
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}